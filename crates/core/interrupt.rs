@@ -0,0 +1,38 @@
+//! Stop work on Ctrl-C or termination so what was done so far is saved.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Exit code of processes stopped by SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Catch Ctrl-C and termination requests instead of exiting.
+///
+/// Readers stop taking new bundles and finish the ones they have. A second
+/// request exits right away.
+pub fn install() {
+    let handler = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        eprintln!();
+        eprintln!("Stopping, press Ctrl-C again to exit without saving...");
+    });
+
+    if let Err(e) = handler {
+        eprintln!("failed to set Ctrl-C handler: {}", e);
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Exit with the SIGINT exit code if work was interrupted.
+///
+/// Called once everything that was done has been saved.
+pub fn exit_if_interrupted() {
+    if interrupted() {
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+}
@@ -13,6 +13,9 @@ use utility::{
 };
 mod reader;
 use reader::Index as Index;
+mod interrupt;
+#[cfg(feature = "serde_support")]
+mod migrate;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const PROGRAM_NAME: &str = env!("CARGO_PKG_NAME");
@@ -49,9 +52,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             println!("      --no-save           Disable saving cache.");
             println!("  -c, --cache <FILE>      Set cache file to save/load work with.");
             println!("  -k, --keys <FILE>       Set keys file to use when doing reverse lookup with hashes.");
+            println!("      --mmap              Memory map bundles instead of reading them.");
             println!("  -o, --out <DIR>         Set output directory.");
-            println!("  -d, --dir <DIR>         Set input directory.");
+            println!("      --paranoid          Hash whole bundles to find changed bundles.");
+            println!("  -d, --dir <DIR>         Set input directory. Moves a cache of another directory.");
             println!("  -t, --threads <COUNT>   Set thread count.");
+            println!("      --verify            Check every chunk and file of indexed bundles.");
     } else if let Ok(word) = pico.value_from_str::<_, String>("--hash") {
         let hash = stingray::hash::murmur_hash(word.as_bytes());
         let half = (hash & 0xFFFFFFFF) as u32;
@@ -61,10 +67,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("{:016x}", hash.swap_bytes());
         println!("{:08x}", half.swap_bytes());
     } else {
-        let dir: PathBuf = match pico.value_from_str("--dir")
+        let dir_arg: Option<PathBuf> = pico.value_from_str("--dir")
             .or_else(|_| pico.value_from_str("-d"))
-            .or_else(|_| get_vermintide_dir())
-        {
+            .ok();
+
+        // caches saved for another directory are moved to one passed with -d
+        let relocate = dir_arg.is_some();
+        let dir: PathBuf = match dir_arg.map(Ok).unwrap_or_else(get_vermintide_dir) {
             Ok(dir) if dir.exists() => dir,
             Ok(dir) => {
                 println!("Invalid target directory \"{}\"", dir.display());
@@ -137,6 +146,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let do_extensions  = pico.contains("--extensions");
         let do_info        = pico.contains("--info") || pico.contains("-i");
         let no_save        = pico.contains("--no-save");
+        let do_verify      = pico.contains("--verify");
+        let mmap           = pico.contains("--mmap");
+        let paranoid       = pico.contains("--paranoid");
 
         if let Some((bundle_in, bundle_out)) = bundle {
             if let Ok(mut fd) = File::open(bundle_in) {
@@ -147,7 +159,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .create(true)
                     .open(bundle_out).unwrap();
 
-                reader::decompress_bundle(&mut fd, &mut target);
+                reader::decompress_bundle(&mut fd, &mut target)?;
             }
         } else {
             if num_threads == 0 {
@@ -155,16 +167,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("Exiting...");
             }
 
+            // indexing and extracting stop early so the cache can be saved
+            interrupt::install();
+
             let index_path = match force_index {
                 false => Some(index_file.as_ref()),
                 true => None,
             };
-            let mut index = reader::load_index(&dir, index_path, num_threads, !force_buffered)?;
+            let mut index = reader::load_index(&dir, relocate, index_path, num_threads, !force_buffered, mmap, paranoid)?;
             if keys.exists() {
                 index.load_keys(&keys);
             }
 
-            if let Some(pattern) = pattern {
+            if let Some(pattern) = pattern.filter(|_| !interrupt::interrupted()) {
                 let out = if benchmark || (cfg!(debug_assertions) && !pico.contains("--debug-extract")) {
                     None
                 } else {
@@ -179,6 +194,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 index.extract_files_with_progress(out, &pattern, num_threads, unbuffered, hash_fallback)?;
             }
 
+            if do_verify && !interrupt::interrupted() {
+                index.verify_with_progress(num_threads, false)?;
+            }
+
+            // extracting only reads matching bundles from the cache
+            if do_extensions || do_info {
+                index.load_bundles();
+            }
+
             if do_extensions {
                 print_extensions(&index);
             }
@@ -187,11 +211,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 print_info(&index)?;
             }
 
+            // bundles left by an interrupted index are indexed by the next run
+            if interrupt::interrupted() {
+                println!();
+                println!("Interrupted");
+            }
+
             // if force_index is false then save_reader will do hash comparison
-            // for final check to avoid compression if nothing has changed
+            // for final check to avoid writing if nothing has changed
             if index.dirty() && !no_save {
-                save_reader(&index_file, &index, force_index)?;
+                save_reader(&index_file, &mut index, force_index)?;
             }
+
+            interrupt::exit_if_interrupted();
         }
     }
 
@@ -0,0 +1,312 @@
+//! Migration of index caches saved by the first release.
+//!
+//! bincode is not self describing so the payload is read with a copy of the
+//! old layout and written with the layout of the current index.
+//!
+//! Version 1 caches lack the `.stream` data and bundle headers of the current
+//! index and store chunk sizes instead of chunk end offsets. Headers are read
+//! again from the first chunk of each bundle. Bundles with a `.stream` file need stream offsets from every
+//! file header and bundles where file sizes did not add up only have guessed
+//! offsets so both are dropped and indexed again.
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use stingray::{BundleHeader, Patch, ReadBuffer};
+use stingray::format_bundle;
+
+/// Size of the compressed bundle header before the first chunk.
+const CHUNK_START: u64 = 12;
+const ZLIB_CHUNK_SIZE: u64 = 0x10000;
+
+/// Migrate `payload` saved with `version` to the bincode layout of the current index.
+///
+/// Returns `None` if caches of `version` can not be migrated.
+pub fn migrate(version: u16, payload: Vec<u8>) -> bincode::Result<Option<Vec<u8>>> {
+    match version {
+        1 => v1_to_current(&payload).map(Some),
+        _ => Ok(None),
+    }
+}
+
+// Layout of `Index` and the stingray types it saves
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct Index {
+    dir: PathBuf,
+    is_ssd: Option<bool>,
+    hash: u64,
+    bundles: Vec<Bundle>,
+    timestamps: HashMap<(u64, Patch), u64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct Bundle {
+    hash: u64,
+    versions: Vec<BundleVersion>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct BundleVersion {
+    patch: Patch,
+    diff: u64,
+    size: u64,
+    stream: Option<u64>,
+    header: BundleHeader,
+    reader: BundleReader,
+    files: Vec<BundleFile>,
+}
+
+/// Chunk table stores the offset of the end of each chunk.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct BundleReader {
+    size: u64,
+    chunks: Vec<u64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct BundleFile {
+    hash: u64,
+    ext: u64,
+    size: u64,
+    offset: u64,
+    stream_offset: u64,
+    stream_size: u64,
+    flags: u8,
+}
+
+/// Layout of the first release before streams and bundle headers were indexed.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct V1 {
+    dir: PathBuf,
+    is_ssd: bool,
+    hash: u64,
+    bundles: Vec<V1Bundle>,
+    timestamps: HashMap<(u64, Patch), u64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct V1Bundle {
+    hash: u64,
+    versions: Vec<V1BundleVersion>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct V1BundleVersion {
+    patch: Patch,
+    diff: u32,
+    size: u32,
+    reader: V1BundleReader,
+    files: Vec<V1BundleFile>,
+}
+
+/// Chunk table stores compressed chunk sizes with `0` for raw chunks.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct V1BundleReader {
+    size: u32,
+    chunks: Vec<u16>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct V1BundleFile {
+    hash: u64,
+    ext: u64,
+    size: u32,
+    offset: u32,
+    flags: u8,
+}
+
+/// Read the header of the bundle at `path` from its first chunk.
+fn read_header(path: &Path, buffer: &mut ReadBuffer) -> Option<BundleHeader> {
+    let mut fd = File::open(path).ok()?;
+    let scrap = stingray::BundleReader::new().read(&mut fd, buffer, 0..4 + BundleHeader::SIZE, None).ok()?;
+    BundleHeader::from_bytes(&scrap[4..]).ok()
+}
+
+fn v1_to_current(payload: &[u8]) -> bincode::Result<Vec<u8>> {
+    let index: V1 = bincode::deserialize(payload)?;
+    let dir = &index.dir;
+    let streams = crate::reader::scan_dir_streams(dir);
+    let mut buffer = ReadBuffer::default();
+    let mut timestamps = index.timestamps;
+
+    let bundles = index.bundles.into_iter().map(|bundle| {
+        let hash = bundle.hash;
+        let versions = bundle.versions.into_iter().filter_map(|version| {
+            let key = (hash, version.patch);
+            let header = match version.diff > 0 || streams.contains_key(&key) {
+                true => None,
+                false => read_header(&dir.join(format_bundle(hash, version.patch)), &mut buffer),
+            };
+
+            // dropped bundles are indexed again
+            if header.is_none() {
+                timestamps.remove(&key);
+            }
+
+            let mut end = CHUNK_START;
+            let chunks = version.reader.chunks.into_iter().map(|size| {
+                end += 4 + match size {
+                    0 => ZLIB_CHUNK_SIZE,
+                    size => size as u64,
+                };
+                end
+            }).collect();
+
+            Some(BundleVersion {
+                patch: version.patch,
+                diff: version.diff.into(),
+                size: version.size.into(),
+                stream: None,
+                header: header?,
+                reader: BundleReader {
+                    size: version.reader.size.into(),
+                    chunks,
+                },
+                files: version.files.into_iter().map(|file| BundleFile {
+                    hash: file.hash,
+                    ext: file.ext,
+                    size: file.size.into(),
+                    offset: file.offset.into(),
+                    stream_offset: 0,
+                    stream_size: 0,
+                    flags: file.flags,
+                }).collect(),
+            })
+        }).collect::<Vec<_>>();
+
+        Bundle { hash, versions }
+    }).filter(|bundle| !bundle.versions.is_empty()).collect();
+
+    let index = Index {
+        dir: index.dir,
+        // drives that failed detection were saved as not being SSDs
+        is_ssd: Some(index.is_ssd).filter(|is_ssd| *is_ssd),
+        hash: index.hash,
+        bundles,
+        timestamps,
+    };
+    bincode::serialize(&index)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latest_layout() {
+        // fails if the fields of bincode saves change without a migration
+        let index = Index {
+            dir: "bundle".into(),
+            is_ssd: Some(true),
+            hash: 7,
+            bundles: vec![Bundle {
+                hash: 1,
+                versions: vec![BundleVersion {
+                    patch: Patch::new_base(),
+                    diff: 100,
+                    size: 100,
+                    stream: Some(3),
+                    header: BundleHeader::default(),
+                    reader: BundleReader {
+                        size: 100,
+                        chunks: vec![200],
+                    },
+                    files: vec![BundleFile {
+                        hash: 2,
+                        ext: 3,
+                        size: 100,
+                        offset: 100,
+                        stream_offset: 4,
+                        stream_size: 100,
+                        flags: 1,
+                    }],
+                }],
+            }],
+            timestamps: vec![((1, Patch::new_base()), 5)].into_iter().collect(),
+        };
+        let payload = bincode::serialize(&index).unwrap();
+        let loaded: crate::reader::Index = bincode::deserialize(&payload).unwrap();
+        assert_eq!(bincode::serialize(&loaded).unwrap(), payload);
+    }
+
+    #[test]
+    fn from_v1() {
+        use stingray::BundleWriter;
+        use stingray::file::{FileKind, FileVariant, Language};
+
+        let dir = std::env::temp_dir().join(format!("yarex-from-v1-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // bundle 1 is plain, 2 has a stream, 3 is gone and 4 had guessed offsets
+        let base = Patch::new_base();
+        let header = BundleHeader::new(&[11, 12]).unwrap();
+        for hash in [1, 2, 4] {
+            let mut writer = BundleWriter::new(6).unwrap();
+            writer.set_header(header.clone());
+            writer.add_file(FileKind::config as u64, 2, vec![FileVariant::new(Language::English, 4)], vec![1; 4]).unwrap();
+            let mut bundle = Vec::new();
+            writer.write(&mut bundle).unwrap();
+            std::fs::write(dir.join(format_bundle(hash, base)), bundle).unwrap();
+        }
+        std::fs::write(dir.join(stingray::format_stream(2, base)), [0; 8]).unwrap();
+
+        let v1 = V1 {
+            dir: dir.clone(),
+            is_ssd: true,
+            hash: 7,
+            bundles: (1..5).map(|hash| V1Bundle {
+                hash,
+                versions: vec![V1BundleVersion {
+                    patch: base,
+                    diff: match hash {
+                        4 => 8,
+                        _ => 0,
+                    },
+                    size: 100,
+                    reader: V1BundleReader {
+                        size: 300,
+                        chunks: vec![40],
+                    },
+                    files: vec![V1BundleFile {
+                        hash: 2,
+                        ext: FileKind::config as u64,
+                        size: 4,
+                        offset: 284,
+                        flags: 0,
+                    }],
+                }],
+            }).collect(),
+            timestamps: (1..5).map(|hash| ((hash, base), 5)).collect(),
+        };
+
+        let payload = migrate(1, bincode::serialize(&v1).unwrap()).unwrap().unwrap();
+        let migrated: Index = bincode::deserialize(&payload).unwrap();
+        assert_eq!((migrated.is_ssd, migrated.hash), (Some(true), 7));
+        assert_eq!(migrated.timestamps, vec![((1, base), 5)].into_iter().collect());
+        assert_eq!(migrated.bundles.len(), 1);
+
+        let bundle = &migrated.bundles[0];
+        assert_eq!((bundle.hash, bundle.versions.len()), (1, 1));
+        let version = &bundle.versions[0];
+        assert_eq!((version.stream, &version.header), (None, &header));
+        assert_eq!((version.size, version.reader.size), (100, 300));
+        assert_eq!(version.reader.chunks, vec![CHUNK_START + 44]);
+        assert_eq!(version.files, vec![BundleFile {
+            hash: 2,
+            ext: FileKind::config as u64,
+            size: 4,
+            offset: 284,
+            stream_offset: 0,
+            stream_size: 0,
+            flags: 0,
+        }]);
+
+        // migrated cache loads as the current index
+        let _: crate::reader::Index = bincode::deserialize(&payload).unwrap();
+        assert_eq!(migrate(2, Vec::new()).unwrap(), None);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
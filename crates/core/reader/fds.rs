@@ -0,0 +1,184 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Descriptors left for output files, stream files and the index file.
+const FD_RESERVE: usize = 256;
+
+/// Path and if the file was opened for unbuffered reads.
+type FdKey = (PathBuf, bool);
+
+struct Fds {
+    tick: u64,
+    fds: HashMap<FdKey, (u64, Arc<File>)>,
+    lru: BTreeMap<u64, FdKey>,
+}
+
+/// Bounded LRU of open bundle handles shared between index, verify and extract.
+///
+/// Reopening every bundle for each pass is slow on directories with thousands
+/// of bundles while keeping all of them open runs into the open file limit.
+/// Evicted handles stay open until the last reader drops them.
+pub struct FdCache {
+    capacity: usize,
+    inner: Mutex<Fds>,
+}
+
+impl FdCache {
+    /// Creates `FdCache` that holds up to `capacity` handles.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(Fds {
+                tick: 0,
+                fds: HashMap::new(),
+                lru: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Creates `FdCache` sized from the open file limit of the process.
+    ///
+    /// [FD_RESERVE](FD_RESERVE) descriptors are always left free.
+    pub fn with_fd_limit() -> Self {
+        Self::new(fd_limit().saturating_sub(FD_RESERVE))
+    }
+
+    /// Get cached handle of `path` or open it with `open`.
+    pub fn open<F>(&self, path: &Path, unbuffered: bool, open: F) -> io::Result<Arc<File>>
+        where
+            F: FnOnce() -> io::Result<File>,
+    {
+        let key = (path.to_owned(), unbuffered);
+        if let Some(fd) = self.get(&key) {
+            return Ok(fd);
+        }
+
+        // open without holding the lock so bundles are opened in parallel
+        let fd = Arc::new(open()?);
+        Ok(self.insert(key, fd))
+    }
+
+    fn get(&self, key: &FdKey) -> Option<Arc<File>> {
+        let mut inner = self.inner.lock().unwrap();
+        let Fds { tick, fds, lru } = &mut *inner;
+        let (last_used, fd) = fds.get_mut(key)?;
+        *tick += 1;
+        lru.remove(last_used);
+        lru.insert(*tick, key.clone());
+        *last_used = *tick;
+        Some(fd.clone())
+    }
+
+    /// Add handle, evicting the least recently used handle if full.
+    ///
+    /// Returns the handle already cached if another thread opened `key` first.
+    fn insert(&self, key: FdKey, fd: Arc<File>) -> Arc<File> {
+        if self.capacity == 0 {
+            return fd;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let Fds { tick, fds, lru } = &mut *inner;
+        if let Some((_, cached)) = fds.get(&key) {
+            return cached.clone();
+        }
+
+        if fds.len() >= self.capacity {
+            let oldest = lru.keys().next().copied();
+            if let Some(evict) = oldest.and_then(|oldest| lru.remove(&oldest)) {
+                fds.remove(&evict);
+            }
+        }
+
+        *tick += 1;
+        lru.insert(*tick, key.clone());
+        fds.insert(key, (*tick, fd.clone()));
+        fd
+    }
+}
+
+/// Highest soft limit macOS accepts when the hard limit is unlimited.
+#[cfg(unix)]
+const OPEN_MAX: libc::rlim_t = 10240;
+
+/// Raise the soft limit of open files to the hard limit and return the new limit.
+#[cfg(unix)]
+fn fd_limit() -> usize {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    unsafe {
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) != 0 {
+            return FD_RESERVE;
+        }
+
+        // some systems refuse the hard limit so a lower one is tried next
+        for raise_to in [limit.rlim_max, limit.rlim_max.min(OPEN_MAX)] {
+            if limit.rlim_cur >= raise_to {
+                break;
+            }
+
+            let raised = libc::rlimit {
+                rlim_cur: raise_to,
+                rlim_max: limit.rlim_max,
+            };
+            if libc::setrlimit(libc::RLIMIT_NOFILE, &raised) == 0 {
+                limit = raised;
+                break;
+            }
+        }
+    }
+
+    limit.rlim_cur.min(usize::MAX as libc::rlim_t) as usize
+}
+
+/// Conservative limit of open files where it is not queried.
+#[cfg(not(unix))]
+fn fd_limit() -> usize {
+    512
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reuses_and_evicts_handles() {
+        let dir = std::env::temp_dir().join(format!("yarex-fds-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = (0..3).map(|i| dir.join(i.to_string())).collect::<Vec<_>>();
+        for path in &paths {
+            std::fs::write(path, b"bundle").unwrap();
+        }
+
+        let cache = FdCache::new(2);
+        let open = |i: usize| cache.open(&paths[i], false, || File::open(&paths[i])).unwrap();
+
+        let first = open(0);
+        open(1);
+        assert!(Arc::ptr_eq(&first, &open(0)));
+        open(2);
+
+        // 1 was least recently used
+        assert!(Arc::ptr_eq(&first, &open(0)));
+        let reopened = cache.open(&paths[1], false, || Err(io::ErrorKind::NotFound.into()));
+        assert!(reopened.is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reserve_stays_free() {
+        let cache = FdCache::with_fd_limit();
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) }, 0);
+        let reserve = FD_RESERVE as libc::rlim_t;
+        assert!(cache.capacity as libc::rlim_t + reserve <= limit.rlim_cur.max(reserve));
+    }
+}
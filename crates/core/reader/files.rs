@@ -1,14 +1,23 @@
-use std::sync::{Mutex, Condvar};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Condvar, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, Thread};
+use std::time::Duration;
 use std::fs::{File, OpenOptions, Metadata};
 use std::fs::read_dir;
 use std::path::Path;
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
 
 use crossbeam_utils::thread::Scope;
+use drive::Storage;
+use memmap2::Mmap;
+use stingray::{BundleReader, BundleSource, ChunkCache, ReadBuffer, StingrayError};
 use stingray::Patch;
 use stingray::get_bundle_hash_patch;
+use stingray::get_stream_hash_patch;
 
 use crate::utility::format_bundle;
+use super::fds::FdCache;
 
 pub fn scan_dir_filter<P>(dir: &Path, mut filter: P) -> Vec<(u64, Patch)>
     where
@@ -32,145 +41,284 @@ pub fn scan_dir_filter<P>(dir: &Path, mut filter: P) -> Vec<(u64, Patch)>
     bundles
 }
 
+/// Find `.stream` files and their size keyed by the owning bundle.
+pub fn scan_dir_streams(dir: &Path) -> HashMap<(u64, Patch), u64> {
+    let mut streams = HashMap::new();
+    if let Ok(dir) = read_dir(dir) {
+        for entry in dir.flatten() {
+            if let Some((hash, patch)) = get_stream_hash_patch(entry.path()) {
+                if let Ok(metadata) = entry.metadata() {
+                    streams.insert((hash, patch), metadata.len());
+                }
+            }
+        }
+    }
+    streams
+}
+
+/// Bytes hashed from each end of a bundle for its [Fingerprint](Fingerprint).
+///
+/// Covers the bundle header and the first and last compressed chunk.
+const FINGERPRINT_SPAN: u64 = 12 + 4 + ReadBuffer::CHUNK_SIZE as u64;
+
+/// Content of a bundle file to tell if it changed without trusting modified times.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub size: u64,
+
+    /// Hash of the start and end of the file.
+    pub sample: u64,
+
+    /// Hash of the whole file if it was fingerprinted with `--paranoid`.
+    pub full: Option<u64>,
+}
+
+impl Fingerprint {
+    /// Fingerprint bundle at `path` of `size` bytes, hashing the whole file if `full`.
+    pub fn new(path: &Path, size: u64, full: bool) -> io::Result<Self> {
+        let mut fd = File::open(path)?;
+        let mut sample = Vec::with_capacity(2 * FINGERPRINT_SPAN as usize);
+        if size <= 2 * FINGERPRINT_SPAN {
+            fd.read_to_end(&mut sample)?;
+        } else {
+            (&mut fd).take(FINGERPRINT_SPAN).read_to_end(&mut sample)?;
+            fd.seek(SeekFrom::Start(size - FINGERPRINT_SPAN))?;
+            (&mut fd).take(FINGERPRINT_SPAN).read_to_end(&mut sample)?;
+        }
+
+        let full = match full {
+            // mapped so big bundles are not read into memory at once
+            true if size > 0 => Some(stingray::hash::murmur_hash(&unsafe { Mmap::map(&fd)? })),
+            true => Some(stingray::hash::murmur_hash(&[])),
+            false => None,
+        };
+
+        Ok(Self {
+            size,
+            sample: stingray::hash::murmur_hash(&sample),
+            full,
+        })
+    }
+
+    /// Check if the `current` fingerprint of a file still matches this one.
+    ///
+    /// Whole file hashes are only compared if both fingerprints have one.
+    pub fn matches(&self, current: &Self) -> bool {
+        self.size == current.size
+            && self.sample == current.sample
+            && match (self.full, current.full) {
+                (Some(saved), Some(current)) => saved == current,
+                _ => true,
+            }
+    }
+}
+
+/// Bundle opened by [Reader](Reader).
+pub enum BundleFd {
+    File(Arc<File>),
+    Mapped(Arc<Mmap>),
+}
+
+impl BundleFd {
+    /// Size of the compressed bundle.
+    pub fn len(&self) -> io::Result<u64> {
+        match self {
+            BundleFd::File(fd) => Ok(fd.metadata()?.len()),
+            BundleFd::Mapped(map) => Ok(map.len() as u64),
+        }
+    }
+
+    /// Share the bundle for reading from another thread.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            BundleFd::File(fd) => Ok(BundleFd::File(fd.clone())),
+            BundleFd::Mapped(map) => Ok(BundleFd::Mapped(map.clone())),
+        }
+    }
+}
+
+impl BundleSource for BundleFd {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            // positional reads so clones do not race on the shared file cursor
+            #[cfg(unix)]
+            BundleFd::File(fd) => std::os::unix::fs::FileExt::read_at(&**fd, buf, offset),
+            #[cfg(windows)]
+            BundleFd::File(fd) => std::os::windows::fs::FileExt::seek_read(&**fd, buf, offset),
+            #[cfg(not(any(unix, windows)))]
+            BundleFd::File(fd) => BundleSource::read_at(&mut &**fd, offset, buf),
+            BundleFd::Mapped(map) => stingray::MappedBundle::new(map).read_at(offset, buf),
+        }
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        match self {
+            BundleFd::File(_) => None,
+            BundleFd::Mapped(map) => Some(&map[..]),
+        }
+    }
+}
+
 pub struct Reader {
     files: Mutex<Vec<(LazyFile, Option<u64>, u64, Patch)>>,
     num_files: Mutex<u64>,
     has_num: Condvar,
 
     ready: Condvar,
-    sorted: AtomicBool,
-    read: AtomicBool,
     done: AtomicBool,
 
-    is_ssd: AtomicBool,
+    is_ssd: Mutex<Option<bool>>,
+    mmap: bool,
+    fds: Arc<FdCache>,
+
+    /// Bundles that failed to open and were skipped.
+    errors: Mutex<Vec<StingrayError>>,
 }
 
 impl Reader {
-    pub fn new(is_ssd: bool) -> Self {
+    pub fn new(is_ssd: Option<bool>, mmap: bool, fds: Arc<FdCache>) -> Self {
         Self {
             files: Mutex::new(Vec::new()),
             num_files: Mutex::new(u64::MAX),
             has_num: Condvar::new(),
             ready: Condvar::new(),
-            sorted: AtomicBool::new(false),
-            read: AtomicBool::new(false),
             done: AtomicBool::new(false),
-            is_ssd: AtomicBool::new(is_ssd),
+            is_ssd: Mutex::new(is_ssd),
+            mmap,
+            fds,
+            errors: Mutex::new(Vec::new()),
         }
     }
 
-    pub fn is_ssd(&self) -> bool {
-        self.is_ssd.load(Ordering::SeqCst)
+    /// Take errors of bundles that failed to open.
+    pub fn take_errors(&self) -> Vec<StingrayError> {
+        self.errors.lock().unwrap().drain(..).collect()
+    }
+
+    fn open_failed(&self, hash: u64, patch: Patch, e: io::Error) {
+        self.errors.lock().unwrap().push(StingrayError::new(&format!(
+            "bundle \"{}\" failed to open with error: {}",
+            format_bundle(hash, patch),
+            e)));
+    }
+
+    /// Drive type of the bundle directory or `None` if it is unknown.
+    pub fn is_ssd(&self) -> Option<bool> {
+        *self.is_ssd.lock().unwrap()
     }
 
     pub fn num_files(&self) -> u64 {
         *self.has_num.wait_while(self.num_files.lock().unwrap(), |num_files| *num_files == u64::MAX).unwrap()
     }
 
-    pub fn pop(&self) -> Option<(File, u64, Patch, bool)> {
-        let is_ssd = self.is_ssd.load(Ordering::SeqCst);
+    /// Take the next opened bundle, waiting for bundles that are still being opened.
+    ///
+    /// Bundles that fail to open are skipped and kept for [take_errors](Reader::take_errors).
+    pub fn pop(&self) -> Option<(BundleFd, u64, Patch, bool)> {
+        let is_ssd = self.is_ssd() == Some(true);
         loop {
-            if self.done.load(Ordering::SeqCst) {
-                let mut files = self.files.lock().unwrap();
-                break files.pop();
-            } else {
-                match self.ready.wait_timeout(self.files.lock().unwrap(), std::time::Duration::from_millis(2)) {
-                    Err(_) => continue,
-                    Ok((mut files, _)) => {
-                        if files.len() == 0 {
-                            continue;
-                        }
-
-                        break files.pop();
-                    }
-                }
+            let (lazy, _, hash, patch) = self.next()?;
+            match lazy.open() {
+                Ok(fd) => return Some((self.map(fd), hash, patch, is_ssd)),
+                Err(e) => self.open_failed(hash, patch, e),
+            }
+        }
+    }
+
+    fn next(&self) -> Option<(LazyFile, Option<u64>, u64, Patch)> {
+        let mut files = self.files.lock().unwrap();
+        loop {
+            if crate::interrupt::interrupted() {
+                break None;
+            } else if let Some(file) = files.pop() {
+                break Some(file);
+            } else if self.done.load(Ordering::SeqCst) {
+                break None;
+            }
+
+            files = self.ready.wait(files).unwrap();
+        }
+    }
+
+    /// Wake threads waiting in [pop](Reader::pop) after the last bundle was opened.
+    fn finish(&self) {
+        let _files = self.files.lock().unwrap();
+        self.done.store(true, Ordering::SeqCst);
+        self.ready.notify_all();
+    }
+
+    /// Memory map `fd` if enabled, falls back to reading through `fd` if mapping fails.
+    fn map(&self, fd: Arc<File>) -> BundleFd {
+        if self.mmap {
+            // bundles are only read and are not expected to change while mapped
+            if let Ok(map) = unsafe { Mmap::map(&*fd) } {
+                return BundleFd::Mapped(Arc::new(map));
             }
-        }.map(|(lazy, _, hash, patch)| (lazy.open(), hash, patch, is_ssd))
+        }
+
+        BundleFd::File(fd)
     }
 
     pub fn open_bundles<'a>(
         &'a self,
         scope: &Scope<'a>,
         dir: &'a Path,
-        mut bundles: Vec<(u64, Patch)>,
+        bundles: Vec<(u64, Patch)>,
         num_threads: usize,
         unbuffered: bool,
     ) {
         scope.spawn(move |_| {
-            if let Some(is_ssd) = drive::in_ssd(dir) {
-                if !is_ssd && cfg!(windows) {
-                    let mut files = {
-                        let mut files = self.files.lock().unwrap();
-                        std::mem::take(&mut (*files))
-                    };
-
-                    for (fd, offset, ..) in &mut files {
-                        *offset = drive::file_offset(&fd.borrow());
-                        assert!(offset.is_some());
-                    }
-
-                    files.sort_by(|(_, a, ..), (_, b, ..)| {
-                        sort_offset(a, b)
-                    });
-
-                    {
-                        let mut files2 = self.files.lock().unwrap();
-                        files2.reserve(files.len());
-                        for file in files {
-                            match files2.binary_search_by(|(_, probe, ..)| sort_offset(probe, &file.1)) {
-                                Err(i) => files2.insert(i, file),
-                                Ok(i) => files2.insert(i, file),
-                            }
-                        }
-                    }
-                }
-                self.is_ssd.store(is_ssd, Ordering::SeqCst);
+            // drive type decides if bundles are sorted by offset while they are opened
+            // and probing has to time reads before the openers compete with it
+            if self.is_ssd().is_none() {
+                *self.is_ssd.lock().unwrap() = detect_ssd(dir, &bundles);
             }
-            self.sorted.store(true, Ordering::SeqCst);
-            if self.read.load(Ordering::SeqCst) {
-                self.done.store(true, Ordering::SeqCst);
-            }
-        });
 
-        scope.spawn(move |_| {
             if !bundles.is_empty() {
                 {
                     *self.num_files.lock().unwrap() = bundles.len() as u64;
                     self.has_num.notify_all();
                 }
 
-                let num_threads = if bundles.len() > num_threads {
-                    num_threads
-                } else {
-                    bundles.len()
-                };
-                let thread_size = bundles.len() / num_threads;
+                let num_threads = num_threads.min(bundles.len());
+                let bundles = &bundles;
+                let next = &AtomicUsize::new(0);
+                let is_ssd = self.is_ssd() == Some(true);
 
                 crossbeam_utils::thread::scope(|s| {
-                    let mut split = &mut bundles[..];
-                    for i in 0..num_threads {
-                        let end = match i + 1 {
-                            x if x == num_threads => split.len(),
-                            _ => thread_size,
-                        };
-
-                        let (bundles, r) = split.split_at_mut(end);
-                        split = r;
-
+                    for _ in 0..num_threads {
                         let mut path = dir.to_owned();
                         s.spawn(move |_| {
-                            for (bundle_hash, patch) in bundles {
+                            // claim bundles one at a time so slow opens do not hold up a whole split
+                            while let Some((bundle_hash, patch)) = bundles.get(next.fetch_add(1, Ordering::Relaxed)) {
+                                if crate::interrupt::interrupted() {
+                                    break;
+                                }
+
                                 path.push(format_bundle(*bundle_hash, *patch));
 
-                                let lazy = LazyFile::new(&path, unbuffered);
+                                let lazy = match LazyFile::new(&path, unbuffered, &self.fds) {
+                                    Ok(lazy) => lazy,
+                                    Err(e) => {
+                                        self.open_failed(*bundle_hash, *patch, e);
+                                        path.pop();
+                                        continue;
+                                    }
+                                };
 
                                 {
-                                    #[cfg(target_os = "windows")]
-                                    if self.is_ssd.load(Ordering::SeqCst) {
+                                    if is_ssd {
                                         let mut files = self.files.lock().unwrap();
                                         files.push((lazy, None, *bundle_hash, *patch));
                                     } else {
-                                        let offset = drive::file_offset(&lazy.borrow());
+                                        let offset = match lazy.borrow() {
+                                            Ok(fd) => drive::file_offset(&fd),
+                                            Err(e) => {
+                                                self.open_failed(*bundle_hash, *patch, e);
+                                                path.pop();
+                                                continue;
+                                            }
+                                        };
                                         let mut files = self.files.lock().unwrap();
                                         if offset.is_none() {
                                             files.push((lazy, None, *bundle_hash, *patch));
@@ -181,12 +329,6 @@ impl Reader {
                                             }
                                         }
                                     }
-
-                                    #[cfg(not(target_os = "windows"))]
-                                    {
-                                        let mut files = self.files.lock().unwrap();
-                                        files.push((lazy, None, *bundle_hash, *patch));
-                                    }
                                     self.ready.notify_one();
                                 }
                                 path.pop();
@@ -196,60 +338,175 @@ impl Reader {
                 }).unwrap();
             }
 
-            self.read.store(true, Ordering::SeqCst);
-            if self.sorted.load(Ordering::SeqCst) {
-                //println!("READ-DONE");
-                self.done.store(true, Ordering::SeqCst);
-            }
-            self.ready.notify_all();
+            self.finish();
         });
     }
 }
 
+/// Drive type of `dir`, timing reads of some of its `bundles` if the mount does not tell.
+///
+/// `None` for network shares and drives of unknown type.
+pub fn detect_ssd(dir: &Path, bundles: &[(u64, Patch)]) -> Option<bool> {
+    drive::in_ssd(dir).or_else(|| {
+        // bundles spread over the directory
+        let probe_files = bundles.iter()
+            .step_by(bundles.len() / PROBE_FILES + 1)
+            .map(|(hash, patch)| dir.join(format_bundle(*hash, *patch)))
+            .collect::<Vec<_>>();
+
+        match drive::probe(&probe_files) {
+            Some(Storage::Ssd) => Some(true),
+            Some(Storage::Hdd) => Some(false),
+            // network shares are read in parallel like drives of unknown type
+            Some(Storage::Network) | None => None,
+        }
+    })
+}
+
+/// Number of bundles timed by [drive::probe] when the drive type is unknown.
+const PROBE_FILES: usize = 4;
+
 fn sort_offset(a: &Option<u64>, b: &Option<u64>) -> std::cmp::Ordering {
     (*b).cmp(a)
 }
 
+/// How many chunks [Prefetcher](Prefetcher) may run ahead of the extracting thread.
+///
+/// Kept small so prefetched chunks are not evicted from the cache before they are read.
+const PREFETCH_CHUNKS: usize = 8;
+
+#[derive(Default)]
+struct PrefetchState {
+    chunk: AtomicUsize,
+    done: AtomicBool,
+}
+
+struct PrefetchJob {
+    fd: BundleFd,
+    reader: BundleReader,
+    chunks: Vec<usize>,
+    state: Arc<PrefetchState>,
+}
+
+/// Decompresses upcoming chunks of a bundle into the [ChunkCache](ChunkCache) on a background thread.
+///
+/// Lets reading and inflating the next chunks overlap with decompiling and
+/// writing the current file.
+pub struct Prefetcher {
+    jobs: mpsc::SyncSender<PrefetchJob>,
+    thread: Thread,
+    state: Arc<PrefetchState>,
+}
+
+impl Prefetcher {
+    pub fn spawn(scope: &Scope<'_>, cache: Arc<ChunkCache>) -> Self {
+        let (jobs, recv) = mpsc::sync_channel(1);
+        let handle = scope.spawn(move |_| prefetch_chunks(recv, cache));
+
+        Self {
+            jobs,
+            thread: handle.thread().clone(),
+            state: Arc::default(),
+        }
+    }
+
+    /// Start prefetching `chunks` in order, cancelling the previous bundle.
+    pub fn start(&mut self, fd: &BundleFd, reader: &BundleReader, chunks: Vec<usize>) {
+        self.finish();
+
+        // extraction continues without read-ahead if the bundle can't be opened twice
+        let fd = match fd.try_clone() {
+            Ok(fd) => fd,
+            Err(_) => return,
+        };
+
+        let mut reader = reader.clone();
+        reader.set_threads(1);
+
+        self.state = Arc::default();
+        let _ = self.jobs.send(PrefetchJob {
+            fd,
+            reader,
+            chunks,
+            state: self.state.clone(),
+        });
+    }
+
+    /// Mark `chunk` as the chunk currently being read.
+    pub fn advance(&self, chunk: usize) {
+        self.state.chunk.store(chunk, Ordering::Relaxed);
+        self.thread.unpark();
+    }
+
+    /// Stop prefetching the current bundle.
+    pub fn finish(&self) {
+        self.state.done.store(true, Ordering::Relaxed);
+        self.thread.unpark();
+    }
+}
+
+fn prefetch_chunks(jobs: mpsc::Receiver<PrefetchJob>, cache: Arc<ChunkCache>) {
+    let mut read_buffer = ReadBuffer::default();
+    read_buffer.set_cache(cache);
+
+    for PrefetchJob { mut fd, mut reader, chunks, state } in jobs {
+        for chunk in chunks {
+            while chunk > state.chunk.load(Ordering::Relaxed) + PREFETCH_CHUNKS
+                && !state.done.load(Ordering::Relaxed)
+            {
+                thread::park_timeout(Duration::from_millis(10));
+            }
+
+            // errors are left for the extracting thread to report
+            if state.done.load(Ordering::Relaxed)
+                || reader.prefetch(&mut fd, &mut read_buffer, chunk).is_err()
+            {
+                break;
+            }
+        }
+    }
+}
+
 
 
 // LazyFiles is a workaround to caching open file handles on Linux
 // WSL2 had 1024 and 4096 file descriptor soft and hard limits respectively
 // as of 7/30/2021 Vermintide 2 has 11495 bundles
 //
-// TODO try other caching strategies on Linux
+// handles are opened through FdCache which keeps as many open as the limit allows
 #[cfg(target_os = "windows")]
 use std::os::windows::prelude::*;
 
 #[cfg(target_os = "windows")]
 struct LazyFile {
-    fd: File,
+    fd: Arc<File>,
 }
 
 #[cfg(target_os = "windows")]
 impl LazyFile {
-    fn new(path: &Path, unbuffered: bool) -> Self {
-        let fd = if unbuffered {
+    fn new(path: &Path, unbuffered: bool, fds: &Arc<FdCache>) -> io::Result<Self> {
+        let fd = fds.open(path, unbuffered, || if unbuffered {
             OpenOptions::new()
                 .read(true)
                 .attributes(0x20000000) //FILE_FLAG_NO_BUFFERING
-                .open(path).unwrap()
+                .open(path)
         } else {
             OpenOptions::new()
                 .read(true)
-                .open(path).unwrap()
-        };
+                .open(path)
+        })?;
 
-        Self {
+        Ok(Self {
             fd,
-        }
+        })
     }
 
-    fn borrow(&self) -> &File {
-        &self.fd
+    fn borrow(&self) -> io::Result<&File> {
+        Ok(&self.fd)
     }
 
-    fn open(self) -> File {
-        self.fd
+    fn open(self) -> io::Result<Arc<File>> {
+        Ok(self.fd)
     }
 }
 
@@ -259,24 +516,130 @@ use std::path::PathBuf;
 #[cfg(not(target_os = "windows"))]
 struct LazyFile {
     path: PathBuf,
+    unbuffered: bool,
+    fds: Arc<FdCache>,
 }
 
 #[cfg(not(target_os = "windows"))]
 impl LazyFile {
-    fn new(path: &Path, _unbuffered: bool) -> Self {
-        Self {
+    /// Files are only opened when they are read so this does not fail.
+    fn new(path: &Path, unbuffered: bool, fds: &Arc<FdCache>) -> io::Result<Self> {
+        Ok(Self {
             path: path.to_owned(),
-        }
+            unbuffered,
+            fds: fds.clone(),
+        })
     }
 
-    fn borrow(&self) -> File {
+    fn borrow(&self) -> io::Result<Arc<File>> {
         self.open()
     }
 
-    fn open(&self) -> File {
+    fn open(&self) -> io::Result<Arc<File>> {
+        self.fds.open(&self.path, self.unbuffered, || self.open_fd())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_fd(&self) -> io::Result<File> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        // some file systems like tmpfs do not support O_DIRECT
+        // aligned reads still work on buffered files so fall back to those
+        if self.unbuffered {
+            if let Ok(fd) = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_DIRECT)
+                .open(&self.path)
+            {
+                return Ok(fd);
+            }
+        }
+
+        OpenOptions::new()
+            .read(true)
+            .open(&self.path)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn open_fd(&self) -> io::Result<File> {
         OpenOptions::new()
             .read(true)
-            .open(&self.path).unwrap()
+            .open(&self.path)
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fingerprint_matches() {
+        let saved = Fingerprint { size: 10, sample: 1, full: None };
+        let paranoid = Fingerprint { full: Some(3), ..saved };
+        assert!(saved.matches(&saved));
+        assert!(!saved.matches(&Fingerprint { size: 11, ..saved }));
+        assert!(!saved.matches(&Fingerprint { sample: 2, ..saved }));
+
+        // whole file hashes only count if both sides have one
+        assert!(saved.matches(&paranoid));
+        assert!(paranoid.matches(&saved));
+        assert!(paranoid.matches(&paranoid));
+        assert!(!paranoid.matches(&Fingerprint { full: Some(4), ..saved }));
+        assert!(!paranoid.matches(&Fingerprint { sample: 2, ..paranoid }));
+    }
+
+    #[test]
+    fn fingerprint_files() {
+        let dir = std::env::temp_dir().join(format!("yarex-fingerprint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bundle");
+
+        // big enough that only the ends are sampled
+        let mut data = vec![7; 4 * FINGERPRINT_SPAN as usize];
+        std::fs::write(&path, &data).unwrap();
+        let len = data.len() as u64;
+        let saved = Fingerprint::new(&path, len, true).unwrap();
+        assert_eq!(saved, Fingerprint::new(&path, len, true).unwrap());
+        assert_eq!(Fingerprint::new(&path, len, false).unwrap(), Fingerprint { full: None, ..saved });
+
+        // middle changes are only seen by the whole file hash
+        data[2 * FINGERPRINT_SPAN as usize] = 8;
+        std::fs::write(&path, &data).unwrap();
+        assert!(saved.matches(&Fingerprint::new(&path, len, false).unwrap()));
+        assert!(!saved.matches(&Fingerprint::new(&path, len, true).unwrap()));
+
+        data[0] = 8;
+        std::fs::write(&path, &data).unwrap();
+        assert!(!saved.matches(&Fingerprint::new(&path, len, false).unwrap()));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn missing_bundles() {
+        let dir = std::env::temp_dir().join(format!("yarex-missing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base = Patch::new_base();
+        std::fs::write(dir.join(format_bundle(1, base)), [0; 16]).unwrap();
+
+        // bundles removed after the directory was scanned are skipped and reported
+        for is_ssd in [Some(true), Some(false)] {
+            let reader = Reader::new(is_ssd, false, Arc::new(FdCache::new(4)));
+            let opened = crossbeam_utils::thread::scope(|s| {
+                reader.open_bundles(s, &dir, vec![(1, base), (2, base)], 2, false);
+                let mut opened = Vec::new();
+                while let Some((_, hash, patch, _)) = reader.pop() {
+                    opened.push((hash, patch));
+                }
+                opened
+            }).unwrap();
+
+            assert_eq!(opened, vec![(1, base)]);
+            let errors = reader.take_errors();
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0].to_string().contains(&format_bundle(2, base)));
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
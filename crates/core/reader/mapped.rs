@@ -0,0 +1,602 @@
+//! Index cache layout that is memory mapped and read in place.
+//!
+//! The layout starts with the bundle database hash, the drive type and the
+//! offset and record count of every table. Tables hold fixed size records of
+//! little endian `u64` fields:
+//!
+//!   dir         UTF-8 path of the bundle directory, one byte per record
+//!   timestamps  bundle hash, patch, modified time
+//!   versions    bundle hash, patch, size, diff, stream size, uncompressed size,
+//!               first file, file count, first chunk, chunk count
+//!               sorted by bundle hash and patch
+//!   headers     256 byte bundle header of every version
+//!   files       name hash, extension hash, size, offset, stream offset,
+//!               stream size, flags, sorted by extension and name per version
+//!   chunks      compressed end offset of every known chunk
+//!   names       name hash, version, sorted by name hash
+//!   fingerprints  bundle hash, patch, size, sample hash, has whole file hash,
+//!               whole file hash
+//!
+//! Saves of version 8 end with the names table.
+//! Only bundles that are used are turned into [Bundle]s so extracting a few
+//! files does not pay for reading the whole index.
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+
+use memmap2::Mmap;
+use stingray::{Bundle, BundleFile, BundleHeader, BundleReader, BundleVersion, Patch};
+
+use stingray::hash::KeyMap;
+
+use super::Index;
+use super::files::Fingerprint;
+
+const DIR: usize = 0;
+const TIMESTAMPS: usize = 1;
+const VERSIONS: usize = 2;
+const HEADERS: usize = 3;
+const FILES: usize = 4;
+const CHUNKS: usize = 5;
+const NAMES: usize = 6;
+const FINGERPRINTS: usize = 7;
+const TABLES: usize = 8;
+
+const RECORD_SIZES: [usize; TABLES] = [1, 3 * 8, 10 * 8, BundleHeader::SIZE, 7 * 8, 8, 2 * 8, 6 * 8];
+
+/// Save version that added the fingerprints table.
+const FINGERPRINTS_VERSION: u16 = 9;
+
+/// Bundle database hash, drive type and offset and count of `tables` tables.
+const fn layout_size(tables: usize) -> usize {
+    (2 + tables * 2) * 8
+}
+
+/// Stream size of versions without a `.stream` file.
+const NO_STREAM: u64 = u64::MAX;
+
+/// Read `u64` field `field` of a record.
+fn field(record: &[u8], field: usize) -> u64 {
+    u64::from_le_bytes(record[field * 8..field * 8 + 8].try_into().unwrap())
+}
+
+/// Read the patch number in field 1 of a record.
+///
+/// Records with a patch number [Patch](Patch) does not allow are rejected by
+/// [open](MappedIndex::open), so this is only called on checked records.
+fn patch(record: &[u8]) -> Patch {
+    Patch::new(field(record, 1) as u16)
+}
+
+/// Check that field 1 of a record holds a valid patch number.
+fn is_patch(record: &[u8]) -> bool {
+    field(record, 1) < 1000
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupted index cache")
+}
+
+/// Index cache mapped into memory.
+pub struct MappedIndex {
+    map: Mmap,
+    start: usize,
+
+    /// Offset in `map` and record count of every table.
+    tables: [(usize, usize); TABLES],
+}
+
+impl MappedIndex {
+    /// Map cache of save `version` with the layout at `start` of `fd`.
+    ///
+    /// Every record is checked so later reads can't go out of bounds.
+    pub fn open(fd: &File, start: usize, version: u16) -> io::Result<Self> {
+        let num_tables = if version < FINGERPRINTS_VERSION {
+            FINGERPRINTS
+        } else {
+            TABLES
+        };
+        let layout_size = layout_size(num_tables);
+
+        // caches are replaced by renaming a new file over them and not written in place
+        let map = unsafe { Mmap::map(fd)? };
+        let layout = map.get(start..start + layout_size).ok_or_else(invalid)?;
+
+        // tables added by later versions are empty
+        let mut tables = [(start, 0); TABLES];
+        for (table, (offset, count)) in tables.iter_mut().enumerate().take(num_tables) {
+            *offset = usize::try_from(field(layout, 2 + table * 2)).map_err(|_| invalid())?;
+            *count = usize::try_from(field(layout, 3 + table * 2)).map_err(|_| invalid())?;
+            let end = count.checked_mul(RECORD_SIZES[table])
+                .and_then(|size| size.checked_add(*offset))
+                .and_then(|end| end.checked_add(start));
+            *offset += start;
+
+            match end {
+                Some(end) if *offset >= start + layout_size && end <= map.len() => (),
+                _ => return Err(invalid()),
+            }
+        }
+
+        let index = Self {
+            map,
+            start,
+            tables,
+        };
+        index.check()?;
+        Ok(index)
+    }
+
+    fn check(&self) -> io::Result<()> {
+        std::str::from_utf8(self.table(DIR)).map_err(|_| invalid())?;
+
+        if self.len(HEADERS) != self.len(VERSIONS) {
+            return Err(invalid());
+        }
+
+        let in_table = |table, start: u64, len: u64| {
+            matches!(start.checked_add(len), Some(end) if end <= self.len(table) as u64)
+        };
+
+        let mut last = None;
+        for record in self.records(VERSIONS) {
+            let key = (field(record, 0), field(record, 1));
+            if !is_patch(record)
+                || last >= Some(key)
+                || !in_table(FILES, field(record, 6), field(record, 7))
+                || !in_table(CHUNKS, field(record, 8), field(record, 9))
+            {
+                return Err(invalid());
+            }
+            last = Some(key);
+        }
+
+        if self.records(TIMESTAMPS).chain(self.records(FINGERPRINTS)).any(|record| !is_patch(record)) {
+            return Err(invalid());
+        }
+
+        let mut last = 0;
+        for record in self.records(NAMES) {
+            if field(record, 0) < last || !in_table(VERSIONS, field(record, 1), 1) {
+                return Err(invalid());
+            }
+            last = field(record, 0);
+        }
+
+        Ok(())
+    }
+
+    fn len(&self, table: usize) -> usize {
+        self.tables[table].1
+    }
+
+    fn table(&self, table: usize) -> &[u8] {
+        let (offset, count) = self.tables[table];
+        &self.map[offset..offset + count * RECORD_SIZES[table]]
+    }
+
+    fn records(&self, table: usize) -> std::slice::Chunks<'_, u8> {
+        self.table(table).chunks(RECORD_SIZES[table])
+    }
+
+    fn record(&self, table: usize, i: usize) -> &[u8] {
+        let size = RECORD_SIZES[table];
+        &self.table(table)[i * size..(i + 1) * size]
+    }
+
+    fn layout(&self) -> &[u8] {
+        &self.map[self.start..self.start + layout_size(0)]
+    }
+
+    pub fn hash(&self) -> u64 {
+        field(self.layout(), 0)
+    }
+
+    pub fn is_ssd(&self) -> Option<bool> {
+        match field(self.layout(), 1) {
+            1 => Some(false),
+            2 => Some(true),
+            _ => None,
+        }
+    }
+
+    pub fn dir(&self) -> PathBuf {
+        std::str::from_utf8(self.table(DIR)).unwrap().into()
+    }
+
+    pub fn timestamps(&self) -> HashMap<(u64, Patch), u64> {
+        self.records(TIMESTAMPS)
+            .map(|record| ((field(record, 0), patch(record)), field(record, 2)))
+            .collect()
+    }
+
+    pub fn fingerprints(&self) -> HashMap<(u64, Patch), Fingerprint> {
+        self.records(FINGERPRINTS)
+            .map(|record| {
+                let fingerprint = Fingerprint {
+                    size: field(record, 2),
+                    sample: field(record, 3),
+                    full: Some(field(record, 5)).filter(|_| field(record, 4) != 0),
+                };
+                ((field(record, 0), patch(record)), fingerprint)
+            })
+            .collect()
+    }
+
+    /// Hashes of bundles with a file named `name_hash`.
+    pub fn bundles_with_name(&self, name_hash: u64) -> Vec<u64> {
+        let names = self.len(NAMES);
+        let first = partition_point(names, |i| field(self.record(NAMES, i), 0) < name_hash);
+
+        let mut hashes = (first..names)
+            .map(|i| self.record(NAMES, i))
+            .take_while(|record| field(record, 0) == name_hash)
+            .map(|record| field(self.record(VERSIONS, field(record, 1) as usize), 0))
+            .collect::<Vec<_>>();
+        hashes.sort_unstable();
+        hashes.dedup();
+        hashes
+    }
+
+    /// Hashes of bundles with a file of extension `ext_hash`.
+    pub fn bundles_with_ext(&self, ext_hash: u64) -> Vec<u64> {
+        let mut hashes = self.records(VERSIONS)
+            .filter(|version| self.files(version).any(|file| field(file, 1) == ext_hash))
+            .map(|version| field(version, 0))
+            .collect::<Vec<_>>();
+        hashes.dedup();
+        hashes
+    }
+
+    fn files<'a>(&'a self, version: &[u8]) -> impl Iterator<Item = &'a [u8]> {
+        let start = field(version, 6) as usize;
+        (start..start + field(version, 7) as usize).map(move |i| self.record(FILES, i))
+    }
+
+    /// Every bundle in the cache.
+    pub fn bundles(&self) -> Vec<Bundle> {
+        let mut bundles = Vec::<Bundle>::new();
+        for i in 0..self.len(VERSIONS) {
+            let hash = field(self.record(VERSIONS, i), 0);
+            if bundles.last().map(|bundle| bundle.hash()) != Some(hash) {
+                bundles.push(Bundle::new(hash));
+            }
+            bundles.last_mut().unwrap().add_version(self.version(i));
+        }
+        bundles
+    }
+
+    pub fn bundle(&self, hash: u64) -> Option<Bundle> {
+        let versions = self.len(VERSIONS);
+        let first = partition_point(versions, |i| field(self.record(VERSIONS, i), 0) < hash);
+
+        let mut bundle = Bundle::new(hash);
+        for i in (first..versions).take_while(|i| field(self.record(VERSIONS, *i), 0) == hash) {
+            bundle.add_version(self.version(i));
+        }
+        Some(bundle).filter(|bundle| !bundle.versions().is_empty())
+    }
+
+    fn version(&self, i: usize) -> BundleVersion {
+        let record = self.record(VERSIONS, i);
+        let chunks = field(record, 8) as usize..(field(record, 8) + field(record, 9)) as usize;
+        let reader = BundleReader::with_chunk_offsets(
+            field(record, 5),
+            chunks.map(|chunk| field(self.record(CHUNKS, chunk), 0)).collect());
+
+        let files = self.files(record)
+            .map(|file| BundleFile::from_parts(
+                field(file, 0),
+                field(file, 1),
+                field(file, 2),
+                field(file, 3),
+                field(file, 4),
+                field(file, 5),
+                field(file, 6) as u8))
+            .collect();
+
+        BundleVersion::from_parts(
+            patch(record),
+            field(record, 2),
+            field(record, 3),
+            Some(field(record, 4)).filter(|stream| *stream != NO_STREAM),
+            BundleHeader::from_bytes(self.record(HEADERS, i)).unwrap(),
+            reader,
+            files,
+        )
+    }
+}
+
+/// First index in `0..len` where `before` is `false`.
+fn partition_point(len: usize, before: impl Fn(usize) -> bool) -> usize {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if before(mid) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+fn patch_number(patch: Patch) -> u64 {
+    patch.get().unwrap_or(0) as u64
+}
+
+fn write(index: &Index) -> io::Result<Vec<u8>> {
+    let dir = index.dir.to_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bundle directory is not valid UTF-8"))?;
+
+    let mut tables = vec![Vec::new(); TABLES];
+    tables[DIR].extend_from_slice(dir.as_bytes());
+
+    let mut timestamps = index.timestamps.iter().collect::<Vec<_>>();
+    timestamps.sort_unstable_by_key(|((hash, patch), _)| (*hash, *patch));
+    for ((hash, patch), time) in timestamps {
+        push(&mut tables[TIMESTAMPS], &[*hash, patch_number(*patch), *time]);
+    }
+
+    let mut fingerprints = index.fingerprints.iter().collect::<Vec<_>>();
+    fingerprints.sort_unstable_by_key(|((hash, patch), _)| (*hash, *patch));
+    for ((hash, patch), fingerprint) in fingerprints {
+        push(&mut tables[FINGERPRINTS], &[
+            *hash,
+            patch_number(*patch),
+            fingerprint.size,
+            fingerprint.sample,
+            fingerprint.full.is_some() as u64,
+            fingerprint.full.unwrap_or(0),
+        ]);
+    }
+
+    let mut names = Vec::new();
+    let mut num_files = 0;
+    let mut num_chunks = 0;
+    for bundle in &index.bundles {
+        for version in bundle.versions() {
+            let files = version.all_files();
+            let chunks = version.reader().chunk_offsets();
+            let num_versions = tables[HEADERS].len() / BundleHeader::SIZE;
+
+            push(&mut tables[VERSIONS], &[
+                bundle.hash(),
+                patch_number(version.patch()),
+                version.size(),
+                version.diff(),
+                version.stream_size().unwrap_or(NO_STREAM),
+                version.reader().size(),
+                num_files,
+                files.len() as u64,
+                num_chunks,
+                chunks.len() as u64,
+            ]);
+            tables[HEADERS].extend_from_slice(&version.bundle_header().to_bytes());
+
+            for file in files {
+                push(&mut tables[FILES], &[
+                    file.name_hash(),
+                    file.ext_hash(),
+                    file.size(),
+                    file.offset(),
+                    file.stream_offset(),
+                    file.stream_size(),
+                    file.flags() as u64,
+                ]);
+                names.push((file.name_hash(), num_versions as u64));
+            }
+            push(&mut tables[CHUNKS], chunks);
+
+            num_files += files.len() as u64;
+            num_chunks += chunks.len() as u64;
+        }
+    }
+
+    names.sort_unstable();
+    for (name, version) in names {
+        push(&mut tables[NAMES], &[name, version]);
+    }
+
+    let is_ssd = match index.is_ssd {
+        None => 0,
+        Some(false) => 1,
+        Some(true) => 2,
+    };
+
+    let mut out = Vec::with_capacity(layout_size(TABLES) + tables.iter().map(Vec::len).sum::<usize>());
+    push(&mut out, &[index.hash, is_ssd]);
+    let mut offset = layout_size(TABLES);
+    for (table, data) in tables.iter().enumerate() {
+        push(&mut out, &[offset as u64, (data.len() / RECORD_SIZES[table]) as u64]);
+        offset += data.len();
+    }
+    for data in tables {
+        out.extend_from_slice(&data);
+    }
+    Ok(out)
+}
+
+fn push(out: &mut Vec<u8>, fields: &[u64]) {
+    for field in fields {
+        out.extend_from_slice(&field.to_le_bytes());
+    }
+}
+
+impl Index {
+    /// Creates `Index` that reads bundles from `mapped` when they are needed.
+    pub fn from_mapped(mapped: MappedIndex) -> Self {
+        Self {
+            dir: mapped.dir(),
+            is_ssd: mapped.is_ssd(),
+            hash: mapped.hash(),
+            bundles: Vec::new(),
+            timestamps: mapped.timestamps(),
+            fingerprints: mapped.fingerprints(),
+            mapped: Some(mapped),
+            dirty: false,
+            mmap: false,
+            paranoid: false,
+            chunk_cache: super::new_chunk_cache(),
+            fds: super::new_fd_cache(),
+            key_map: KeyMap::default(),
+        }
+    }
+
+    /// Layout of the index for [MappedIndex](MappedIndex).
+    ///
+    /// Bundles still in a mapped cache have to be loaded first.
+    pub fn to_mapped(&self) -> io::Result<Vec<u8>> {
+        write(self)
+    }
+
+    /// Read every bundle that is still only in the mapped cache.
+    pub fn load_bundles(&mut self) {
+        if let Some(mapped) = self.mapped.take() {
+            for bundle in mapped.bundles() {
+                if let Err(i) = self.bundles.binary_search_by(|probe| probe.hash().cmp(&bundle.hash())) {
+                    self.bundles.insert(i, bundle);
+                }
+            }
+        }
+    }
+
+    /// Read bundles from the mapped cache that may have files matching `name_hash` and `ext_hash`.
+    ///
+    /// `None` matches every name or extension.
+    pub(super) fn load_matching(&mut self, name_hash: Option<u64>, ext_hash: Option<u64>) {
+        let mapped = match &self.mapped {
+            Some(mapped) => mapped,
+            None => return,
+        };
+
+        let hashes = match (name_hash, ext_hash) {
+            (Some(name_hash), _) => mapped.bundles_with_name(name_hash),
+            (None, Some(ext_hash)) => mapped.bundles_with_ext(ext_hash),
+            (None, None) => return self.load_bundles(),
+        };
+
+        for hash in hashes {
+            if let Err(i) = self.bundles.binary_search_by(|probe| probe.hash().cmp(&hash)) {
+                if let Some(bundle) = mapped.bundle(hash) {
+                    self.bundles.insert(i, bundle);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    /// Write the mapped layout of `index` to `path` and map it again.
+    fn reopen(index: &Index, path: &Path) -> MappedIndex {
+        std::fs::write(path, write(index).unwrap()).unwrap();
+        MappedIndex::open(&File::open(path).unwrap(), 0, FINGERPRINTS_VERSION).unwrap()
+    }
+
+    fn version(patch: u16, files: &[(u64, u64)]) -> BundleVersion {
+        let mut properties = [0; 2];
+        properties[0] = patch as u64 + 1;
+        BundleVersion::from_parts(
+            Patch::new(patch),
+            100 + patch as u64,
+            3,
+            if patch == 0 { Some(40) } else { None },
+            BundleHeader::new(&properties).unwrap(),
+            BundleReader::with_chunk_offsets(200, vec![16, 32 + patch as u64]),
+            files.iter().map(|(ext, name)| BundleFile::from_parts(*name, *ext, 8, 16, 24, 32, 1)).collect(),
+        )
+    }
+
+    fn index(dir: &Path) -> Index {
+        let mut index = Index::new(dir);
+        index.is_ssd = Some(false);
+        index.hash = 7;
+        for (hash, patches) in [(1u64, &[0u16, 1][..]), (2, &[0])] {
+            let mut bundle = Bundle::new(hash);
+            for patch in patches {
+                bundle.add_version(version(*patch, &[(5, hash * 10), (6, 99 + *patch as u64)]));
+                index.timestamps.insert((hash, Patch::new(*patch)), 1000 + hash);
+                index.fingerprints.insert((hash, Patch::new(*patch)), Fingerprint {
+                    size: 100 + *patch as u64,
+                    sample: hash,
+                    full: Some(hash + 1).filter(|_| *patch == 0),
+                });
+            }
+            index.bundles.push(bundle);
+        }
+        index
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = std::env::temp_dir().join(format!("yarex-mapped-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let index = index(&dir);
+        let mapped = reopen(&index, &dir.join("round_trip.idx"));
+
+        assert_eq!((mapped.hash(), mapped.is_ssd(), mapped.dir()), (7, Some(false), dir.clone()));
+        assert_eq!(mapped.timestamps(), index.timestamps);
+        assert_eq!(mapped.fingerprints(), index.fingerprints);
+        assert_eq!(mapped.bundles_with_name(20), vec![2]);
+        assert_eq!(mapped.bundles_with_name(100), vec![1]);
+        assert_eq!(mapped.bundles_with_name(99), vec![1, 2]);
+        assert_eq!(mapped.bundles_with_name(3), Vec::<u64>::new());
+        assert_eq!(mapped.bundles_with_ext(6), vec![1, 2]);
+        assert!(mapped.bundle(3).is_none());
+
+        let mut loaded = Index::from_mapped(mapped);
+        loaded.load_matching(Some(20), None);
+        assert_eq!(loaded.bundles.iter().map(|bundle| bundle.hash()).collect::<Vec<_>>(), vec![2]);
+        loaded.load_bundles();
+        assert!(loaded.mapped.is_none());
+
+        // saving the loaded index gives the same bytes
+        assert_eq!(write(&loaded).unwrap(), write(&index).unwrap());
+        let version = &loaded.bundles[0].versions()[1];
+        assert_eq!(version.stream_size(), None);
+        assert_eq!(version.bundle_header().properties(), vec![2]);
+        assert_eq!(version.reader().chunk_offsets(), &[16, 33]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn corrupted() {
+        let dir = std::env::temp_dir().join(format!("yarex-corrupted-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("corrupted.idx");
+        let data = write(&index(&dir)).unwrap();
+
+        // cut off fingerprints table
+        std::fs::write(&path, &data[..data.len() - 8]).unwrap();
+        assert!(MappedIndex::open(&File::open(&path).unwrap(), 0, FINGERPRINTS_VERSION).is_err());
+
+        // file range of first version past the end of the files table
+        let mut data = data;
+        let version = layout_size(TABLES) + dir.to_str().unwrap().len() + 3 * 3 * 8;
+        data[version + 7 * 8..version + 8 * 8].copy_from_slice(&100u64.to_le_bytes());
+        std::fs::write(&path, &data).unwrap();
+        assert!(MappedIndex::open(&File::open(&path).unwrap(), 0, FINGERPRINTS_VERSION).is_err());
+
+        // patch numbers that Patch does not allow are rejected instead of panicking
+        let data = write(&index(&dir)).unwrap();
+        let timestamps = layout_size(TABLES) + dir.to_str().unwrap().len();
+        let last_version = version + 2 * RECORD_SIZES[VERSIONS];
+        for (offset, patch) in [(timestamps + 8, 1000u64), (last_version + 8, 0x10000)] {
+            let mut data = data.clone();
+            data[offset..offset + 8].copy_from_slice(&patch.to_le_bytes());
+            std::fs::write(&path, &data).unwrap();
+            let err = MappedIndex::open(&File::open(&path).unwrap(), 0, FINGERPRINTS_VERSION).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
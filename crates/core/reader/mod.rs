@@ -1,28 +1,43 @@
 use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
 use std::fmt::Write as OtherWrite;
-use std::fs::{File, OpenOptions};
+use std::fs::{File, Metadata, OpenOptions};
 use std::path::{Path, PathBuf};
 use std::thread;
 use std::time::{Instant, Duration, SystemTime};
 use std::sync::mpsc;
-use std::sync::Mutex;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
-use stingray::{Bundle, BundleVersion, BundleFile, BundleReader, ReadBuffer, Patch};
-use stingray::format_bundle;
-use stingray::file::{FileKind, get_file_interface, can_file_self_name};
+use stingray::{Bundle, BundleHeader, BundleVersion, BundleFile, BundleReader, BundleStream, ChunkCache, ReadBuffer, Patch, StingrayError, VerifyReport};
+use stingray::{format_bundle, format_stream};
+use stingray::file::{FileKind, get_file_interface_with_stream, can_file_self_name};
 use stingray::hash::{self, KeyMap};
 
+use drive::Storage;
+
 mod files;
 use files::scan_dir_filter;
+pub(crate) use files::scan_dir_streams;
+use files::Fingerprint;
 pub use files::Reader as Reader;
+use files::{BundleFd, Prefetcher};
+
+mod queue;
+use queue::WorkQueue;
+
+mod fds;
+use fds::FdCache;
+
+mod mapped;
+pub use mapped::MappedIndex;
 
 use super::utility::{
     size_to_string,
     load_reader,
+    print_header,
     //format_bundle,
 };
 
@@ -33,37 +48,76 @@ fn calculate_time_hash(time: SystemTime) -> u64 {
     s.finish()
 }
 
+/// Modified time in seconds saved in [Index](Index) timestamps.
+fn modified_secs(metadata: &Metadata) -> u64 {
+    metadata
+        .modified().unwrap()
+        .duration_since(std::time::UNIX_EPOCH).unwrap()
+        .as_secs()
+}
+
+/// Compare directories by their canonical paths if both exist.
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Indexed version `patch` of bundle `hash`.
+fn find_version(bundles: &[Bundle], hash: u64, patch: Patch) -> Option<&BundleVersion> {
+    let i = bundles.binary_search_by(|probe| probe.hash().cmp(&hash)).ok()?;
+    bundles[i].versions().into_iter().find(|version| version.patch() == patch)
+}
+
+/// Stable hash of `bundle_database.data` to detect game updates between runs.
 fn hash_bundle_database(dir: &Path) -> u64 {
     let db = dir.join("bundle_database.data");
     match std::fs::read(db) {
-        Ok(bytes) => {
-            let mut s = DefaultHasher::new();
-            bytes.hash(&mut s);
-            s.finish()
-        }
+        Ok(bytes) => hash::murmur_hash(&bytes),
         Err(_) => 0,
     }
 }
 
+/// Progress of a task for the load bar.
+///
+/// `count` of [Progress](IndexEvent::Progress) goes up to [Size](IndexEvent::Size)
+/// and is a file count or a byte count for dumps of bundles that may be over 4 GiB.
 pub enum IndexEvent {
-    Size(u32),
+    Size(u64),
     Progress {
         read: u64,
-        count: u32,
+        count: u64,
     },
     End,
 }
 
+/// Load index of `dir` from `index_file` or index it.
+///
+/// Caches keep the directory they were saved with unless it no longer exists
+/// or `relocate` is set, which moves them to `dir`.
 pub fn load_index(
     dir: &Path,
+    relocate: bool,
     index_file: Option<&Path>,
     num_threads: usize,
     benchmark: bool,
+    mmap: bool,
+    paranoid: bool,
 ) -> Result<Index, Box<dyn std::error::Error>> {
     let mut index = if let Some(index_file) = index_file {
-        if let Ok(index) = load_reader(index_file) {
-            if !index.has_updated() {
+        if let Ok(mut index) = load_reader(index_file) {
+            // caches follow the game when it moves or is shared with another machine
+            let mut changed = 0;
+            if (relocate || !index.dir.exists()) && !same_dir(&index.dir, dir) {
+                println!("Moving {} from {}", index_file.display(), index.dir.display());
+                changed = index.relocate(dir);
+            }
+
+            // paranoid runs check every bundle even if the game did not update
+            if !paranoid && !index.has_updated() && changed == 0 {
                 println!("Using {}", index_file.display());
+                index.set_mmap(mmap);
                 return Ok(index);
             } else {
                 println!("Updating {}", index_file.display());
@@ -78,24 +132,84 @@ pub fn load_index(
         Index::new(dir)
     };
 
+    index.set_mmap(mmap);
+    index.set_paranoid(paranoid);
     index.index_files_with_progress(num_threads, benchmark)?;
 
     Ok(index)
 }
 
+/// Bundles read at the same time from drives that are slow to seek.
+const HDD_READ_THREADS: usize = 2;
+
+/// Bundles read at the same time from network shares and drives of unknown type.
+const NETWORK_READ_THREADS: usize = 4;
+
+/// Number of bundles read at the same time from `storage` with `num_threads` threads.
+///
+/// Drives that are slow to seek are read by few threads while the other
+/// threads decompress chunks and write files. Network shares gain from a few
+/// requests in flight and SSDs from as many as there are threads.
+fn read_threads(storage: Option<Storage>, num_threads: usize) -> usize {
+    let limit = match storage {
+        Some(Storage::Ssd) => num_threads,
+        Some(Storage::Hdd) => HDD_READ_THREADS,
+        Some(Storage::Network) | None => NETWORK_READ_THREADS,
+    };
+    num_threads.min(limit).max(1)
+}
+
+/// Number of decompressed chunks shared between reader threads.
+const CHUNK_CACHE_SIZE: usize = 256;
+
+fn new_chunk_cache() -> Arc<ChunkCache> {
+    Arc::new(ChunkCache::new(CHUNK_CACHE_SIZE))
+}
+
+fn new_fd_cache() -> Arc<FdCache> {
+    Arc::new(FdCache::with_fd_limit())
+}
+
+/// Result of verifying a single bundle version.
+type BundleReport = (u64, Patch, VerifyReport);
+
 #[cfg_attr(feature = "serde_support", derive(serde::Serialize, serde::Deserialize))]
 pub struct Index {
     dir: PathBuf,
-    is_ssd: bool,
+
+    /// Drive type of `dir` or `None` if it could not be detected or is a network share.
+    is_ssd: Option<bool>,
     hash: u64,
 
     bundles: Vec<Bundle>,
 
     timestamps: HashMap<(u64, Patch), u64>,
 
+    /// Fingerprints of indexed bundles, only saved by the mapped cache.
+    ///
+    /// Bundles indexed before fingerprints were added only have timestamps.
+    #[cfg_attr(feature = "serde_support", serde(skip))]
+    fingerprints: HashMap<(u64, Patch), Fingerprint>,
+
+    /// Cache that bundles missing from `bundles` are read from when needed.
+    #[cfg_attr(feature = "serde_support", serde(skip))]
+    mapped: Option<MappedIndex>,
+
     #[cfg_attr(feature = "serde_support", serde(skip))]
     dirty: bool,
 
+    #[cfg_attr(feature = "serde_support", serde(skip))]
+    mmap: bool,
+
+    #[cfg_attr(feature = "serde_support", serde(skip))]
+    paranoid: bool,
+
+    #[cfg_attr(feature = "serde_support", serde(skip, default = "new_chunk_cache"))]
+    chunk_cache: Arc<ChunkCache>,
+
+    #[cfg_attr(feature = "serde_support", serde(skip, default = "new_fd_cache"))]
+    fds: Arc<FdCache>,
+
     #[cfg_attr(feature = "serde_support", serde(skip))]
     key_map: KeyMap,
 }
@@ -104,15 +218,90 @@ impl Index {
     fn new(dir: &Path) -> Self {
         Self {
             dir: dir.to_owned(),
-            is_ssd: false,
+            is_ssd: None,
             hash: hash_bundle_database(dir),
             bundles: Vec::new(),
             timestamps: HashMap::new(),
+            fingerprints: HashMap::new(),
+            mapped: None,
             dirty: false,
+            mmap: false,
+            paranoid: false,
+            chunk_cache: new_chunk_cache(),
+            fds: new_fd_cache(),
             key_map: KeyMap::default(),
         }
     }
 
+    /// Number of bundles read at the same time from the drive of `dir`.
+    ///
+    /// Network shares are not told apart from drives of unknown type in the cache.
+    fn read_threads(&self, num_threads: usize) -> usize {
+        let storage = self.is_ssd.map(|is_ssd| match is_ssd {
+            true => Storage::Ssd,
+            false => Storage::Hdd,
+        });
+        read_threads(storage, num_threads)
+    }
+
+    /// Move index to the bundles in `dir`.
+    ///
+    /// Bundles are matched by hash, patch, size and fingerprint so copies with
+    /// new modified times are kept. Returns the number of bundles that are new or differ
+    /// and have to be indexed again.
+    pub fn relocate(&mut self, dir: &Path) -> usize {
+        self.load_bundles();
+        self.dir = dir.to_owned();
+        self.dirty = true;
+
+        let bundles = &self.bundles;
+        let fingerprints = &self.fingerprints;
+        let streams = scan_dir_streams(dir);
+        let mut timestamps = HashMap::with_capacity(self.timestamps.len());
+        let changed = scan_dir_filter(dir, |(hash, patch, metadata)| {
+            // bundles not checked when interrupted are indexed next run
+            if crate::interrupt::interrupted() {
+                return true;
+            }
+
+            let key = (*hash, *patch);
+            let same_size = matches!(find_version(bundles, *hash, *patch), Some(version)
+                if version.size() == metadata.len() && version.stream_size() == streams.get(&key).copied());
+            let unchanged = same_size && match fingerprints.get(&key) {
+                Some(saved) => matches!(
+                    Fingerprint::new(&dir.join(format_bundle(*hash, *patch)), metadata.len(), false),
+                    Ok(current) if saved.matches(&current)),
+                None => true,
+            };
+
+            if unchanged {
+                timestamps.insert(key, modified_secs(metadata));
+            }
+            !unchanged
+        });
+
+        for bundle in &mut self.bundles {
+            let hash = bundle.hash();
+            let changed = bundle.versions().iter()
+                .map(|version| version.patch())
+                .filter(|patch| !timestamps.contains_key(&(hash, *patch)))
+                .collect::<Vec<_>>();
+            for patch in changed {
+                bundle.remove_version(patch);
+            }
+        }
+        self.bundles.retain(|bundle| !bundle.versions().is_empty());
+
+        self.fingerprints.retain(|key, _| timestamps.contains_key(key));
+        self.timestamps = timestamps;
+
+        let num_changed = changed.len();
+        let mut found = changed;
+        found.extend(self.timestamps.keys().copied());
+        self.is_ssd = files::detect_ssd(dir, &found);
+        num_changed
+    }
+
     pub fn has_updated(&self) -> bool {
         self.hash != hash_bundle_database(&self.dir)
     }
@@ -126,6 +315,21 @@ impl Index {
         self.dirty
     }
 
+    /// Save index even if nothing was indexed, e.g. after migrating an old save.
+    pub fn set_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Hash whole bundles to find bundles that changed.
+    pub fn set_paranoid(&mut self, enable: bool) {
+        self.paranoid = enable;
+    }
+
+    /// Memory map bundles instead of reading them through file handles.
+    pub fn set_mmap(&mut self, enable: bool) {
+        self.mmap = enable;
+    }
+
     pub fn load_keys(&mut self, path: &Path) {
         if let Ok(fd) = File::open(path) {
             let reader = io::BufReader::new(fd).lines();
@@ -175,7 +379,9 @@ impl Index {
     }
 
     pub fn index_files_with_progress(&mut self, num_threads: usize, unbuffered: bool) -> Result<(), Box<dyn std::error::Error>> {
+        self.load_bundles();
         self.dirty = true;
+        let hash = hash_bundle_database(&self.dir);
         let (tx, rx) = mpsc::channel();
 
         let start = Instant::now();
@@ -196,12 +402,20 @@ impl Index {
             Ok(())
         });
 
-        self.index_files_mt(num_threads, unbuffered, Some(tx.clone()))?; //blocking call
+        let errors = self.index_files_mt(num_threads, unbuffered, Some(tx.clone()))?; //blocking call
+
+        // an unknown database hash makes the next run check every bundle
+        self.hash = match crate::interrupt::interrupted() {
+            false => hash,
+            true => 0,
+        };
 
         tx.send(IndexEvent::End).unwrap();
 
         t.join().unwrap()?;
 
+        print_errors("Skipped bundles that failed to index", &errors);
+
         Ok(())
     }
 
@@ -210,16 +424,19 @@ impl Index {
         num_threads: usize,
         unbuffered: bool,
         send: Option<mpsc::Sender<IndexEvent>>
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<Vec<StingrayError>, Box<dyn std::error::Error>> {
         let files = self.find_and_check_bundles();
         if files.is_empty() {
-            return Ok(());
+            return Ok(Vec::new());
         }
 
         let dir = &self.dir;
+        let streams = &scan_dir_streams(dir);
+        let pending = files.clone();
         let bundles = &Mutex::new(&mut self.bundles);
-        let count = &AtomicU32::new(0);
-        let reader = &Reader::new(false);
+        let errors = &Mutex::new(Vec::new());
+        let count = &AtomicU64::new(0);
+        let reader = &Reader::new(None, self.mmap, self.fds.clone());
 
         crossbeam_utils::thread::scope(|s| {
             reader.open_bundles(s, dir, files, num_threads, unbuffered);
@@ -230,19 +447,50 @@ impl Index {
                 threads.push(s.spawn(move |_| {
                     let mut read_buffer = ReadBuffer::default();
                     while let Some((mut file, hash, patch, is_ssd)) = reader.pop() {
-                        let mut version = BundleVersion::new(patch, file.metadata().unwrap().len());
+                        let size = match file.len() {
+                            Ok(size) => size,
+                            Err(e) => {
+                                errors.lock().unwrap().push(StingrayError::new(&format!(
+                                    "bundle \"{}\" failed to read its size with error: {}",
+                                    format_bundle(hash, patch),
+                                    e)));
+
+                                if let Some(ref send) = send {
+                                    send.send(IndexEvent::Progress {
+                                        read: 0,
+                                        count: 1 + count.fetch_add(1, Ordering::SeqCst),
+                                    }).unwrap();
+                                }
+                                continue;
+                            }
+                        };
+                        let mut version = BundleVersion::new(patch, size);
 
                         let reader = version.reader_mut();
                         reader.ssd_accelerator(is_ssd);
 
-                        #[cfg(target_os = "windows")]
+                        #[cfg(any(target_os = "windows", target_os = "linux"))]
                         reader.unbuffered(unbuffered);
 
-                        let read = version.index(&mut file, hash, &mut read_buffer)
-                            .unwrap_or_else(|e| panic!("bundle \"{}\" failed index with error: {}",
-                                format_bundle(hash, patch),
-                                e));
-                        {
+                        let mut read = match version.index(&mut file, hash, &mut read_buffer) {
+                            Ok(read) => Some(read),
+                            Err(e) => {
+                                errors.lock().unwrap().push(e);
+                                None
+                            }
+                        };
+
+                        if let (Some(read), Some(stream_size)) = (read.as_mut(), streams.get(&(hash, patch))) {
+                            match version.index_stream(&mut file, hash, *stream_size, &mut read_buffer) {
+                                Ok(stream_read) => *read += stream_read,
+                                Err(e) => errors.lock().unwrap().push(StingrayError::new(&format!(
+                                    "stream \"{}\" failed index with error: {}",
+                                    format_stream(hash, patch),
+                                    e))),
+                            }
+                        }
+
+                        if read.is_some() {
                             let mut bundles = bundles.lock().unwrap();
                             match bundles.binary_search_by(|probe| probe.hash().cmp(&hash)) {
                                 Ok(i) => bundles.get_mut(i).unwrap().add_version(version),
@@ -256,7 +504,7 @@ impl Index {
 
                         if let Some(ref send) = send {
                             send.send(IndexEvent::Progress {
-                                read,
+                                read: read.unwrap_or(0),
                                 count: 1 + count.fetch_add(1, Ordering::SeqCst),
                             }).unwrap();
                         }
@@ -265,7 +513,7 @@ impl Index {
             }
 
             if let Some(ref send) = send {
-                send.send(IndexEvent::Size(reader.num_files() as u32)).unwrap();
+                send.send(IndexEvent::Size(reader.num_files())).unwrap();
             }
 
             for thread in threads {
@@ -274,10 +522,152 @@ impl Index {
         }).unwrap();
 
         self.is_ssd = reader.is_ssd();
+        self.forget_unindexed(pending);
+
+        let mut errors = errors.lock().unwrap().drain(..).collect::<Vec<_>>();
+        errors.extend(reader.take_errors());
+        Ok(errors)
+    }
+
+    /// Forget timestamps of `pending` bundles that failed or were skipped when
+    /// interrupted so they are indexed next run, errors do not always know their bundle.
+    fn forget_unindexed(&mut self, pending: Vec<(u64, Patch)>) {
+        for key in pending {
+            if find_version(&self.bundles, key.0, key.1).is_none() {
+                self.timestamps.remove(&key);
+                self.fingerprints.remove(&key);
+            }
+        }
+    }
+
+    pub fn verify_with_progress(
+        &mut self,
+        num_threads: usize,
+        unbuffered: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.load_bundles();
+        let (tx, rx) = mpsc::channel();
+
+        let start = Instant::now();
+        println!();
+        println!("Verifying bundles...");
+        let t = thread::spawn(move || -> io::Result<()> {
+            let (read, count, _size) = load_bar(rx)?;
+
+            let millis = start.elapsed().as_millis();
+            println!(
+                "Verified {} bundles ({}) in {}.{:02} seconds",
+                count,
+                size_to_string(read),
+                millis / 1000,
+                (millis % 1000) / 10
+            );
+
+            Ok(())
+        });
+
+        let (reports, errors) = self.verify_mt(num_threads, unbuffered, Some(tx.clone()))?;
+
+        tx.send(IndexEvent::End).unwrap();
+
+        t.join().unwrap()?;
+
+        print_verify(&reports);
+        print_errors("Skipped bundles that failed to open", &errors);
 
         Ok(())
     }
 
+    fn verify_mt(
+        &mut self,
+        num_threads: usize,
+        unbuffered: bool,
+        send: Option<mpsc::Sender<IndexEvent>>
+    ) -> Result<(Vec<BundleReport>, Vec<StingrayError>), Box<dyn std::error::Error>> {
+        let num_threads = self.read_threads(num_threads);
+
+        let mut versions = Vec::<(u64, &mut BundleVersion)>::new();
+        for bundle in &mut self.bundles {
+            let hash = bundle.hash();
+            for version in bundle.versions_mut() {
+                versions.push((hash, version));
+            }
+        }
+
+        let dir = &self.dir;
+        let filter = versions.iter().map(|(hash, version)| (*hash, version.patch())).collect::<HashSet<_>>();
+        let files = scan_dir_filter(dir, |(bundle, patch, _)| filter.contains(&(*bundle, *patch)));
+        if files.is_empty() {
+            return Ok((Vec::new(), Vec::new()));
+        }
+
+        let versions = &Mutex::new(versions);
+        let reports = &Mutex::new(Vec::new());
+        let count = &AtomicU64::new(0);
+        let learned_any = &AtomicBool::new(false);
+        let reader = &Reader::new(self.is_ssd, self.mmap, self.fds.clone());
+
+        crossbeam_utils::thread::scope(|s| {
+            reader.open_bundles(s, dir, files, num_threads, unbuffered);
+
+            let mut threads = Vec::with_capacity(num_threads);
+            for _ in 0..num_threads {
+                let send = send.as_ref().cloned();
+                threads.push(s.spawn(move |_| {
+                    let mut read_buffer = ReadBuffer::new(ReadBuffer::CHUNK_SIZE * 4);
+                    while let Some((mut fd, bundle_hash, patch, is_ssd)) = reader.pop() {
+                        let (_, version) = {
+                            let mut versions = versions.lock().unwrap();
+                            match versions.binary_search_by(|(version_hash, version)| {
+                                (*version_hash, version.patch()).cmp(&(bundle_hash, patch))
+                            }) {
+                                Ok(i) => versions.remove(i),
+                                Err(_) => panic!("missing data"),
+                            }
+                        };
+
+                        let reader = version.reader_mut();
+                        reader.ssd_accelerator(is_ssd);
+
+                        #[cfg(any(target_os = "windows", target_os = "linux"))]
+                        reader.unbuffered(unbuffered);
+
+                        let known = reader.chunk_offsets().len();
+                        let report = version.verify(&mut fd, bundle_hash, &mut read_buffer);
+                        if version.reader().chunk_offsets().len() > known {
+                            learned_any.store(true, Ordering::Relaxed);
+                        }
+                        reports.lock().unwrap().push((bundle_hash, patch, report));
+
+                        if let Some(ref send) = send {
+                            send.send(IndexEvent::Progress {
+                                read: version.size(),
+                                count: 1 + count.fetch_add(1, Ordering::SeqCst),
+                            }).unwrap();
+                        }
+                    }
+                }));
+            }
+
+            if let Some(ref send) = send {
+                send.send(IndexEvent::Size(reader.num_files())).unwrap();
+            }
+
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        }).unwrap();
+
+        // only save again if reading found chunks missing from the index
+        if learned_any.load(Ordering::Relaxed) {
+            self.dirty = true;
+        }
+
+        let mut reports = reports.lock().unwrap().drain(..).collect::<Vec<_>>();
+        reports.sort_by_key(|(hash, patch, _)| (*hash, *patch));
+        Ok((reports, reader.take_errors()))
+    }
+
     pub fn extract_files_with_progress(
         &mut self,
         out_dir: Option<&Path>,
@@ -286,7 +676,6 @@ impl Index {
         unbuffered: bool,
         hash_fallback: bool,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        self.dirty = true;
         let (tx, rx) = mpsc::channel();
 
         let start = Instant::now();
@@ -310,12 +699,29 @@ impl Index {
             Ok(())
         });
 
-        self.extract_files_mt(out_dir, pattern, num_threads, unbuffered, hash_fallback, Some(tx.clone()))?;
+        let errors = self.extract_files_mt(out_dir, pattern, num_threads, unbuffered, hash_fallback, Some(tx.clone()))?;
 
         tx.send(IndexEvent::End).unwrap();
 
         t.join().unwrap()?;
 
+        if out_dir.is_none() {
+            let cache = &self.chunk_cache;
+            let total = cache.hits() + cache.misses();
+            println!(
+                "Chunk cache: {} hits, {} misses, {} prefetched ({:.1}% hit rate)",
+                cache.hits(),
+                cache.misses(),
+                cache.prefetched(),
+                match total {
+                    0 => 0.,
+                    _ => cache.hits() as f64 / total as f64 * 100.,
+                }
+            );
+        }
+
+        print_errors("Skipped files that failed to extract", &errors);
+
         Ok(())
     }
 
@@ -323,14 +729,14 @@ impl Index {
         &mut self,
         out_dir: Option<&Path>,
         pattern: &str,
-        mut num_threads: usize,
+        num_threads: usize,
         unbuffered: bool,
         hash_fallback: bool,
         send: Option<mpsc::Sender<IndexEvent>>
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        if !self.is_ssd {
-            num_threads = 1;
-        }
+    ) -> Result<Vec<StingrayError>, Box<dyn std::error::Error>> {
+        // threads left for decompressing chunks when a drive can only take one reader
+        let num_cpus = num_threads;
+        let read_threads = self.read_threads(num_threads);
 
         let mut split: Vec<&str> = pattern.split('.').collect();
 
@@ -371,6 +777,10 @@ impl Index {
             }
         };
 
+        self.load_matching(
+            Some(name_hash).filter(|_| path != "*"),
+            Some(extension_hash).filter(|_| last != "*"));
+
         let mut num_files = 0;
         let mut set = HashSet::new();
         let mut bundles = Vec::<(u64, &mut BundleVersion, Vec<(u64, u64)>)>::new();
@@ -419,7 +829,7 @@ impl Index {
                 send.send(IndexEvent::End).unwrap();
             }
 
-            return Ok(());
+            return Ok(Vec::new());
         }
 
         let dir = &self.dir;
@@ -427,124 +837,214 @@ impl Index {
         let files = scan_dir_filter(dir,
             |(bundle, patch, _)| filter.contains(&(*bundle, Patch::from(*patch))));
         if files.is_empty() {
-            return Ok(());
+            return Ok(Vec::new());
         }
 
         let bundles = &Mutex::new(bundles);
-        let count = &AtomicU32::new(0);
-        let reader = &Reader::new(self.is_ssd);
+        let errors = &Mutex::new(Vec::new());
+        let count = &AtomicU64::new(0);
+        let busy = &AtomicUsize::new(0);
+        let learned_any = &AtomicBool::new(false);
+        let cache = &self.chunk_cache;
+        let reader = &Reader::new(self.is_ssd, self.mmap, self.fds.clone());
+        let queue = &WorkQueue::new(read_threads);
         let key_map = &self.key_map;
 
+        // ignore unknown extensions
+        let is_extracted = &|ext_hash: u64, hash: u64| {
+            key_map.get_key(ext_hash).is_some()
+                && (hash_fallback
+                    || key_map.get_key(hash).is_some()
+                    || can_file_self_name(ext_hash))
+        };
+
+        let (write_send, write_recv) = mpsc::sync_channel::<WriteJob>(num_threads * WRITE_QUEUE_DEPTH);
+        let write_recv = &Mutex::new(write_recv);
+
         crossbeam_utils::thread::scope(|s| {
             reader.open_bundles(s, dir, files, num_threads, unbuffered);
 
-            let mut threads = Vec::with_capacity(num_threads);
-            for _ in 0..num_threads {
+            let mut threads = Vec::with_capacity(read_threads + num_threads);
+
+            // decompiling and writing files is left to writer threads so reads
+            // are not held up by disk output
+            if let Some(out_dir) = out_dir {
+                for _ in 0..num_threads {
+                    let send = send.as_ref().cloned();
+                    threads.push(s.spawn(move |_| {
+                        let mut hash_buffer = String::with_capacity(16);
+                        let mut ext_buffer = String::with_capacity(16);
+                        let mut path_buffer = PathBuf::with_capacity(512);
+
+                        loop {
+                            let job = write_recv.lock().unwrap().recv();
+                            let job = match job {
+                                Ok(job) => job,
+                                Err(_) => break,
+                            };
+
+                            match job.write(out_dir, hash_fallback, &mut hash_buffer, &mut ext_buffer, &mut path_buffer) {
+                                Ok(true) => if let Some(ref send) = send {
+                                    send.send(IndexEvent::Progress {
+                                        read: 0,
+                                        count: 1 + count.fetch_add(1, Ordering::Relaxed),
+                                    }).unwrap();
+                                },
+                                Ok(false) => (),
+                                Err(e) => errors.lock().unwrap().push(e),
+                            }
+                        }
+                    }));
+                }
+            }
+
+            for worker in 0..read_threads {
                 let send = send.as_ref().cloned();
-                threads.push(s.spawn(move |_| {
+                let write_send = write_send.clone();
+                threads.push(s.spawn(move |s| {
                     let mut read_buffer = ReadBuffer::new(ReadBuffer::CHUNK_SIZE * 4);
-                    let mut hash_buffer = String::with_capacity(16);
-                    let mut ext_buffer = String::with_capacity(16);
-                    let mut path_buffer = PathBuf::with_capacity(512);
+                    read_buffer.set_cache(cache.clone());
+                    let mut prefetcher = Prefetcher::spawn(s, cache.clone());
+                    let mut stream_buffer = Vec::new();
+
+                    let mut producing = true;
+                    loop {
+                        let task = match queue.try_pop(worker) {
+                            Some(task) => task,
+                            None if producing => {
+                                match reader.pop() {
+                                    Some((fd, bundle_hash, patch, is_ssd)) => {
+                                        let (_, version, mut files) = {
+                                            let mut bundles = bundles.lock().unwrap();
+                                            if let Ok(i) = bundles.binary_search_by(|(version_hash, version, ..)| {
+                                                (*version_hash, version.patch()).cmp(&(bundle_hash, Patch::from(patch)))
+                                            }) {
+                                                bundles.remove(i)
+                                            } else {
+                                                panic!("missing data")
+                                            }
+                                        };
 
-                    while let Some((mut fd, bundle_hash, patch, is_ssd)) = reader.pop() {
-                        let (_, version, files) = {
-                            let mut bundles = bundles.lock().unwrap();
-                            if let Ok(i) = bundles.binary_search_by(|(version_hash, version, ..)| {
-                                (*version_hash, version.patch()).cmp(&(bundle_hash, Patch::from(patch)))
-                            }) {
-                                bundles.remove(i)
-                            } else {
-                                panic!("missing data")
+                                        let reader = version.reader_mut();
+                                        reader.ssd_accelerator(is_ssd);
+
+                                        #[cfg(any(target_os = "windows", target_os = "linux"))]
+                                        reader.unbuffered(unbuffered);
+
+                                        files.retain(|(ext_hash, hash)| is_extracted(*ext_hash, *hash));
+                                        for task in ExtractTask::split(bundle_hash, patch, version, learned_any, fd, files) {
+                                            queue.push(worker, task);
+                                        }
+                                    }
+                                    None => {
+                                        producing = false;
+                                        queue.close();
+                                    }
+                                }
+                                continue;
                             }
+                            None => match queue.pop(worker) {
+                                Some(task) => task,
+                                None => break,
+                            },
                         };
 
-                        let reader = version.reader_mut();
-                        reader.ssd_accelerator(is_ssd);
+                        busy.fetch_add(1, Ordering::SeqCst);
+                        // files are sorted by offset so chunks are prefetched in read order
+                        let chunks = task.chunks();
+                        let ExtractTask { bundle_hash, patch, version, mut fd, files } = task;
+                        let mut bundle_reader = version.version.reader().clone();
+
+                        let mut stream_fd = match version.version.stream_size() {
+                            Some(_) => match File::open(dir.join(format_stream(bundle_hash, patch))) {
+                                Ok(fd) => Some(fd),
+                                Err(e) => {
+                                    errors.lock().unwrap().push(StingrayError::new(&format!(
+                                        "stream \"{}\" failed to open with error: {}",
+                                        format_stream(bundle_hash, patch),
+                                        e)));
+                                    None
+                                }
+                            },
+                            None => None,
+                        };
 
-                        #[cfg(target_os = "windows")]
-                        reader.unbuffered(unbuffered);
+                        prefetcher.start(&fd, &bundle_reader, chunks);
 
                         let mut files_read = 0;
                         let mut read = 0;
                         for (ext_hash, hash) in &files {
-                            let name_key = key_map.get_key(*hash);
-                            let ext_key = key_map.get_key(*ext_hash);
-
-                            // ignore unknown extensions
-                            if ext_key.is_some()
-                                && (hash_fallback
-                                    || name_key.is_some()
-                                    || can_file_self_name(*ext_hash))
-                            {
-                                let buffer = version.read_file(
-                                    &mut fd,
-                                    bundle_hash,
-                                    *ext_hash,
-                                    *hash,
-                                    &mut read_buffer,
-                                ).unwrap_or_else(|e| panic!("bundle \"{}\" failed index with error: {}",
-                                    format_bundle(*hash, patch),
-                                    e));
-
-                                read += buffer.len() as u64;
-
-                                if let Some(out_dir) = out_dir {
-                                    let mut i_file = get_file_interface(buffer).unwrap();
-
-                                    let (self_name, self_ext) = i_file.path();
-
-                                    let name = match name_key {
-                                        Some(name) => name,
-                                        None => {
-                                            if let Some(name) = self_name {
-                                                name
-                                            } else if hash_fallback {
-                                                hash_buffer.clear();
-                                                write!(hash_buffer, "{:016x}", *hash).unwrap();
-                                                hash_buffer.as_str()
-                                            } else {
-                                                continue
-                                            }
-                                        }
-                                    };
-
-                                    let ext = match self_ext {
-                                        Some(ext) => ext,
-                                        None => {
-                                            if let Some(ext) = ext_key {
-                                                ext
-                                            } else if hash_fallback {
-                                                ext_buffer.clear();
-                                                write!(ext_buffer, "{:016x}", *hash).unwrap();
-                                                ext_buffer.as_str()
-                                            } else {
-                                                continue
-                                            }
-                                        }
-                                    };
+                            // files already read are still written when interrupted
+                            if crate::interrupt::interrupted() {
+                                break;
+                            }
 
-                                    path_buffer.clear();
-                                    path_buffer.push(out_dir);
-                                    path_buffer.push(name);
-                                    path_buffer.set_extension(ext);
+                            if let Some(file) = version.version.file(*ext_hash, *hash) {
+                                prefetcher.advance(chunk_of(file.offset()));
+                            }
+
+                            // split idle cores between tasks still being read
+                            // so large bundles at the end of the queue use every core
+                            let threads = num_cpus / busy.load(Ordering::SeqCst).max(1);
+                            bundle_reader.set_threads(threads);
+
+                            let buffer = match version.version.read_file_with(
+                                &mut bundle_reader,
+                                &mut fd,
+                                bundle_hash,
+                                *ext_hash,
+                                *hash,
+                                &mut read_buffer,
+                            ) {
+                                Ok(buffer) => buffer,
+                                Err(e) => {
+                                    errors.lock().unwrap().push(e);
+                                    continue;
+                                }
+                            };
 
-                                    if let Some(dir) = path_buffer.parent() {
-                                        //println!("{}", dir.display());
-                                        std::fs::create_dir_all(&dir).unwrap();
+                            let stream = match stream_fd {
+                                Some(ref mut stream_fd) => match version.version.read_stream(
+                                    stream_fd,
+                                    *ext_hash,
+                                    *hash,
+                                    &mut stream_buffer,
+                                ) {
+                                    Ok(stream) => stream,
+                                    Err(e) => {
+                                        errors.lock().unwrap().push(StingrayError::new(&format!(
+                                            "stream \"{}\" failed read with error: {}",
+                                            format_stream(bundle_hash, patch),
+                                            e)));
+                                        continue;
                                     }
+                                },
+                                None => &[],
+                            };
 
-                                    let mut fd = OpenOptions::new()
-                                        .write(true)
-                                        .truncate(true)
-                                        .create(true)
-                                        .open(&path_buffer).unwrap();
-                                    i_file.decompile(&mut fd).unwrap();
-                                }
+                            read += (buffer.len() + stream.len()) as u64;
+
+                            if out_dir.is_some() {
+                                // writers count files once they are written
+                                write_send.send(WriteJob {
+                                    bundle_hash,
+                                    patch,
+                                    hash: *hash,
+                                    name_key: key_map.get_key(*hash),
+                                    ext_key: key_map.get_key(*ext_hash),
+                                    data: buffer.to_vec(),
+                                    stream: stream.to_vec(),
+                                }).unwrap();
+                            } else {
                                 files_read += 1;
                             }
                         }
 
+                        prefetcher.finish();
+                        version.learned.lock().unwrap().merge(&bundle_reader);
+                        busy.fetch_sub(1, Ordering::SeqCst);
+                        queue.done();
+
                         if let Some(ref send) = send {
                             send.send(IndexEvent::Progress {
                                 read,
@@ -554,9 +1054,10 @@ impl Index {
                     }
                 }));
             }
+            drop(write_send);
 
             if let Some(ref send) = send {
-                send.send(IndexEvent::Size(num_files as u32)).unwrap();
+                send.send(IndexEvent::Size(num_files as u64)).unwrap();
             }
 
             for thread in threads {
@@ -564,36 +1065,69 @@ impl Index {
             }
         }).unwrap();
 
-        Ok(())
+        // only save again if reading found chunks missing from the index
+        if learned_any.load(Ordering::Relaxed) {
+            self.dirty = true;
+        }
+
+        let mut errors = errors.lock().unwrap().drain(..).collect::<Vec<_>>();
+        errors.extend(reader.take_errors());
+        Ok(errors)
     }
 
     fn find_and_check_bundles(&mut self) -> Vec<(u64, Patch)> {
         let incremental = !self.bundles.is_empty();
         let dir = &self.dir;
+        let paranoid = self.paranoid;
         let timestamps = &mut self.timestamps;
+        let fingerprints = &mut self.fingerprints;
+        let mut new_fingerprints = HashMap::with_capacity(timestamps.len());
+        // interrupted runs fall back to timestamps and keep the saved fingerprints
+        let fingerprint = |hash: u64, patch: Patch, metadata: &Metadata| {
+            if crate::interrupt::interrupted() {
+                return None;
+            }
+            Fingerprint::new(&dir.join(format_bundle(hash, patch)), metadata.len(), paranoid).ok()
+        };
 
-        if incremental {
+        let files = if incremental {
             let mut new_timestamps = HashMap::with_capacity(timestamps.len());
 
+            // `.stream` files are only checked by size since their bundle keeps the timestamp
+            let streams = scan_dir_streams(dir);
             let bundles = &mut self.bundles;
             let files = scan_dir_filter(dir, |(hash, patch, metadata)| {
-                let time = metadata
-                    .modified().unwrap()
-                    .duration_since(std::time::UNIX_EPOCH).unwrap()
-                    .as_secs();
+                let time = modified_secs(metadata);
+                let current = fingerprint(*hash, *patch, metadata);
+                let saved = fingerprints.get(&(*hash, *patch));
+                if let Some(current) = current {
+                    new_fingerprints.insert((*hash, *patch), current);
+                }
 
                 if let Some(prev_time) = timestamps.remove(&(*hash, *patch)) {
                     new_timestamps.insert((*hash, *patch), time);
-                    if time != prev_time {
-                        match bundles.binary_search_by(|probe| probe.hash().cmp(hash)) {
-                            Ok(i) => {
-                                let bundle = bundles.get_mut(i).unwrap();
-                                bundle.remove_version(*patch);
-                            }
-                            Err(_) => panic!("no existing bundle matching parallel timestamp"),
+
+                    // fingerprints catch rewrites that keep the modified time
+                    // and copies that only change it
+                    let changed = match (saved, current) {
+                        (Some(saved), Some(current)) => !saved.matches(&current),
+                        _ => time != prev_time,
+                    } || matches!(find_version(bundles, *hash, *patch),
+                        Some(version) if version.stream_size() != streams.get(&(*hash, *patch)).copied());
+
+                    // timestamps without a version are from caches that kept failed bundles
+                    if changed || find_version(bundles, *hash, *patch).is_none() {
+                        if let Ok(i) = bundles.binary_search_by(|probe| probe.hash().cmp(hash)) {
+                            bundles[i].remove_version(*patch);
                         }
                         true
                     } else {
+                        // keep the whole file hash of an earlier paranoid run
+                        if let Some(saved) = saved {
+                            if !matches!(current, Some(current) if current.full.is_some()) {
+                                new_fingerprints.insert((*hash, *patch), *saved);
+                            }
+                        }
                         false
                     }
                 } else {
@@ -605,12 +1139,8 @@ impl Index {
             std::mem::swap(&mut new_timestamps, timestamps);
             let old_timestamps = new_timestamps;
             for ((hash, patch), ..) in old_timestamps {
-                match bundles.binary_search_by(|probe| probe.hash().cmp(&hash)) {
-                    Ok(i) => {
-                        let bundle = bundles.get_mut(i).unwrap();
-                        bundle.remove_version(patch);
-                    }
-                    Err(_) => panic!("no bundle matching parallel timestamp for removal"),
+                if let Ok(i) = bundles.binary_search_by(|probe| probe.hash().cmp(&hash)) {
+                    bundles[i].remove_version(patch);
                 }
             }
 
@@ -619,21 +1149,252 @@ impl Index {
             timestamps.clear();
 
             scan_dir_filter(dir, |(hash, patch, metadata)| {
-                let time = metadata
-                    .modified().unwrap()
-                    .duration_since(std::time::UNIX_EPOCH).unwrap()
-                    .as_secs();
+                let time = modified_secs(metadata);
+                if let Some(current) = fingerprint(*hash, *patch, metadata) {
+                    new_fingerprints.insert((*hash, *patch), current);
+                }
 
                 timestamps.insert((*hash, *patch), time);
 
                 true
             })
+        };
+
+        self.fingerprints = new_fingerprints;
+        files
+    }
+}
+
+/// Uncompressed bytes of files per [ExtractTask](ExtractTask) when splitting big bundles.
+const TASK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Files each writer thread can have waiting before readers block.
+const WRITE_QUEUE_DEPTH: usize = 4;
+
+/// Bundle version shared by the tasks it was split into.
+///
+/// Chunks learned by every task are kept in the index once the last task is done.
+struct SharedVersion<'a> {
+    version: &'a mut BundleVersion,
+    learned: Mutex<BundleReader>,
+
+    /// Set if any version learned new chunks.
+    learned_any: &'a AtomicBool,
+}
+
+impl Drop for SharedVersion<'_> {
+    fn drop(&mut self) {
+        if self.version.reader_mut().merge(self.learned.get_mut().unwrap()) {
+            self.learned_any.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Files of a bundle version read in order by one worker.
+struct ExtractTask<'a> {
+    bundle_hash: u64,
+    patch: Patch,
+    version: Arc<SharedVersion<'a>>,
+    fd: BundleFd,
+    files: Vec<(u64, u64)>,
+}
+
+impl<'a> ExtractTask<'a> {
+    /// Split `files` into tasks of about [TASK_SIZE](TASK_SIZE) so idle workers can take part of a big bundle.
+    fn split(
+        bundle_hash: u64,
+        patch: Patch,
+        version: &'a mut BundleVersion,
+        learned_any: &'a AtomicBool,
+        fd: BundleFd,
+        files: Vec<(u64, u64)>,
+    ) -> Vec<Self> {
+        let mut slices = vec![Vec::new()];
+        let mut size = 0;
+        for key in files {
+            if size >= TASK_SIZE {
+                slices.push(Vec::new());
+                size = 0;
+            }
+
+            size += version.file(key.0, key.1).map_or(0, |file| file.size());
+            slices.last_mut().unwrap().push(key);
+        }
+
+        // every task needs its own handle, read with one worker if the bundle can't be opened again
+        let mut fds = Vec::with_capacity(slices.len());
+        while fds.len() + 1 < slices.len() {
+            match fd.try_clone() {
+                Ok(fd) => fds.push(fd),
+                Err(_) => break,
+            }
+        }
+        fds.push(fd);
+        if fds.len() < slices.len() {
+            slices = vec![slices.concat()];
+            fds.drain(..fds.len() - 1);
+        }
+
+        let learned = Mutex::new(version.reader().clone());
+        let version = Arc::new(SharedVersion { version, learned, learned_any });
+        slices.into_iter().zip(fds).map(|(files, fd)| Self {
+            bundle_hash,
+            patch,
+            version: version.clone(),
+            fd,
+            files,
+        }).collect()
+    }
+
+    /// Chunks holding the files of the task in read order.
+    fn chunks(&self) -> Vec<usize> {
+        let mut chunks = Vec::new();
+        for file in self.files.iter().filter_map(|(ext_hash, hash)| self.version.version.file(*ext_hash, *hash)) {
+            let end = file.offset() + file.size() + 36;
+            for chunk in chunk_of(file.offset())..=chunk_of(end - 1) {
+                if !matches!(chunks.last(), Some(last) if *last >= chunk) {
+                    chunks.push(chunk);
+                }
+            }
+        }
+        chunks
+    }
+}
+
+/// Chunk holding uncompressed bundle offset `offset`.
+fn chunk_of(offset: u64) -> usize {
+    offset as usize / ReadBuffer::CHUNK_SIZE
+}
+
+/// File waiting to be decompiled and written by a writer thread.
+struct WriteJob<'a> {
+    bundle_hash: u64,
+    patch: Patch,
+    hash: u64,
+    name_key: Option<&'a str>,
+    ext_key: Option<&'a str>,
+    data: Vec<u8>,
+    stream: Vec<u8>,
+}
+
+impl WriteJob<'_> {
+    /// Returns `false` if the file was skipped for not having a name.
+    fn write(
+        &self,
+        out_dir: &Path,
+        hash_fallback: bool,
+        hash_buffer: &mut String,
+        ext_buffer: &mut String,
+        path_buffer: &mut PathBuf,
+    ) -> Result<bool, StingrayError> {
+        let mut i_file = get_file_interface_with_stream(&self.data, &self.stream)?;
+
+        let (self_name, self_ext) = i_file.path();
+
+        let name = match self.name_key {
+            Some(name) => name,
+            None => {
+                if let Some(name) = self_name {
+                    name
+                } else if hash_fallback {
+                    hash_buffer.clear();
+                    write!(hash_buffer, "{:016x}", self.hash).unwrap();
+                    hash_buffer.as_str()
+                } else {
+                    return Ok(false);
+                }
+            }
+        };
+
+        let ext = match self_ext {
+            Some(ext) => ext,
+            None => {
+                if let Some(ext) = self.ext_key {
+                    ext
+                } else if hash_fallback {
+                    ext_buffer.clear();
+                    write!(ext_buffer, "{:016x}", self.hash).unwrap();
+                    ext_buffer.as_str()
+                } else {
+                    return Ok(false);
+                }
+            }
+        };
+
+        path_buffer.clear();
+        path_buffer.push(out_dir);
+        path_buffer.push(name);
+        path_buffer.set_extension(ext);
+
+        if let Some(dir) = path_buffer.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| self.error(path_buffer, "create its directory", e))?;
+        }
+
+        // files are renamed once complete so exiting early never leaves them half written
+        let mut part_path = path_buffer.clone().into_os_string();
+        part_path.push(".part");
+        let part_path = PathBuf::from(part_path);
+
+        let mut fd = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .open(&part_path)
+            .map_err(|e| self.error(&part_path, "open", e))?;
+        if let Err(e) = i_file.decompile(&mut fd) {
+            drop(fd);
+            let _ = std::fs::remove_file(&part_path);
+            return Err(self.error(path_buffer, "decompile", e));
         }
+        drop(fd);
+        std::fs::rename(&part_path, &path_buffer).map_err(|e| self.error(path_buffer, "rename", e))?;
+
+        Ok(true)
+    }
+
+    fn error(&self, path: &Path, action: &str, e: impl std::fmt::Display) -> StingrayError {
+        StingrayError::new(&format!(
+            "file \"{}\" in bundle \"{}\" failed to {} with error: {}",
+            path.display(),
+            format_bundle(self.bundle_hash, self.patch),
+            action,
+            e))
+    }
+}
+
+/// Writer that reports bytes written to the load bar.
+struct ProgressWriter<'a, W> {
+    inner: &'a mut W,
+    send: mpsc::Sender<IndexEvent>,
+    total: u64,
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        self.total += size as u64;
+        self.send.send(IndexEvent::Progress {
+            read: size as u64,
+            count: self.total,
+        }).unwrap();
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
-pub fn decompress_bundle(fd: &mut File, target: &mut File) {
-    let chunk_size = ReadBuffer::CHUNK_SIZE as u64;
+pub fn decompress_bundle(fd: &mut File, target: &mut File) -> io::Result<()> {
+    let mut stream = BundleStream::new(fd)?;
+
+    let mut header = [0; 260];
+    stream.read_exact(&mut header)?;
+    match BundleHeader::from_bytes(&header[4..]) {
+        Ok(header) => print_header(&header),
+        Err(e) => eprintln!("failed to parse bundle header: {}", e),
+    }
+    stream.seek(SeekFrom::Start(0))?;
 
     let (tx, rx) = mpsc::channel();
 
@@ -655,45 +1416,76 @@ pub fn decompress_bundle(fd: &mut File, target: &mut File) {
         Ok(())
     });
 
-    let mut buffer = ReadBuffer::default();
-
-    let mut reader = BundleReader::new();
+    tx.send(IndexEvent::Size(stream.len())).unwrap();
 
-    // workaround to get BundleReader to read bundle's uncompressed size
-    reader.read(fd, &mut buffer, 0..256, None).unwrap();
-
-    tx.send(IndexEvent::Size(reader.size() as u32)).unwrap();
+    let len = stream.len();
+    let mut target = ProgressWriter {
+        inner: target,
+        send: tx.clone(),
+        total: 0,
+    };
+    let copied = target.inner.set_len(len).and_then(|_| io::copy(&mut stream, &mut target));
 
-    target.set_len(reader.size()).unwrap();
-    let last = reader.size() % chunk_size;
+    // load bar is stopped before reporting a failed copy
+    tx.send(IndexEvent::End).unwrap();
 
-    let mut chunks = reader.size() / chunk_size;
-    if last > 0 { chunks += 1; }
+    t.join().unwrap()?;
+    copied.map(|_| ())
+}
 
-    let mut total = 0;
-    for i in 0..chunks {
-        let offset = (i * chunk_size) as usize;
+fn print_errors(title: &str, errors: &[StingrayError]) {
+    if errors.is_empty() {
+        return;
+    }
 
-        let size = if i == chunks - 1 {
-            last
-        } else {
-            chunk_size
-        } as usize;
+    eprintln!();
+    eprintln!("{} ({}):", title, errors.len());
+    for e in errors {
+        eprintln!("  {}", e);
+    }
+}
 
-        let out = reader.read(fd, &mut buffer, offset..offset + size, None).unwrap();
+fn print_verify(reports: &[BundleReport]) {
+    let mut chunks = 0;
+    let mut files = 0;
+    let mut num_diff = 0;
+    let mut num_errors = 0;
+    for (.., report) in reports {
+        chunks += report.chunks();
+        files += report.files();
+        if report.diff() > 0 {
+            num_diff += 1;
+        }
+        if !report.is_ok() {
+            num_errors += 1;
+        }
+    }
 
-        target.write_all(out).unwrap();
+    println!();
+    println!("Checked {} chunks and {} files", chunks, files);
+    println!("  bundles with size diff: {}", num_diff);
+    println!("  bundles with errors: {}", num_errors);
 
-        total += size;
-        tx.send(IndexEvent::Progress {
-            read: size as u64,
-            count: total as u32,
-        }).unwrap();
+    if num_diff == 0 && num_errors == 0 {
+        return;
     }
 
-    tx.send(IndexEvent::End).unwrap();
+    println!();
+    println!("{:<28} {:<10} {:<7} Skipped", "Bundle", "Diff", "Errors");
+    for (hash, patch, report) in reports {
+        if report.diff() == 0 && report.is_ok() {
+            continue;
+        }
 
-    t.join().unwrap().unwrap();
+        println!("{:<28} {:<10} {:<7} {}",
+            format_bundle(*hash, *patch),
+            report.diff(),
+            report.errors().len(),
+            report.skipped());
+        for e in report.errors() {
+            println!("    {}", e);
+        }
+    }
 }
 
 fn load_bar(rx: mpsc::Receiver<IndexEvent>) -> io::Result<(u64, u64, u64)> {
@@ -723,7 +1515,7 @@ fn load_bar(rx: mpsc::Receiver<IndexEvent>) -> io::Result<(u64, u64, u64)> {
                     IndexEvent::Size(size) => total_size = Some(size),
                     IndexEvent::Progress { read, count } => {
                         total_read += read;
-                        total_count = count as u64;
+                        total_count = count;
                     }
                     IndexEvent::End => break is_done = true,
                 },
@@ -787,10 +1579,443 @@ fn load_bar(rx: mpsc::Receiver<IndexEvent>) -> io::Result<(u64, u64, u64)> {
         }
     }
 
-    let total_size = total_size.map(|n| n as u64);
     Ok((total_read, total_count, total_size.unwrap_or(total_count)))
 }
 
+#[cfg(test)]
+mod test {
+    use std::convert::TryInto;
+
+    use stingray::BundleWriter;
+    use stingray::file::{FileVariant, Language};
+
+    use super::*;
+
+    /// Payload of config file `i` in bundle `hash`.
+    fn file_data(i: u64, hash: u64, len: usize) -> Vec<u8> {
+        (0..len).map(|n| (n as u64 ^ i ^ hash) as u8).collect()
+    }
+
+    /// Write bundle with `num_files` config files of `len` bytes to `dir`.
+    fn write_bundle(dir: &Path, hash: u64, patch: Patch, num_files: u64, len: usize) {
+        let mut writer = BundleWriter::new(6).unwrap();
+        for i in 0..num_files {
+            let variants = vec![FileVariant::new(Language::English, len as u32)];
+            writer.add_file(FileKind::config as u64, i, variants, file_data(i, hash, len)).unwrap();
+        }
+
+        let mut bundle = Vec::new();
+        writer.write(&mut bundle).unwrap();
+        std::fs::write(dir.join(format_bundle(hash, patch)), bundle).unwrap();
+    }
+
+    /// Write bundle with one config file of `len` bytes of noise to `dir`.
+    ///
+    /// Noise does not compress so every chunk is stored raw.
+    fn write_noise_bundle(dir: &Path, hash: u64, len: usize) {
+        let mut state = hash;
+        let payload = (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect::<Vec<_>>();
+
+        let mut writer = BundleWriter::new(6).unwrap();
+        let variants = vec![FileVariant::new(Language::English, len as u32)];
+        writer.add_file(FileKind::config as u64, 0, variants, payload).unwrap();
+        let mut bundle = Vec::new();
+        writer.write(&mut bundle).unwrap();
+        std::fs::write(dir.join(format_bundle(hash, Patch::new_base())), bundle).unwrap();
+    }
+
+    /// Write base bundles of `(hash, num_files, len)` to a new temporary directory and index them.
+    fn indexed(name: &str, bundles: &[(u64, u64, usize)]) -> (PathBuf, Index) {
+        let dir = std::env::temp_dir().join(format!("yarex-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for &(hash, num_files, len) in bundles {
+            write_bundle(&dir, hash, Patch::new_base(), num_files, len);
+        }
+
+        let mut index = Index::new(&dir);
+        assert!(index.index_files_mt(1, false, None).unwrap().is_empty());
+        (dir, index)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn unbuffered_reads() {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let base = Patch::new_base();
+        let len = 3 * ReadBuffer::CHUNK_SIZE + 7;
+        let (dir, _) = indexed("unbuffered", &[(1, 3, len)]);
+
+        let path = dir.join(format_bundle(1, base));
+        let fd = match OpenOptions::new().read(true).custom_flags(libc::O_DIRECT).open(&path) {
+            Ok(fd) => fd,
+            // some file systems like tmpfs do not support O_DIRECT
+            Err(_) => {
+                let _ = std::fs::remove_dir_all(&dir);
+                return;
+            }
+        };
+        let mut fd = BundleFd::File(Arc::new(fd));
+
+        let mut version = BundleVersion::new(base, fd.len().unwrap());
+        version.reader_mut().unbuffered(true);
+        // buffer size is rounded up to whole aligned blocks
+        let mut buffer = ReadBuffer::new(ReadBuffer::CHUNK_SIZE + 5000);
+        version.index(&mut fd, 1, &mut buffer).unwrap();
+
+        // out of order so reads seek backwards
+        for &i in &[2u64, 0, 1, 2] {
+            let out = version.read_file(&mut fd, 1, FileKind::config as u64, i, &mut buffer).unwrap();
+            assert_eq!(out[36..], file_data(i, 1, len)[..]);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn storage_read_threads() {
+        assert_eq!(read_threads(Some(Storage::Ssd), 12), 12);
+        assert_eq!(read_threads(Some(Storage::Hdd), 12), HDD_READ_THREADS);
+        assert_eq!(read_threads(Some(Storage::Network), 12), NETWORK_READ_THREADS);
+        assert_eq!(read_threads(None, 12), NETWORK_READ_THREADS);
+        assert_eq!(read_threads(None, 3), 3);
+        assert_eq!(read_threads(Some(Storage::Hdd), 0), 1);
+
+        // cached drive types map to a storage class
+        let mut index = Index::new(&std::env::temp_dir());
+        index.is_ssd = Some(false);
+        assert_eq!(index.read_threads(12), HDD_READ_THREADS);
+        index.is_ssd = Some(true);
+        assert_eq!(index.read_threads(12), 12);
+    }
+
+    #[test]
+    fn split_tasks() {
+        let (dir, _) = indexed("split-tasks", &[]);
+        let base = Patch::new_base();
+        let len = TASK_SIZE as usize / 3 + 1;
+
+        // raw chunks so big files are quick to write
+        let mut writer = BundleWriter::new(6).unwrap();
+        writer.set_codec(Arc::new(stingray::codec::Stored));
+        for i in 0..4u64 {
+            let variants = vec![FileVariant::new(Language::English, len as u32)];
+            writer.add_file(FileKind::config as u64, i, variants, vec![1; len]).unwrap();
+        }
+        let mut bundle = Vec::new();
+        writer.write(&mut bundle).unwrap();
+        let path = dir.join(format_bundle(1, base));
+        std::fs::write(&path, &bundle).unwrap();
+
+        let mut version = BundleVersion::new(base, bundle.len() as u64);
+        version.reader_mut().set_codec(Arc::new(stingray::codec::Stored));
+        version.index(&mut stingray::MappedBundle::new(&bundle), 1, &mut ReadBuffer::default()).unwrap();
+
+        // a task takes files until it holds at least TASK_SIZE bytes
+        let learned_any = AtomicBool::new(false);
+        let files = (0..4u64).map(|i| (FileKind::config as u64, i)).collect::<Vec<_>>();
+        let fd = BundleFd::File(Arc::new(File::open(&path).unwrap()));
+        let tasks = ExtractTask::split(1, base, &mut version, &learned_any, fd, files.clone());
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[0].files, files[..3]);
+        assert_eq!(tasks[1].files, files[3..]);
+        assert!(Arc::ptr_eq(&tasks[0].version, &tasks[1].version));
+        assert!(tasks.iter().all(|task| (task.bundle_hash, task.patch) == (1, base)));
+        drop(tasks);
+
+        // small bundles are one task
+        let fd = BundleFd::File(Arc::new(File::open(&path).unwrap()));
+        let tasks = ExtractTask::split(1, base, &mut version, &learned_any, fd, files[..2].to_vec());
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].files, files[..2]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn prefetch_chunks() {
+        let base = Patch::new_base();
+        let len = 2 * ReadBuffer::CHUNK_SIZE + 5;
+        let (dir, mut index) = indexed("prefetch-chunks", &[(1, 3, len)]);
+
+        let learned_any = AtomicBool::new(false);
+        let version = index.bundles[0].versions_mut().remove(0);
+        let files = (0..3u64).map(|i| (FileKind::config as u64, i)).collect::<Vec<_>>();
+        let file = |i| version.file(FileKind::config as u64, i).unwrap().clone();
+        let first = chunk_of(file(1).offset());
+        let last = chunk_of(file(2).offset() + file(2).size() + 36 - 1);
+
+        // files sharing a chunk prefetch it once
+        let fd = BundleFd::File(Arc::new(File::open(dir.join(format_bundle(1, base))).unwrap()));
+        let tasks = ExtractTask::split(1, base, version, &learned_any, fd, files[1..].to_vec());
+        assert_eq!(tasks[0].chunks(), (first..=last).collect::<Vec<_>>());
+        drop(tasks);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn extract_files() {
+        let len = 2 * ReadBuffer::CHUNK_SIZE + 5;
+        let (dir, mut index) = indexed("extract-files", &[(1, 2, len), (2, 3, 100)]);
+
+        let out = dir.join("out");
+        let (tx, rx) = mpsc::channel();
+        let errors = index.extract_files_mt(Some(&out), "*", 2, false, true, Some(tx)).unwrap();
+        assert!(errors.is_empty(), "{:?}", errors);
+        // files in several bundles are extracted from the first one
+        for (i, hash, len) in [(0u64, 1u64, len), (1, 1, len), (2, 2, 100)] {
+            let data = std::fs::read(out.join(format!("{:016x}.config", i))).unwrap();
+            assert!(data == file_data(i, hash, len), "file {} does not match bundle {}", i, hash);
+        }
+        // files are only written under their temporary name until they are complete
+        assert_eq!(std::fs::read_dir(&out).unwrap().count(), 3);
+
+        // writer threads count every file they wrote
+        let written = rx.iter()
+            .filter_map(|event| match event {
+                IndexEvent::Progress { read: 0, count } => Some(count),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(written.len(), 3);
+        assert_eq!(written.iter().max(), Some(&3));
+
+        // failed writes are reported instead of stopping the writers
+        let out = dir.join("not-a-dir");
+        std::fs::write(&out, []).unwrap();
+        let mut index = Index::new(&dir);
+        assert!(index.index_files_mt(2, false, None).unwrap().is_empty());
+        let errors = index.extract_files_mt(Some(&out), "*", 2, false, true, None).unwrap();
+        assert_eq!(errors.len(), 3);
+        assert!(errors.iter().all(|e| e.to_string().contains("failed to create its directory")));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn forget_unindexed() {
+        let base = Patch::new_base();
+        let (dir, mut index) = indexed("forget-unindexed", &[(1, 2, 100), (2, 2, 100)]);
 
+        // bundle 2 failed or was skipped when interrupted
+        let i = index.bundles.binary_search_by(|probe| probe.hash().cmp(&2)).unwrap();
+        index.bundles[i].remove_version(base);
+        index.forget_unindexed(vec![(1, base), (2, base)]);
 
+        assert!(index.timestamps.contains_key(&(1, base)));
+        assert!(index.fingerprints.contains_key(&(1, base)));
+        assert!(!index.timestamps.contains_key(&(2, base)));
+        assert!(!index.fingerprints.contains_key(&(2, base)));
+        assert_eq!(index.find_and_check_bundles(), vec![(2, base)]);
 
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn stream_changes() {
+        let base = Patch::new_base();
+        let (dir, mut index) = indexed("stream-changes", &[(1, 2, 100), (2, 2, 100)]);
+        assert_eq!(find_version(&index.bundles, 1, base).unwrap().stream_size(), None);
+
+        // bundle is indexed again when only its stream changed
+        for len in [8, 16] {
+            std::fs::write(dir.join(format_stream(1, base)), vec![0; len]).unwrap();
+            assert_eq!(index.find_and_check_bundles(), vec![(1, base)]);
+            assert!(index.index_files_mt(1, false, None).unwrap().is_empty());
+            assert_eq!(find_version(&index.bundles, 1, base).unwrap().stream_size(), Some(len as u64));
+            assert!(index.find_and_check_bundles().is_empty());
+        }
+
+        std::fs::remove_file(dir.join(format_stream(1, base))).unwrap();
+        assert_eq!(index.find_and_check_bundles(), vec![(1, base)]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn relocate_copy() {
+        let base = Patch::new_base();
+        let (dir, mut index) = indexed("relocate-from", &[(1, 2, 100), (2, 2, 100), (3, 2, 100)]);
+        std::fs::write(dir.join(format_stream(2, base)), [0; 8]).unwrap();
+        assert!(index.index_files_mt(1, false, None).unwrap().is_empty());
+
+        // copies get new modified times
+        let (copy, _) = indexed("relocate-to", &[]);
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(entry.path(), copy.join(entry.file_name())).unwrap();
+        }
+
+        // bundle 1 changes size, the stream of bundle 2 changes and bundle 4 is new
+        write_bundle(&copy, 1, base, 3, 100);
+        std::fs::write(copy.join(format_stream(2, base)), [0; 16]).unwrap();
+        write_bundle(&copy, 4, base, 1, 100);
+
+        assert_eq!(index.relocate(&copy), 3);
+        assert_eq!(index.dir(), copy);
+        assert!(find_version(&index.bundles, 1, base).is_none());
+        assert!(find_version(&index.bundles, 2, base).is_none());
+        assert!(find_version(&index.bundles, 3, base).is_some());
+        assert_eq!(index.timestamps.keys().collect::<Vec<_>>(), vec![&(3, base)]);
+
+        // only bundles that were dropped or are new are indexed again
+        let mut files = index.find_and_check_bundles();
+        files.sort();
+        assert_eq!(files, vec![(1, base), (2, base), (4, base)]);
+
+        // lookups after indexing read the copies
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(index.index_files_mt(1, false, None).unwrap().is_empty());
+        for (hash, num_files) in [(1, 3), (2, 2), (3, 2), (4, 1)] {
+            assert_eq!(find_version(&index.bundles, hash, base).unwrap().files().len(), num_files);
+        }
+        assert_eq!(find_version(&index.bundles, 2, base).unwrap().stream_size(), Some(16));
+
+        let out = copy.join("out");
+        let errors = index.extract_files_mt(Some(&out), "*", 1, false, true, None).unwrap();
+        assert!(errors.is_empty(), "{:?}", errors);
+        for (i, hash) in [(0u64, 1u64), (1, 1), (2, 1)] {
+            let data = std::fs::read(out.join(format!("{:016x}.config", i))).unwrap();
+            assert!(data == file_data(i, hash, 100), "file {} does not match bundle {}", i, hash);
+        }
+
+        let _ = std::fs::remove_dir_all(&copy);
+    }
+
+    /// Set modified time of `path` without touching its contents.
+    #[cfg(target_os = "linux")]
+    fn set_modified(path: &Path, secs: u64) {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        let times = [libc::timespec { tv_sec: secs as libc::time_t, tv_nsec: 0 }; 2];
+        assert_eq!(unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) }, 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn fingerprint_changes() {
+        let base = Patch::new_base();
+        let (dir, mut index) = indexed("fingerprint-changes", &[(1, 2, 100), (2, 2, 100), (3, 2, 100)]);
+        write_noise_bundle(&dir, 4, ReadBuffer::CHUNK_SIZE * 4);
+        assert!(index.index_files_mt(1, false, None).unwrap().is_empty());
+        let path = |hash| dir.join(format_bundle(hash, base));
+        let time = modified_secs(&std::fs::metadata(path(1)).unwrap());
+
+        // rewrite that keeps the size and modified time
+        let mut bundle = std::fs::read(path(1)).unwrap();
+        *bundle.last_mut().unwrap() ^= 1;
+        std::fs::write(path(1), &bundle).unwrap();
+        set_modified(&path(1), time);
+
+        // copy that only changes the modified time
+        set_modified(&path(2), time + 100);
+
+        let files = index.find_and_check_bundles();
+        assert_eq!(files, vec![(1, base)]);
+        assert!(find_version(&index.bundles, 1, base).is_none());
+        assert!(find_version(&index.bundles, 2, base).is_some());
+        assert_eq!(index.timestamps[&(2, base)], time + 100);
+
+        // whole file hashes are added without reindexing and kept by later runs
+        index.set_paranoid(true);
+        assert_eq!(index.find_and_check_bundles(), vec![(1, base)]);
+        assert!(index.fingerprints[&(3, base)].full.is_some());
+        index.set_paranoid(false);
+        assert_eq!(index.find_and_check_bundles(), vec![(1, base)]);
+        assert!(index.fingerprints[&(3, base)].full.is_some());
+
+        // rewrite between the sampled ends is only found by whole file hashes
+        let mut bundle = std::fs::read(path(4)).unwrap();
+        let middle = bundle.len() / 2;
+        bundle[middle] ^= 1;
+        let time = modified_secs(&std::fs::metadata(path(4)).unwrap());
+        std::fs::write(path(4), &bundle).unwrap();
+        set_modified(&path(4), time);
+        assert_eq!(index.find_and_check_bundles(), vec![(1, base)]);
+        index.set_paranoid(true);
+        let mut files = index.find_and_check_bundles();
+        files.sort();
+        assert_eq!(files, vec![(1, base), (4, base)]);
+        assert!(find_version(&index.bundles, 4, base).is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_bundles() {
+        let base = Patch::new_base();
+        let (dir, mut index) = indexed("failed-bundles", &[(1, 2, 100), (2, 2, 100)]);
+
+        // cut the zlib stream of the only chunk so it fails to inflate with an io error
+        let path = dir.join(format_bundle(2, base));
+        let mut bundle = std::fs::read(&path).unwrap();
+        let len = u32::from_le_bytes(bundle[12..16].try_into().unwrap()) / 2;
+        bundle[12..16].copy_from_slice(&len.to_le_bytes());
+        bundle.truncate(16 + len as usize);
+        std::fs::write(&path, bundle).unwrap();
+
+        let errors = index.index_files_mt(1, false, None).unwrap();
+        assert!(matches!(errors[..], [StingrayError::Io(_)]));
+        assert!(index.timestamps.contains_key(&(1, base)));
+        assert!(!index.timestamps.contains_key(&(2, base)));
+        assert!(!index.fingerprints.contains_key(&(2, base)));
+        assert!(find_version(&index.bundles, 2, base).is_none());
+        assert_eq!(index.find_and_check_bundles(), vec![(2, base)]);
+
+        // the next run indexes the bundle again once it can be read
+        write_bundle(&dir, 2, base, 3, 100);
+        assert!(index.index_files_mt(1, false, None).unwrap().is_empty());
+        assert_eq!(find_version(&index.bundles, 2, base).unwrap().files().len(), 3);
+        assert!(index.timestamps.contains_key(&(2, base)));
+        assert!(index.fingerprints.contains_key(&(2, base)));
+        assert!(index.find_and_check_bundles().is_empty());
+
+        // timestamps left without a version by older caches are indexed again
+        let i = index.bundles.binary_search_by(|probe| probe.hash().cmp(&2)).unwrap();
+        index.bundles[i].remove_version(base);
+        index.fingerprints.remove(&(2, base));
+        assert_eq!(index.find_and_check_bundles(), vec![(2, base)]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn verify_learns_chunks() {
+        let base = Patch::new_base();
+        let (dir, mut index) = indexed("verify", &[(2, 2, 100)]);
+
+        // raw chunks of noise so indexing only learns the first chunks
+        write_noise_bundle(&dir, 1, ReadBuffer::CHUNK_SIZE * 3);
+        assert!(index.index_files_mt(1, false, None).unwrap().is_empty());
+        let known = find_version(&index.bundles, 1, base).unwrap().reader().chunk_offsets().len();
+
+        let (reports, errors) = index.verify_mt(1, false, None).unwrap();
+        assert!(errors.is_empty());
+        let learned = find_version(&index.bundles, 1, base).unwrap().reader().chunk_offsets().len();
+        assert!(learned > known);
+        assert!(index.dirty());
+
+        // every chunk and file of both bundles was checked
+        let mut reports = reports.iter().map(|(hash, _, report)| (*hash, report)).collect::<Vec<_>>();
+        reports.sort_by_key(|(hash, _)| *hash);
+        assert_eq!(reports.iter().map(|(hash, _)| *hash).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(reports.iter().all(|(_, report)| report.is_ok() && report.skipped() == 0));
+        assert_eq!((reports[0].1.chunks(), reports[0].1.files()), (learned, 1));
+        assert_eq!(reports[1].1.files(), 2);
+
+        // nothing left to learn
+        index.dirty = false;
+        index.verify_mt(1, false, None).unwrap();
+        assert!(!index.dirty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+struct State {
+    /// Tasks pushed but not finished yet.
+    pending: usize,
+
+    /// Workers that can still push new tasks.
+    producers: usize,
+}
+
+/// Work-stealing queue shared between a fixed number of workers.
+///
+/// Every worker has its own queue and takes tasks from the front of it. Idle
+/// workers steal from the back of other queues so a worker that split a big
+/// bundle into tasks keeps reading it in order while others help from the end.
+pub struct WorkQueue<T> {
+    queues: Vec<Mutex<VecDeque<T>>>,
+    state: Mutex<State>,
+    ready: Condvar,
+}
+
+impl<T> WorkQueue<T> {
+    /// Creates `WorkQueue` where every worker is a producer until it calls [close](WorkQueue::close).
+    pub fn new(workers: usize) -> Self {
+        Self {
+            queues: (0..workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+            state: Mutex::new(State {
+                pending: 0,
+                producers: workers,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// Add task to the queue of `worker`.
+    pub fn push(&self, worker: usize, task: T) {
+        let mut state = self.state.lock().unwrap();
+        state.pending += 1;
+        self.queues[worker].lock().unwrap().push_back(task);
+        drop(state);
+
+        self.ready.notify_one();
+    }
+
+    /// Take task from the queue of `worker` or steal one from another worker.
+    ///
+    /// Does not block.
+    pub fn try_pop(&self, worker: usize) -> Option<T> {
+        if let Some(task) = self.queues[worker].lock().unwrap().pop_front() {
+            return Some(task);
+        }
+
+        let len = self.queues.len();
+        (1..len).find_map(|i| self.queues[(worker + i) % len].lock().unwrap().pop_back())
+    }
+
+    /// Take task like [try_pop](WorkQueue::try_pop) and wait if other workers may still add tasks.
+    ///
+    /// Returns `None` once every producer closed and every task finished.
+    pub fn pop(&self, worker: usize) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            // tasks are pushed while holding the state lock so none are missed here
+            if let Some(task) = self.try_pop(worker) {
+                return Some(task);
+            } else if state.producers == 0 && state.pending == 0 {
+                return None;
+            }
+
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    /// Mark a task taken from the queue as finished.
+    pub fn done(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pending -= 1;
+        if state.pending == 0 {
+            self.ready.notify_all();
+        }
+    }
+
+    /// Stop adding tasks from the calling worker.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.producers = state.producers.saturating_sub(1);
+        if state.producers == 0 {
+            self.ready.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn steals_from_back() {
+        let queue = WorkQueue::new(2);
+        for i in 0..4 {
+            queue.push(0, i);
+        }
+
+        assert_eq!(queue.try_pop(0), Some(0));
+        assert_eq!(queue.try_pop(1), Some(3));
+        assert_eq!(queue.try_pop(0), Some(1));
+    }
+
+    #[test]
+    fn waits_for_producers() {
+        let queue = &WorkQueue::new(4);
+        let sum = &AtomicUsize::new(0);
+
+        crossbeam_utils::thread::scope(|s| {
+            for worker in 0..4 {
+                s.spawn(move |_| {
+                    // one worker splits work while the others wait for it
+                    if worker == 0 {
+                        for i in 1..=100 {
+                            queue.push(worker, i);
+                        }
+                    }
+                    queue.close();
+
+                    while let Some(i) = queue.pop(worker) {
+                        sum.fetch_add(i, Ordering::Relaxed);
+                        queue.done();
+                    }
+                });
+            }
+        }).unwrap();
+
+        assert_eq!(sum.load(Ordering::Relaxed), 5050);
+    }
+}
@@ -1,18 +1,56 @@
 use std::convert::TryInto;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::Hasher;
 use std::path::{Path, PathBuf};
-use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::io::{self, Read, Write};
 use std::fs;
 
-use flate2::read::{ZlibEncoder, ZlibDecoder};
+use flate2::read::ZlibDecoder;
 use stingray::file::FileKind;
-use stingray::Patch;
+use stingray::{BundleHeader, Patch};
 
 use super::Index;
-
+use super::reader::MappedIndex;
+
+// Index cache files start with a header, integers are little endian:
+//
+//   0..8   MAGIC_WORD
+//   8..10  save version of the payload
+//   10..18 murmur hash of the uncompressed payload
+//   18..   layout of reader::MappedIndex
+//
+// payloads up to BINCODE_VERSION are zlib compressed bincode of Index and
+// version 1 saves of the first release are upgraded with migrate::migrate
 const MAGIC_WORD: u64 = 0x7865646e69736572;
-const SAVE_VERSION: u16 = 1;
+const SAVE_VERSION: u16 = 9;
+const BINCODE_VERSION: u16 = 7;
+
+#[derive(Debug, PartialEq, Eq)]
+struct CacheHeader {
+    version: u16,
+    hash: u64,
+}
+
+impl CacheHeader {
+    const SIZE: usize = 18;
+
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::SIZE || u64::from_le_bytes(bytes[..8].try_into().unwrap()) != MAGIC_WORD {
+            return None;
+        }
+
+        Some(Self {
+            version: u16::from_le_bytes(bytes[8..10].try_into().unwrap()),
+            hash: u64::from_le_bytes(bytes[10..18].try_into().unwrap()),
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut out = [0; Self::SIZE];
+        out[..8].copy_from_slice(&MAGIC_WORD.to_le_bytes());
+        out[8..10].copy_from_slice(&self.version.to_le_bytes());
+        out[10..18].copy_from_slice(&self.hash.to_le_bytes());
+        out
+    }
+}
 
 const KIBYTE: u64 = 1024;
 const MIBYTE: u64 = KIBYTE * 1024;
@@ -35,6 +73,22 @@ pub fn format_bundle(hash: u64, patch: Patch) -> String {
     }
 }
 
+pub fn format_property(hash: u64) -> String {
+    match FileKind::with_hash(hash).as_str() {
+        Some(x) => x.to_owned(),
+        None => format!("{:016x}", hash),
+    }
+}
+
+pub fn print_header(header: &BundleHeader) {
+    let properties = header.properties();
+    println!();
+    println!("Header properties ({}):", properties.len());
+    for hash in properties {
+        println!("  {}", format_property(hash));
+    }
+}
+
 pub fn get_vermintide_dir() -> io::Result<PathBuf> {
     steam::get_steam_apps().and_then(
         |apps| {
@@ -52,86 +106,86 @@ pub fn get_vermintide_dir() -> io::Result<PathBuf> {
 pub fn load_reader(path: &Path) -> io::Result<Index> {
     #[cfg(feature = "serde_support")]
     {
-        fs::OpenOptions::new()
-            .read(true)
-            .open(path)
-            .and_then(|mut file| {
-                let mut contents = Vec::new();
-                file.read_to_end(&mut contents)?;
-                if file.metadata()?.len() > 20 {
-                    let magic_word = u64::from_le_bytes(contents[..8].try_into().unwrap());
-                    if magic_word == MAGIC_WORD {
-                        let save_version = u16::from_le_bytes(contents[8..10].try_into().unwrap());
-                        if save_version == SAVE_VERSION {
-                            // 10..18 is u64 hash which is unused for loads
-                            let mut out = Vec::new();
-                            let mut index = ZlibDecoder::new(&contents[18..]);
-                            index.read_to_end(&mut out)?;
-
-                            return bincode::deserialize(&out[..])
-                                .map_err(|_| io::Error::new(io::ErrorKind::Other, "bincode deserialization failed"))
-                        }
-                    }
-                }
-                Err(io::Error::new(io::ErrorKind::Other, "incompatible save"))
-            })
+        let incompatible = || io::Error::new(io::ErrorKind::Other, "incompatible save");
+        let mut file = fs::File::open(path)?;
+        let mut header = [0; CacheHeader::SIZE];
+        file.read_exact(&mut header)?;
+        let header = CacheHeader::parse(&header).ok_or_else(incompatible)?;
+
+        // hash is only used to skip writing unchanged saves
+        if header.version > BINCODE_VERSION && header.version <= SAVE_VERSION {
+            let mapped = MappedIndex::open(&file, CacheHeader::SIZE, header.version)?;
+            let mut index = Index::from_mapped(mapped);
+
+            // write back with the tables added since
+            if header.version != SAVE_VERSION {
+                index.set_dirty();
+            }
+            return Ok(index);
+        } else if header.version > BINCODE_VERSION {
+            return Err(incompatible());
+        }
+
+        let mut out = Vec::new();
+        ZlibDecoder::new(file).read_to_end(&mut out)?;
+
+        let out = match header.version {
+            BINCODE_VERSION => out,
+            version => super::migrate::migrate(version, out)
+                .map_err(|_| incompatible())?
+                .ok_or_else(incompatible)?,
+        };
+
+        let mut index: Index = bincode::deserialize(&out[..])
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "bincode deserialization failed"))?;
+
+        // write back in the current format even if nothing is indexed
+        index.set_dirty();
+        Ok(index)
     }
 
     #[cfg(not(feature = "serde_support"))]
     Err(io::Error::new(io::ErrorKind::Other, "deserialization not enabled in build"))
 }
 
-pub fn save_reader(path: &Path, index: &Index, force_save: bool) -> io::Result<()> {
+pub fn save_reader(path: &Path, index: &mut Index, force_save: bool) -> io::Result<()> {
     #[cfg(feature = "serde_support")]
     {
         println!();
         print!("Saving to {}...", path.display());
         let start = std::time::Instant::now();
 
-        let bin = bincode::serialize(&index)
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "bincode serialization failed"))?;
-
-        let mut file = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path)?;
-
-        let mut hasher = DefaultHasher::new();
-        hasher.write(&bin);
-        let hash = hasher.finish();
-
-        let hash = if force_save {
-            Some(hash)
-        } else {
-            let mut file_hash = [0; 8];
-            let file_hash = match file.read(&mut file_hash) {
-                Ok(8) => Some(u64::from_le_bytes(file_hash)),
-                _ => None,
-            };
-
-            if file_hash != Some(hash) {
-                Some(hash)
-            } else {
-                None
-            }
+        // bundles only in the old cache would be lost otherwise
+        index.load_bundles();
+        let out = index.to_mapped()?;
+
+        let header = CacheHeader {
+            version: SAVE_VERSION,
+            hash: stingray::hash::murmur_hash(&out),
         };
 
-        if let Some(hash) = hash {
-            let mut out = Vec::new();
-            let mut comp = ZlibEncoder::new(&bin[..], flate2::Compression::new(4));
-            comp.read_to_end(&mut out)?;
-            //let out = bin;
-
-            file.seek(SeekFrom::Start(0))?;
-            file.write_all(&MAGIC_WORD.to_le_bytes())?;
-            file.seek(SeekFrom::Start(8))?;
-            file.write_all(&SAVE_VERSION.to_le_bytes())?;
-            file.seek(SeekFrom::Start(10))?;
-            file.write_all(&hash.to_le_bytes())?;
-            file.seek(SeekFrom::Start(18))?;
-            file.write_all(&out)?;
-            file.set_len(18 + out.len() as u64)?;
+        let unchanged = !force_save && {
+            let mut file_header = [0; CacheHeader::SIZE];
+            fs::File::open(path).and_then(|mut file| file.read_exact(&mut file_header)).is_ok()
+                && CacheHeader::parse(&file_header).as_ref() == Some(&header)
+        };
+
+        // other processes may have the old cache mapped so it is replaced instead of written over
+        if !unchanged {
+            let mut tmp = path.as_os_str().to_owned();
+            tmp.push(".tmp");
+            let tmp = PathBuf::from(tmp);
+
+            let mut file = fs::File::create(&tmp)?;
+            let written = file.write_all(&header.to_bytes())
+                .and_then(|_| file.write_all(&out))
+                .and_then(|_| file.sync_all());
+            drop(file);
+
+            if let Err(e) = written.and_then(|_| fs::rename(&tmp, path)) {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            }
         }
 
         let millis = start.elapsed().as_millis();
@@ -214,21 +268,35 @@ pub fn print_info(index: &Index) -> Result<(), Box<dyn std::error::Error>> {
     let num_total_files = total_files.len();
     let mut total_files_size = 0;
     for file in index.get_all_files() {
-        total_files_size += file.size() as u64;
+        total_files_size += file.size();
     }
 
     let unique_files = index.get_unique_files();
     let num_unique_files = unique_files.len();
     let mut unique_files_size = 0;
     for file in unique_files {
-        unique_files_size += file.size() as u64;
+        unique_files_size += file.size();
     }
 
+    let mut properties = Vec::<(u64, u64)>::new();
+    for bundle in index.get_all_versions() {
+        for hash in bundle.bundle_header().properties() {
+            match properties.binary_search_by(|probe| probe.0.cmp(&hash)) {
+                Ok(i) => properties[i].1 += 1,
+                Err(i) => properties.insert(i, (hash, 1)),
+            }
+        }
+    }
+    let mut properties = properties.into_iter()
+        .map(|(hash, count)| (format_property(hash), count))
+        .collect::<Vec<_>>();
+    properties.sort_by(|(a, ..), (b, ..)| a.cmp(b));
+
     let active_files = index.get_active_files();
     let num_active_files = active_files.len();
     let mut active_files_size = 0;
     for file in active_files {
-        active_files_size += file.size() as u64;
+        active_files_size += file.size();
     }
 
     println!();
@@ -241,5 +309,10 @@ pub fn print_info(index: &Index) -> Result<(), Box<dyn std::error::Error>> {
     println!("  total: {} ({})", size_to_string(total_files_size), num_total_files);
     println!("  unique: {} ({})", size_to_string(unique_files_size), num_unique_files);
     println!("  active: {} ({})", size_to_string(active_files_size), num_active_files);
+    println!();
+    println!("Header properties: (bundle count)");
+    for (property, count) in &properties {
+        println!("  {}: {}", property, count);
+    }
     Ok(())
 }
@@ -1,18 +1,26 @@
-// TODO look at timing file system access instead of OS specific solutions for
-// identifying storage type (HDD, SSD)
-
 //! Small library for getting physical offset of files and drive type to
 //! optimize read patterns.
 //!
-//! Currently only supports Windows.
+//! Windows and Linux are currently supported. Other systems can fall back to
+//! timing reads with [probe].
 use std::path::Path;
 use std::fs::File;
 
+mod probe;
+pub use probe::Storage as Storage;
+
 #[cfg(target_os = "windows")]
 mod windows;
 
+#[cfg(target_os = "linux")]
+mod linux;
+
 /// Get physical offset of File.
 ///
+/// Offsets are only meant for ordering files on the same drive. Windows
+/// returns the cluster of the first extent while Linux returns the byte offset
+/// from `FS_IOC_FIEMAP` or `FIBMAP`.
+///
 /// # Example
 ///
 /// ```no_run
@@ -31,12 +39,12 @@ pub fn file_offset(fd: &File) -> Option<u64> {
 
 /// Attempts to find if given path is on an SSD or not.
 ///
+/// On Linux the block device of the path is looked up in sysfs and checked
+/// through partitions, device-mapper, md and loop devices.
+///
 /// # Example
 ///
 /// ```
-/// use std::path::Path;
-///
-/// #[cfg(target_os = "windows")]
 /// let dir = if cfg!(target_os = "windows") {
 ///     r"C:\"
 /// } else {
@@ -53,6 +61,28 @@ pub fn in_ssd<T: AsRef<Path>>(bundle: T) -> Option<bool> {
     in_ssd_(bundle.as_ref())
 }
 
+/// Classify storage of `files` by timing random and sequential reads.
+///
+/// Meant as a fallback for when [in_ssd] can't tell, e.g. on network shares.
+/// Reads bypass the OS cache where supported. Returns `None` if no file could
+/// be read or every file was too small to time seeks.
+///
+/// # Example
+///
+/// ```no_run
+/// use drive::Storage;
+///
+/// match drive::probe(&["bundle1", "bundle2"]) {
+///     Some(Storage::Ssd) => println!("bundles are stored on an SSD"),
+///     Some(Storage::Hdd) => println!("bundles are stored on an HDD"),
+///     Some(Storage::Network) => println!("bundles are stored on a network share"),
+///     None => println!("unable to time reads"),
+/// }
+/// ```
+pub fn probe<T: AsRef<Path>>(files: &[T]) -> Option<Storage> {
+    probe::probe(files)
+}
+
 
 
 #[cfg(target_os = "windows")]
@@ -67,12 +97,24 @@ fn in_ssd_(path: &Path) -> Option<bool> {
 
 
 
-#[cfg(not(target_os = "windows"))]
+#[cfg(target_os = "linux")]
+fn file_offset_(fd: &File) -> Option<u64> {
+    linux::file_offset(fd)
+}
+
+#[cfg(target_os = "linux")]
+fn in_ssd_(path: &Path) -> Option<bool> {
+    linux::in_ssd(path)
+}
+
+
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
 fn file_offset_(_fd: &File) -> Option<u64> {
     None
 }
 
-#[cfg(not(target_os = "windows"))]
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
 fn in_ssd_(_path: &Path) -> Option<bool> {
     None
 }
@@ -0,0 +1,367 @@
+use std::fs;
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Limit for following stacked devices like LVM on RAID on loop devices.
+const MAX_DEPTH: usize = 8;
+
+/// `_IOWR('f', 11, struct fiemap)`
+const FS_IOC_FIEMAP: u64 = 0xc020660b;
+/// `_IO(0x00, 1)`
+const FIBMAP: u64 = 1;
+/// `_IO(0x00, 2)`
+const FIGETBSZ: u64 = 2;
+
+/// Physical location of the extent is not known yet, e.g. delayed allocation.
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+
+#[repr(C)]
+#[derive(Default)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+/// `struct fiemap` with room for a single extent.
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+    extents: [FiemapExtent; 1],
+}
+
+pub fn file_offset(fd: &File) -> Option<u64> {
+    fiemap_offset(fd).or_else(|| fibmap_offset(fd))
+}
+
+pub fn in_ssd(path: &Path) -> Option<bool> {
+    Sysfs::new("/").in_ssd(path, 0)
+}
+
+/// Byte offset on the device of the first extent of `fd`.
+fn fiemap_offset(fd: &File) -> Option<u64> {
+    let mut map = Fiemap {
+        length: u64::MAX,
+        extent_count: 1,
+        ..Default::default()
+    };
+
+    let ret = unsafe { libc::ioctl(fd.as_raw_fd(), FS_IOC_FIEMAP as _, &mut map as *mut Fiemap) };
+    if ret < 0 {
+        return None;
+    }
+
+    // empty files have no extents
+    if map.mapped_extents == 0 {
+        Some(0)
+    } else if map.extents[0].flags & FIEMAP_EXTENT_UNKNOWN != 0 {
+        None
+    } else {
+        Some(map.extents[0].physical)
+    }
+}
+
+/// Byte offset on the device of the first block of `fd`.
+///
+/// Older interface for file systems without FIEMAP. Needs `CAP_SYS_RAWIO`.
+fn fibmap_offset(fd: &File) -> Option<u64> {
+    let fd = fd.as_raw_fd();
+    let mut block_size: libc::c_int = 0;
+    let mut block: libc::c_int = 0;
+
+    unsafe {
+        if libc::ioctl(fd, FIGETBSZ as _, &mut block_size as *mut libc::c_int) < 0
+            || libc::ioctl(fd, FIBMAP as _, &mut block as *mut libc::c_int) < 0
+        {
+            return None;
+        }
+    }
+
+    // block 0 is returned for holes and unmapped files
+    if block <= 0 || block_size <= 0 {
+        None
+    } else {
+        Some(block as u64 * block_size as u64)
+    }
+}
+
+/// Block device lookup through sysfs and procfs under `root`.
+///
+/// `root` is only changed to point at a fake tree in tests.
+struct Sysfs {
+    root: PathBuf,
+}
+
+impl Sysfs {
+    fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_owned(),
+        }
+    }
+
+    fn in_ssd(&self, path: &Path, depth: usize) -> Option<bool> {
+        let dev = fs::metadata(path).ok()?.dev();
+        let block = self.device_dir(major(dev), minor(dev))
+            .or_else(|| self.mount_source_dir(path))?;
+
+        self.block_in_ssd(&block, depth)
+    }
+
+    /// Sysfs directory of the block device with number `major:minor`.
+    ///
+    /// File systems like btrfs and overlayfs report an anonymous device with
+    /// major `0` that has no entry in sysfs.
+    fn device_dir(&self, major: u64, minor: u64) -> Option<PathBuf> {
+        if major == 0 {
+            return None;
+        }
+
+        fs::canonicalize(self.root.join(format!("sys/dev/block/{}:{}", major, minor))).ok()
+    }
+
+    /// Sysfs directory of the device the mount containing `path` was mounted from.
+    fn mount_source_dir(&self, path: &Path) -> Option<PathBuf> {
+        let path = fs::canonicalize(path).ok()?;
+        let mountinfo = fs::read_to_string(self.root.join("proc/self/mountinfo")).ok()?;
+
+        let (_, source) = mountinfo.lines()
+            .filter_map(parse_mount)
+            .filter(|(mount_point, _)| path.starts_with(mount_point))
+            .max_by_key(|(mount_point, _)| mount_point.as_os_str().len())?;
+
+        // /dev/mapper/* and /dev/disk/by-* are links to the kernel name
+        let source = source.strip_prefix("/").unwrap_or(&source);
+        let source = fs::canonicalize(self.root.join(source)).unwrap_or_else(|_| source.to_owned());
+        let name = source.file_name()?;
+
+        fs::canonicalize(self.root.join("sys/class/block").join(name)).ok()
+    }
+
+    /// Check block device at sysfs directory `dir` and the devices it is built on.
+    fn block_in_ssd(&self, dir: &Path, depth: usize) -> Option<bool> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        // partitions share the queue of their disk
+        let dir = if dir.join("partition").exists() {
+            dir.parent()?
+        } else {
+            dir
+        };
+
+        // device-mapper, md and bcache devices list the devices they are built on
+        if let Ok(slaves) = fs::read_dir(dir.join("slaves")) {
+            let slaves = slaves.flatten()
+                .filter_map(|slave| fs::canonicalize(slave.path()).ok())
+                .map(|slave| self.block_in_ssd(&slave, depth + 1))
+                .collect::<Vec<_>>();
+
+            if !slaves.is_empty() {
+                // one slow device slows down the whole stack
+                return slaves.into_iter().try_fold(true, |is_ssd, slave| Some(is_ssd && slave?));
+            }
+        }
+
+        // loop devices are as fast as the drive of their backing file
+        if let Ok(backing_file) = fs::read_to_string(dir.join("loop/backing_file")) {
+            return self.in_ssd(Path::new(backing_file.trim_end()), depth + 1);
+        }
+
+        match fs::read_to_string(dir.join("queue/rotational")).ok()?.trim() {
+            "0" => Some(true),
+            "1" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// Get mount point and source from a line of `/proc/self/mountinfo`.
+fn parse_mount(line: &str) -> Option<(PathBuf, PathBuf)> {
+    let (mount, fs) = line.split_once(" - ")?;
+    let mount_point = mount.split(' ').nth(4)?;
+    let source = fs.split(' ').nth(1)?;
+
+    Some((unescape(mount_point).into(), unescape(source).into()))
+}
+
+/// Undo octal escapes of spaces, tabs, newlines and backslashes in mountinfo.
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        match rest.get(i + 1..i + 4).and_then(|code| u8::from_str_radix(code, 8).ok()) {
+            Some(byte) => {
+                out.push(byte as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                out.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn major(dev: u64) -> u64 {
+    ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff)
+}
+
+fn minor(dev: u64) -> u64 {
+    (dev & 0xff) | ((dev >> 12) & !0xff)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    struct FakeRoot(PathBuf);
+
+    impl FakeRoot {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("drive-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            Self(root)
+        }
+
+        fn write(&self, path: &str, data: &str) {
+            let path = self.0.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+
+        fn link(&self, path: &str, target: &str) {
+            let path = self.0.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            symlink(self.0.join(target), path).unwrap();
+        }
+
+        /// Add disk `name` with its partitions to the fake tree.
+        fn disk(&self, name: &str, dev: &str, rotational: bool, partitions: &[(&str, &str)]) {
+            let dir = format!("sys/devices/pci0000:00/block/{}", name);
+            self.write(&format!("{}/queue/rotational", dir), if rotational { "1\n" } else { "0\n" });
+            self.link(&format!("sys/dev/block/{}", dev), &dir);
+            self.link(&format!("sys/class/block/{}", name), &dir);
+
+            for (partition, dev) in partitions {
+                let dir = format!("{}/{}", dir, partition);
+                self.write(&format!("{}/partition", dir), "1\n");
+                self.link(&format!("sys/dev/block/{}", dev), &dir);
+                self.link(&format!("sys/class/block/{}", partition), &dir);
+            }
+        }
+
+        /// Add virtual block device `name` built on `slaves`.
+        fn virtual_disk(&self, name: &str, dev: &str, slaves: &[&str]) -> String {
+            let dir = format!("sys/devices/virtual/block/{}", name);
+            self.write(&format!("{}/queue/rotational", dir), "0\n");
+            self.link(&format!("sys/dev/block/{}", dev), &dir);
+            self.link(&format!("sys/class/block/{}", name), &dir);
+
+            for slave in slaves {
+                self.link(&format!("{}/slaves/{}", dir, slave), &format!("sys/class/block/{}", slave));
+            }
+            dir
+        }
+
+        fn sysfs(&self) -> Sysfs {
+            Sysfs::new(&self.0)
+        }
+    }
+
+    impl Drop for FakeRoot {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn in_ssd(sysfs: &Sysfs, major: u64, minor: u64) -> Option<bool> {
+        sysfs.block_in_ssd(&sysfs.device_dir(major, minor)?, 0)
+    }
+
+    #[test]
+    fn partitions() {
+        let root = FakeRoot::new("partitions");
+        root.disk("sda", "8:0", true, &[("sda1", "8:1")]);
+        root.disk("nvme0n1", "259:0", false, &[("nvme0n1p1", "259:1")]);
+        let sysfs = root.sysfs();
+
+        assert_eq!(in_ssd(&sysfs, 8, 1), Some(false));
+        assert_eq!(in_ssd(&sysfs, 259, 1), Some(true));
+        assert_eq!(in_ssd(&sysfs, 259, 0), Some(true));
+        assert_eq!(in_ssd(&sysfs, 8, 2), None);
+    }
+
+    #[test]
+    fn device_mapper() {
+        let root = FakeRoot::new("dm");
+        root.disk("sda", "8:0", true, &[("sda1", "8:1")]);
+        root.disk("nvme0n1", "259:0", false, &[("nvme0n1p1", "259:1"), ("nvme0n1p2", "259:2")]);
+        root.virtual_disk("dm-0", "253:0", &["nvme0n1p1", "nvme0n1p2"]);
+        root.virtual_disk("dm-1", "253:1", &["nvme0n1p1", "sda1"]);
+        root.virtual_disk("md0", "9:0", &["dm-0"]);
+        let sysfs = root.sysfs();
+
+        assert_eq!(in_ssd(&sysfs, 253, 0), Some(true));
+        assert_eq!(in_ssd(&sysfs, 253, 1), Some(false));
+        assert_eq!(in_ssd(&sysfs, 9, 0), Some(true));
+    }
+
+    #[test]
+    fn loop_device() {
+        let root = FakeRoot::new("loop");
+        root.disk("sdb", "8:16", true, &[("sdb1", "8:17")]);
+        root.write("bundles.img", "");
+        root.write("proc/self/mountinfo", &format!(
+            "22 1 0:21 / / rw - ext4 /dev/sdb1 rw\n\
+             23 22 0:22 / {} rw - tmpfs tmpfs rw\n",
+            root.0.join("sys").display()));
+        let dir = root.virtual_disk("loop0", "7:0", &[]);
+        root.write(&format!("{}/loop/backing_file", dir), &format!("{}\n", root.0.join("bundles.img").display()));
+        let sysfs = root.sysfs();
+
+        // backing file is found through the mount table since the fake tree
+        // does not know the device number of the temp directory
+        assert_eq!(in_ssd(&sysfs, 7, 0), Some(false));
+    }
+
+    #[test]
+    fn mountinfo() {
+        assert_eq!(
+            parse_mount("36 35 98:0 /mnt1 /mnt/steam\\040library rw,noatime master:1 - ext4 /dev/mapper/vg-root rw"),
+            Some(("/mnt/steam library".into(), "/dev/mapper/vg-root".into())));
+        assert_eq!(parse_mount("36 35 98:0 /mnt1 /mnt2 rw"), None);
+        assert_eq!(unescape(r"a\134b\"), r"a\b\");
+    }
+
+    #[test]
+    fn fiemap_layout() {
+        // sizes from linux/fiemap.h
+        assert_eq!(std::mem::size_of::<FiemapExtent>(), 56);
+        assert_eq!(std::mem::size_of::<Fiemap>(), 32 + 56);
+    }
+
+    #[test]
+    fn device_numbers() {
+        assert_eq!((major(0x801), minor(0x801)), (8, 1));
+        assert_eq!((major(0x10301), minor(0x10301)), (259, 1));
+        assert_eq!((major(0x10082c), minor(0x10082c)), (8, 300));
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Size and alignment of probe reads.
+///
+/// Unbuffered reads need sector aligned offsets, sizes and buffers.
+const BLOCK_SIZE: usize = 4096;
+
+/// Reads per file for each access pattern.
+const SAMPLES: usize = 8;
+
+/// Files smaller than this are skipped since their blocks are too close to
+/// each other to cause seeks.
+const MIN_FILE_SIZE: u64 = (BLOCK_SIZE * SAMPLES * 16) as u64;
+
+/// Random reads faster than this are from a drive without seek times.
+const SSD_LATENCY: Duration = Duration::from_micros(500);
+
+/// Drives that seek are this much slower for random reads than sequential
+/// reads. Network shares pay the same round trip for both.
+const SEEK_RATIO: u32 = 4;
+
+/// Storage type found by [probe](crate::probe).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Storage {
+    Ssd,
+    Hdd,
+    Network,
+}
+
+pub fn probe<T: AsRef<Path>>(files: &[T]) -> Option<Storage> {
+    let mut random = Vec::new();
+    let mut sequential = Vec::new();
+    let mut buffer = vec![0; BLOCK_SIZE * 2];
+    let align = buffer.as_ptr().align_offset(BLOCK_SIZE);
+    let buffer = &mut buffer[align..align + BLOCK_SIZE];
+
+    for path in files {
+        let _ = time_reads(path.as_ref(), buffer, &mut random, &mut sequential);
+    }
+
+    Some(classify(median(&mut random)?, median(&mut sequential)?))
+}
+
+fn classify(random: Duration, sequential: Duration) -> Storage {
+    if random < SSD_LATENCY {
+        Storage::Ssd
+    } else if random >= sequential * SEEK_RATIO {
+        Storage::Hdd
+    } else {
+        Storage::Network
+    }
+}
+
+fn median(samples: &mut [Duration]) -> Option<Duration> {
+    samples.sort_unstable();
+    samples.get(samples.len() / 2).copied()
+}
+
+/// Time reads spread over the back half of the file and reads of consecutive
+/// blocks from the start.
+fn time_reads(
+    path: &Path,
+    buffer: &mut [u8],
+    random: &mut Vec<Duration>,
+    sequential: &mut Vec<Duration>,
+) -> io::Result<()> {
+    let fd = open_unbuffered(path)?;
+    let size = fd.metadata()?.len();
+    if size < MIN_FILE_SIZE {
+        return Ok(());
+    }
+
+    let blocks = size / BLOCK_SIZE as u64;
+    let mut state = size | 1;
+    for _ in 0..SAMPLES {
+        // xorshift is enough to keep the drive from predicting the next read
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+
+        let block = blocks / 2 + state % (blocks / 2);
+        random.push(time_read(&fd, block * BLOCK_SIZE as u64, buffer)?);
+    }
+
+    // first read seeks to the start of the file
+    time_read(&fd, 0, buffer)?;
+    for block in 1..=SAMPLES as u64 {
+        sequential.push(time_read(&fd, block * BLOCK_SIZE as u64, buffer)?);
+    }
+
+    Ok(())
+}
+
+fn time_read(fd: &File, offset: u64, buffer: &mut [u8]) -> io::Result<Duration> {
+    let start = Instant::now();
+    read_at(fd, offset, buffer)?;
+    Ok(start.elapsed())
+}
+
+#[cfg(unix)]
+fn read_at(fd: &File, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(fd, buffer, offset)
+}
+
+#[cfg(windows)]
+fn read_at(fd: &File, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(fd, buffer, offset)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_at(mut fd: &File, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    fd.seek(SeekFrom::Start(offset))?;
+    fd.read(buffer)
+}
+
+#[cfg(target_os = "windows")]
+fn open_unbuffered(path: &Path) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(0x20000000) //FILE_FLAG_NO_BUFFERING
+        .open(path)
+}
+
+#[cfg(target_os = "linux")]
+fn open_unbuffered(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    if let Ok(fd) = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+    {
+        return Ok(fd);
+    }
+
+    // file systems without O_DIRECT can still drop cached pages of the file
+    let fd = OpenOptions::new().read(true).open(path)?;
+    unsafe {
+        libc::posix_fadvise(fd.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+    Ok(fd)
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+fn open_unbuffered(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_latency() {
+        let us = Duration::from_micros;
+
+        assert_eq!(classify(us(80), us(20)), Storage::Ssd);
+        assert_eq!(classify(us(8000), us(150)), Storage::Hdd);
+        assert_eq!(classify(us(1200), us(900)), Storage::Network);
+    }
+
+    #[test]
+    fn probe_files() {
+        let dir = std::env::temp_dir().join(format!("drive-probe-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let small = dir.join("small");
+        let large = dir.join("large");
+        std::fs::write(&small, vec![1; BLOCK_SIZE]).unwrap();
+        std::fs::write(&large, vec![1; MIN_FILE_SIZE as usize]).unwrap();
+
+        let missing = probe(&[&dir.join("missing"), &small]);
+        let found = probe(&[&large]);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(missing, None);
+        assert!(found.is_some());
+    }
+}
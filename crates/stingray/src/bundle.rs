@@ -1,8 +1,10 @@
 use std::convert::TryInto;
 use std::io::Read;
 use std::io::Seek;
+use std::io::SeekFrom;
 use std::cmp::Ordering;
 
+use crate::codec;
 use crate::consts;
 use crate::file;
 use crate::file::BundleFile;
@@ -11,6 +13,103 @@ use crate::utility::Patch;
 use crate::utility::format_bundle;
 use crate::reader::BundleReader;
 use crate::reader::ReadBuffer;
+use crate::reader::BundleSource;
+
+/// Number of property hashes in [BundleHeader](BundleHeader).
+const NUM_PROPERTIES: usize = 32;
+
+/// Bundle header stored at `4..260` of the uncompressed bundle.
+///
+/// Holds up to 32 resource type/property hashes. Unused slots are 0.
+#[cfg_attr(feature = "serde_support", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BundleHeader {
+    properties: [u64; NUM_PROPERTIES],
+}
+
+impl BundleHeader {
+    /// Size of header in bytes.
+    pub const SIZE: usize = NUM_PROPERTIES * 8;
+
+    /// Creates `BundleHeader` from a list of property hashes.
+    ///
+    /// Returns `None` if there are more than 32 properties.
+    pub fn new(properties: &[u64]) -> Option<Self> {
+        if properties.len() > NUM_PROPERTIES {
+            return None;
+        }
+
+        let mut header = Self::default();
+        header.properties[..properties.len()].copy_from_slice(properties);
+        Some(header)
+    }
+
+    /// Parse header from the 256 bytes after the file count.
+    pub fn from_bytes(bytes: &[u8]) -> crate::StingrayResult<Self> {
+        if bytes.len() < Self::SIZE {
+            return Err(stingray_error!("bundle header needs {} bytes but got {}", Self::SIZE, bytes.len()));
+        }
+
+        let mut header = Self::default();
+        for (i, property) in header.properties.iter_mut().enumerate() {
+            *property = u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into()?);
+        }
+        Ok(header)
+    }
+
+    pub fn to_bytes(&self) -> [u8; BundleHeader::SIZE] {
+        let mut out = [0; Self::SIZE];
+        for (i, property) in self.properties.iter().enumerate() {
+            out[i * 8..i * 8 + 8].copy_from_slice(&property.to_le_bytes());
+        }
+        out
+    }
+
+    /// Non-zero property hashes.
+    pub fn properties(&self) -> Vec<u64> {
+        self.properties.iter().copied().filter(|hash| *hash != 0).collect()
+    }
+}
+
+/// Problems found by [BundleVersion::verify](BundleVersion::verify).
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    chunks: usize,
+    files: usize,
+    skipped: usize,
+    diff: u64,
+    errors: Vec<crate::StingrayError>,
+}
+
+impl VerifyReport {
+    /// Number of chunks that decompressed.
+    pub fn chunks(&self) -> usize {
+        self.chunks
+    }
+
+    /// Number of files that were read.
+    pub fn files(&self) -> usize {
+        self.files
+    }
+
+    /// Number of files that were not read because they overlap a bad chunk.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Difference in size of the bundle and the sum of file sizes in the index.
+    pub fn diff(&self) -> u64 {
+        self.diff
+    }
+
+    pub fn errors(&self) -> &[crate::StingrayError] {
+        &self.errors[..]
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
 
 /// Convenience wrapper around [BundleVersion](BundleVersion).
 ///
@@ -99,10 +198,15 @@ pub struct BundleVersion {
     patch: Patch,
 
     /// Difference in known size of files and actual size of files.
-    diff: u32,
+    diff: u64,
 
     /// Size of compressed bundle.
-    size: u32,
+    size: u64,
+
+    /// Size of `.stream` file if the bundle has one.
+    stream: Option<u64>,
+
+    header: BundleHeader,
 
     reader: BundleReader,
     files: Vec<BundleFile>,
@@ -113,25 +217,63 @@ impl BundleVersion {
         if let Some(patch) = patch.get() {
             assert!(patch < 1000);
         }
-        assert!(size <= u32::MAX as u64);
 
         Self {
             patch,
             diff: 0,
-            size: size as u32,
+            size,
+            stream: None,
+            header: BundleHeader::default(),
             reader: BundleReader::new(),
             files: Vec::new(),
         }
     }
 
+    /// Creates `BundleVersion` from parts saved by an earlier index.
+    ///
+    /// `files` have to be sorted by extension and name hash like [all_files](BundleVersion::all_files).
+    pub fn from_parts(
+        patch: Patch,
+        size: u64,
+        diff: u64,
+        stream: Option<u64>,
+        header: BundleHeader,
+        reader: BundleReader,
+        files: Vec<BundleFile>,
+    ) -> Self {
+        Self {
+            patch,
+            diff,
+            size,
+            stream,
+            header,
+            reader,
+            files,
+        }
+    }
+
     pub fn size(&self) -> u64 {
-        self.size as u64
+        self.size
     }
 
     pub fn patch(&self) -> Patch {
         self.patch
     }
 
+    /// Difference in size of the bundle and the sum of file sizes in the index.
+    pub fn diff(&self) -> u64 {
+        self.diff
+    }
+
+    /// Size of the `.stream` file if it was indexed with [index_stream](BundleVersion::index_stream).
+    pub fn stream_size(&self) -> Option<u64> {
+        self.stream
+    }
+
+    pub fn reader(&self) -> &BundleReader {
+        &self.reader
+    }
+
     /// Get reference to `BundleReader` to enable optimizations for SSD/unbuffered IO.
     ///
     /// May be removed in future release.
@@ -146,32 +288,56 @@ impl BundleVersion {
         }
     }
 
-    fn get_file_index(&self, ext_hash: u64, name_hash: u64) -> Option<usize> {
-        self.files.binary_search_by(|probe| {
-            match probe.ext_hash().cmp(&ext_hash) {
-                Ordering::Equal => probe.name_hash().cmp(&name_hash),
-                x => x,
-            }
-        }).ok()
-    }
-
     pub fn files(&self) -> Vec<&BundleFile> {
         self.files.iter().filter(|file| file.size() > 0).collect()
     }
 
-    /// Read bundle header data at `4..260`.
+    /// Every file in the index including deleted files without data.
+    pub fn all_files(&self) -> &[BundleFile] {
+        &self.files
+    }
+
+    /// Bundle header read by [index](BundleVersion::index).
+    pub fn bundle_header(&self) -> &BundleHeader {
+        &self.header
+    }
+
+    /// Read raw bundle header data at `4..260`.
+    #[deprecated(note = "use `read_header` to parse the header or `bundle_header` for the indexed header")]
     pub fn header<'a>(
         &mut self,
-        fd: &mut (impl Read + Seek),
+        fd: &mut impl BundleSource,
         buffer: &'a mut ReadBuffer,
     ) -> crate::StingrayResult<&'a [u8]> {
         self.reader.read(fd, buffer, 4..260, None)
     }
 
+    /// Read bundle header data at `4..260`.
+    pub fn read_header(
+        &mut self,
+        fd: &mut impl BundleSource,
+        buffer: &mut ReadBuffer,
+    ) -> crate::StingrayResult<BundleHeader> {
+        BundleHeader::from_bytes(self.reader.read(fd, buffer, 4..260, None)?)
+    }
+
     /// Read bundle index.
+    ///
+    /// Errors have the bundle hash and patch attached.
     pub fn index(
         &mut self,
-        fd: &mut (impl Read + Seek),
+        fd: &mut impl BundleSource,
+        bundle_hash: u64,
+        buffer: &mut ReadBuffer,
+    ) -> crate::StingrayResult<u64> {
+        self.index_(fd, bundle_hash, buffer)
+            .map_err(|e| e.with_bundle(bundle_hash, self.patch))
+    }
+
+    #[doc(hidden)]
+    fn index_(
+        &mut self,
+        fd: &mut impl BundleSource,
         bundle_hash: u64,
         buffer: &mut ReadBuffer,
     ) -> crate::StingrayResult<u64> {
@@ -184,6 +350,12 @@ impl BundleVersion {
         let scrap = self.reader.read(fd, buffer, 0..260, Some(&mut read_raw))?;
         read += read_raw;
         let format = self.reader.version().ok_or_else(|| stingray_error!("no format for bundle"))?;
+        if format < 5 || !codec::is_supported(format) {
+            return Err(crate::StingrayError::UnsupportedVersion {
+                bundle: None,
+                version: format,
+            });
+        }
         let index_size = if format < 6 {
             20
         } else {
@@ -191,15 +363,35 @@ impl BundleVersion {
         };
 
         let num_files = u32::from_le_bytes(scrap[0..4].try_into()?) as usize;
+        self.header = BundleHeader::from_bytes(&scrap[4..260])?;
+
+        let uncompressed_size = self.reader.size();
+
+        // chunks after the 12 byte header have a 4 byte length prefix and
+        // inflate to at most ZLIB_CHUNK_SIZE so a bigger size is corrupt
+        let max_size = self.size.saturating_sub(12) / 4 * consts::ZLIB_CHUNK_SIZE as u64;
+        if uncompressed_size > max_size {
+            return Err(crate::StingrayError::OffsetOverflow {
+                bundle: None,
+                offset: uncompressed_size,
+                size: max_size,
+            });
+        }
 
-        let t: usize = num_files as usize * index_size;
+        let t: usize = num_files * index_size;
+        if 260 + t as u64 > uncompressed_size {
+            return Err(crate::StingrayError::OffsetOverflow {
+                bundle: None,
+                offset: 260 + t as u64,
+                size: uncompressed_size,
+            });
+        }
         let scrap = self.reader.read(fd, buffer, 260..260 + t, Some(&mut read_raw))?;
         read += read_raw;
 
-        let uncompressed_size = self.reader.size();
-
         let mut offset = 260 + t as u64;
-        let mut test = 0;
+        self.diff = 0;
+        self.stream = None;
         self.files.truncate(0);
         self.files.reserve(num_files);
         for i in 0..num_files {
@@ -208,15 +400,12 @@ impl BundleVersion {
             let name = u64::from_le_bytes(scrap[b + 8..b + 16].try_into()?);
             let kind = u32::from_le_bytes(scrap[b + 16..b + 20].try_into()?);
 
-            if offset > u32::MAX as u64 {
-                return Err(stingray_error!(
-                    "bundle is bigger than expected {}-{}: {} >= {}, {}, {}",
-                    uncompressed_size, num_files, offset, u32::MAX, test, b));
-            }
             if offset > uncompressed_size {
-                return Err(stingray_error!(
-                    "offset is larger than bundle size {} > {} (p: {}, s: {}, f: {}, t: {}, i: {}, b: {})",
-                    offset, uncompressed_size, self.patch, uncompressed_size, num_files, test, i, b));
+                return Err(crate::StingrayError::OffsetOverflow {
+                    bundle: None,
+                    offset,
+                    size: uncompressed_size,
+                });
             }
 
             // only format 5 and 6 have been tested
@@ -231,7 +420,6 @@ impl BundleVersion {
                 println!("bundle \"{}\" has invalid hash in index at offset {}", bundle_name, 260 + b);
             }
 
-            test = size;
             let file_offset = offset;
             offset += if size > 0 {
                 36 + size as u64
@@ -239,206 +427,941 @@ impl BundleVersion {
                 24
             } else { 0 };
 
-            let mut file = BundleFile::new(name, ext, size, file_offset as u32);
+            let mut file = BundleFile::new(name, ext, size as u64, file_offset);
             file.set_kind(kind);
             self.files.push(file);
         }
 
         // check if bundle is larger than the sum of file sizes from the index
-        // if so then invalidate all files that might be incorrectly size
         if offset != uncompressed_size {
             let (diff, overflow) = uncompressed_size.overflowing_sub(offset);
             if overflow {
                 return Err(stingray_error!("diff underflow"));
             }
-            self.diff = diff as u32;
+            self.diff = diff;
+
+            // legacy bundle formats do not store size in index and some file
+            // types have wrong sizes so find the real sizes from file headers
+            self.resolve_files(fd, format, 260 + t as u64, buffer, &mut read_raw)?;
+            read += read_raw;
+        }
+
+        self.files.sort_by(|a, b| {
+            (a.ext_hash(), a.name_hash()).cmp(&(b.ext_hash(), b.name_hash()))
+        });
 
-            // legacy bundle formats do not store size in index
-            if format < 6 {
-                for file in &mut self.files {
-                    file.set_bad_offset(true);
+        Ok(read as u64)
+    }
+
+    /// Resolve offsets and sizes of files in index order from file headers.
+    ///
+    /// Deleted files take 24 bytes and format 6 files without size take none.
+    /// Every other file is looked for at the end of the previous file and
+    /// after it if the header there belongs to another file. Files that can
+    /// not be found are flagged as [unresolved](BundleFile::is_unresolved).
+    fn resolve_files(
+        &mut self,
+        fd: &mut impl BundleSource,
+        format: u16,
+        start: u64,
+        buffer: &mut ReadBuffer,
+        read: &mut u64,
+    ) -> crate::StingrayResult<()> {
+        let mut read_raw = 0;
+        *read = 0;
+
+        let size = self.reader.size();
+        let mut offset = start;
+        for i in 0..self.files.len() {
+            let (ext, name, kind) = (self.files[i].ext_hash(), self.files[i].name_hash(), self.files[i].kind());
+            if kind == 1 || kind == 2 || (format >= 6 && self.files[i].size() == 0) {
+                self.files[i].set_offset(offset);
+                if kind == 1 || kind == 2 {
+                    offset = size.min(offset + 24);
                 }
-            } else {
-                let mut extensions = Vec::new();
-
-                let mut last = None;
-                let mut start = None;
-                let mut end = None;
-                for (i, file) in self.files.iter_mut().enumerate() {
-                    let kind = FileKind::with_hash(file.ext_hash());
-
-                    match kind {
-                        // assume unknown files are incorrectly sized for
-                        // maximum capability with unsupported file types
-                        FileKind::Unknown
-                        | FileKind::particles
-                        | FileKind::slug
-                        | FileKind::strings => {
-                            if last.is_none() || last != Some(kind) {
-                                last = Some(kind);
-                                extensions.push((file.ext_hash(), kind.as_str().map(|s| s.to_owned())));
-                            }
-
-                            if start.is_none() {
-                                start = Some(i);
-                            }
-
-                            end = Some(i + 1);
-                        },
-                        _ => (),
-                    }
+                continue;
+            }
+
+            let found = self.find_file(fd, offset, (ext, name), buffer, &mut read_raw)?;
+            *read += read_raw;
+            let file_offset = match found {
+                Some(file_offset) => file_offset,
+                None => {
+                    self.files[i].set_unresolved(true);
+                    continue;
                 }
+            };
 
-                // invalidate size of any file between
-                // the first and last detected file
-                if let Some(start) = start {
-                    if let Some(end) = end {
-                        if start + 1 == end {
-                            let file = &mut self.files[start];
-                            file.set_size(file.size() + diff as u32);
-                        } else {
-                            for file in &mut self.files[start..end] {
-                                file.set_bad_offset(true);
-                            }
-                        }
-
-                        for file in &mut self.files[end..num_files] {
-                            file.set_offset(file.offset() + diff as u32);
-                        }
-                    } else {
-                        return Err(stingray_error!(
-                            "fallthrough2 {} {} {}",
-                            format_bundle(bundle_hash, self.patch),
-                            diff,
-                            self.files[start].offset()));
-                    }
-                } else {
-                    return Err(stingray_error!(
-                        "fallthrough1 {} {}",
-                        format_bundle(bundle_hash, self.patch),
-                        diff));
+            let (info, header_size) = self.read_file_info(fd, file_offset, buffer, &mut read_raw)?;
+            *read += read_raw;
+            let data_size = info.variants().iter().map(|variant| variant.size() as u64).sum::<u64>();
+            let end = file_offset + header_size + data_size;
+            if end > size {
+                self.files[i].set_unresolved(true);
+                continue;
+            }
+
+            let file = &mut self.files[i];
+            file.set_unresolved(false);
+            file.set_offset(file_offset);
+            file.set_size((header_size + data_size).saturating_sub(consts::FILE_HEADER_SIZE as u64));
+            offset = end;
+        }
+
+        Ok(())
+    }
+
+    /// Find the offset of the header of file `target` at or after `offset`.
+    ///
+    /// Searches one chunk at a time so big gaps do not need big buffers.
+    fn find_file(
+        &mut self,
+        fd: &mut impl BundleSource,
+        offset: u64,
+        target: (u64, u64),
+        buffer: &mut ReadBuffer,
+        read: &mut u64,
+    ) -> crate::StingrayResult<Option<u64>> {
+        let mut read_raw = 0;
+        *read = 0;
+
+        let mut needle = [0; 16];
+        needle[..8].copy_from_slice(&target.0.to_le_bytes());
+        needle[8..].copy_from_slice(&target.1.to_le_bytes());
+
+        let size = self.reader.size();
+        if offset + 24 > size {
+            return Ok(None);
+        }
+
+        // files are usually right after the previous file
+        let scrap = self.reader.read(fd, buffer, offset as usize..offset as usize + 16, Some(&mut read_raw))?;
+        *read += read_raw;
+        if scrap == needle {
+            return Ok(Some(offset));
+        }
+
+        let mut start = offset;
+        while start + 24 <= size {
+            // overlap windows so headers crossing a window are found
+            let end = size.min(start + consts::ZLIB_CHUNK_SIZE as u64 + 15);
+            let scrap = self.reader.read(fd, buffer, start as usize..end as usize, Some(&mut read_raw))?;
+            *read += read_raw;
+            if let Some(i) = scrap.windows(16).position(|window| window == needle) {
+                if start + i as u64 + 24 <= size {
+                    return Ok(Some(start + i as u64));
                 }
+                return Ok(None);
             }
+
+            start = end - 15;
         }
 
-        self.files.sort_by(|a, b| {
-            (a.ext_hash(), a.name_hash()).cmp(&(b.ext_hash(), b.name_hash()))
-        });
+        Ok(None)
+    }
 
-        Ok(read as u64)
+    /// Read the header of the file at `offset`.
+    ///
+    /// Returns the header and its size.
+    fn read_file_info(
+        &mut self,
+        fd: &mut impl BundleSource,
+        offset: u64,
+        buffer: &mut ReadBuffer,
+        read: &mut u64,
+    ) -> crate::StingrayResult<(file::FileInfo, u64)> {
+        let mut read_raw = 0;
+        *read = 0;
+
+        let size = self.reader.size();
+        let offset = offset as usize;
+        if offset as u64 + 24 > size {
+            return Err(crate::StingrayError::OffsetOverflow {
+                bundle: None,
+                offset: offset as u64 + 24,
+                size,
+            });
+        }
+
+        let scrap = self.reader.read(fd, buffer, offset..offset + 24, Some(&mut read_raw))?;
+        *read += read_raw;
+        let header_size = 24 + u32::from_le_bytes(scrap[16..20].try_into()?) as u64 * 12;
+        if offset as u64 + header_size > size {
+            return Err(crate::StingrayError::OffsetOverflow {
+                bundle: None,
+                offset: offset as u64 + header_size,
+                size,
+            });
+        }
+
+        let scrap = self.reader.read(fd, buffer, offset..offset + header_size as usize, Some(&mut read_raw))?;
+        *read += read_raw;
+        let (info, _) = file::get_file_info(scrap)?;
+        Ok((info, header_size))
+    }
+
+    /// Check every chunk and read every file of an indexed bundle.
+    ///
+    /// Unlike [read_file](BundleVersion::read_file) this does not stop at the first problem.
+    /// Chunks are checked until a chunk fails since the offset of later chunks is unknown.
+    /// Deleted files without data are not read and files that could not be
+    /// found while indexing are reported.
+    pub fn verify(
+        &mut self,
+        fd: &mut impl BundleSource,
+        bundle_hash: u64,
+        buffer: &mut ReadBuffer,
+    ) -> VerifyReport {
+        let mut report = VerifyReport {
+            diff: self.diff,
+            ..VerifyReport::default()
+        };
+
+        let size = self.reader.size() as usize;
+        let mut offset = 0;
+        let mut bad_chunk = None;
+        while offset < size {
+            let end = size.min(offset + consts::ZLIB_CHUNK_SIZE);
+            match self.reader.read(fd, buffer, offset..end, None) {
+                Ok(_) => report.chunks += 1,
+                Err(e) => {
+                    report.errors.push(e.with_bundle(bundle_hash, self.patch));
+                    bad_chunk = Some(offset);
+                    break;
+                }
+            }
+            offset = end;
+        }
+
+        let files = self.files.iter()
+            .filter(|file| file.size() > 0 || file.is_unresolved())
+            .map(|file| (file.ext_hash(), file.name_hash(), file.offset() as usize + file.size() as usize))
+            .collect::<Vec<_>>();
+        for (ext_hash, name_hash, end) in files {
+            // every file past a bad chunk would report the same error
+            if let Some(bad_chunk) = bad_chunk {
+                if end + consts::FILE_HEADER_SIZE > bad_chunk {
+                    report.skipped += 1;
+                    continue;
+                }
+            }
+
+            match self.read_file(fd, bundle_hash, ext_hash, name_hash, buffer) {
+                Ok(_) => report.files += 1,
+                Err(e) => report.errors.push(e),
+            }
+        }
+
+        report
+    }
+
+    /// Read stream offsets for files from the bundle's `.stream` file.
+    ///
+    /// Streamed data is stored back to back in the order files are stored in the
+    /// bundle so this reads every file header. Must be called after [index](BundleVersion::index).
+    pub fn index_stream(
+        &mut self,
+        fd: &mut impl BundleSource,
+        bundle_hash: u64,
+        stream_size: u64,
+        buffer: &mut ReadBuffer,
+    ) -> crate::StingrayResult<u64> {
+        let mut read = 0;
+        let mut read_raw = 0;
+
+        // later stream offsets depend on the stream size of every earlier file
+        if let Some(file) = self.files.iter().find(|file| file.is_unresolved()) {
+            return Err(crate::StingrayError::UnresolvedOffset {
+                bundle: Some((bundle_hash, self.patch)),
+                offset: file.offset(),
+                file: (file.ext_hash(), file.name_hash()),
+            });
+        }
+
+        let mut files = (0..self.files.len())
+            .filter(|i| self.files[*i].size() > 0)
+            .collect::<Vec<_>>();
+        files.sort_by_key(|i| self.files[*i].offset());
+
+        let mut stream_offset = 0;
+        for i in files {
+            let (info, _) = self.read_file_info(fd, self.files[i].offset(), buffer, &mut read_raw)
+                .map_err(|e| e.with_bundle(bundle_hash, self.patch))?;
+            read += read_raw;
+            let file_stream_size = info.variants().iter().map(|variant| variant.stream_size() as u64).sum::<u64>();
+            self.files[i].set_stream(stream_offset, file_stream_size);
+
+            stream_offset += file_stream_size;
+        }
+
+        if stream_offset > stream_size {
+            return Err(stingray_error!(
+                "stream data ({}) is bigger than stream file ({}) for bundle \"{}\"",
+                stream_offset, stream_size, format_bundle(bundle_hash, self.patch)));
+        }
+        self.stream = Some(stream_size);
+
+        Ok(read)
+    }
+
+    /// Read streamed data of a file from the bundle's `.stream` file.
+    ///
+    /// Returns an empty slice if the file has no streamed data.
+    pub fn read_stream<'a>(
+        &self,
+        fd: &mut (impl Read + Seek),
+        ext_hash: u64,
+        file_hash: u64,
+        out: &'a mut Vec<u8>,
+    ) -> crate::StingrayResult<&'a [u8]> {
+        let file = self.file(ext_hash, file_hash)
+            .ok_or_else(|| stingray_error!("failed to get file"))?;
+
+        out.clear();
+        if file.stream_size() > 0 {
+            out.resize(file.stream_size() as usize, 0);
+            fd.seek(SeekFrom::Start(file.stream_offset()))?;
+            fd.read_exact(&mut out[..])?;
+        }
+
+        Ok(&out[..])
     }
 
     /// Read a file from `BundleVersion`.
+    ///
+    /// Errors have the bundle hash and patch attached.
     pub fn read_file<'a>(
         &mut self,
-        fd: &mut (impl Read + Seek),
+        fd: &mut impl BundleSource,
         bundle_hash: u64,
         ext_hash: u64,
         file_hash: u64,
         buffer: &'a mut ReadBuffer,
     ) -> crate::StingrayResult<&'a [u8]> {
-        let diff = self.diff as usize;
+        let patch = self.patch;
+        Self::read_file_(&self.files, patch, &mut self.reader, fd, bundle_hash, ext_hash, file_hash, buffer)
+            .map_err(|e| e.with_bundle(bundle_hash, patch))
+    }
 
-        let i = self.get_file_index(ext_hash, file_hash)
-            .ok_or_else(|| stingray_error!("failed to get file"))?;
-        let (left, right) = self.files.split_at_mut(i + 1);
-        let file = left.get_mut(i)
+    /// Read a file with a separate `reader` so threads can read the same `BundleVersion`.
+    ///
+    /// `reader` should be a clone of [reader](BundleVersion::reader). What it
+    /// learns can be kept with [BundleReader::merge](BundleReader::merge).
+    pub fn read_file_with<'a>(
+        &self,
+        reader: &mut BundleReader,
+        fd: &mut impl BundleSource,
+        bundle_hash: u64,
+        ext_hash: u64,
+        file_hash: u64,
+        buffer: &'a mut ReadBuffer,
+    ) -> crate::StingrayResult<&'a [u8]> {
+        Self::read_file_(&self.files, self.patch, reader, fd, bundle_hash, ext_hash, file_hash, buffer)
+            .map_err(|e| e.with_bundle(bundle_hash, self.patch))
+    }
+
+    #[doc(hidden)]
+    #[allow(clippy::too_many_arguments)]
+    fn read_file_<'a>(
+        files: &[BundleFile],
+        patch: Patch,
+        reader: &mut BundleReader,
+        fd: &mut impl BundleSource,
+        bundle_hash: u64,
+        ext_hash: u64,
+        file_hash: u64,
+        buffer: &'a mut ReadBuffer,
+    ) -> crate::StingrayResult<&'a [u8]> {
+        let i = file_index(files, ext_hash, file_hash)
             .ok_or_else(|| stingray_error!("failed to get file"))?;
+        let file = &files[i];
+
+        if file.is_unresolved() {
+            return Err(crate::StingrayError::UnresolvedOffset {
+                bundle: None,
+                offset: file.offset(),
+                file: (file.ext_hash(), file.name_hash()),
+            });
+        }
+
         let file_offset = file.offset() as usize;
-        let reader = &mut self.reader;
+        let size = consts::FILE_HEADER_SIZE + file.size() as usize;
+
+        if (file_offset as u64 + size as u64) > reader.size() {
+            return Err(crate::StingrayError::OffsetOverflow {
+                bundle: None,
+                offset: file_offset as u64 + size as u64,
+                size: reader.size(),
+            });
+        }
 
-        let out = if diff > 0 {
-            if file.is_bad_offset() {
-                let next_file = right.get_mut(0);
-                let target = (file.ext_hash().to_le_bytes(), file.name_hash().to_le_bytes());
+        let out = reader.read(fd, buffer, file_offset..file_offset + size, None)?;
 
-                // TODO do chunking when diff is large to minimize buffer size
-                let mut size = consts::FILE_HEADER_SIZE + file.size() as usize + diff + 16;
+        if out.len() < 36 {
+            return Err(stingray_error!(
+                "error processing file {:016x} {:016x} in bundle \"{}\"",
+                file.ext_hash().swap_bytes(),
+                file.name_hash().swap_bytes(),
+                format_bundle(bundle_hash, patch)));
+        }
 
-                let mut max_size = if let Some(ref f) = next_file {
-                    if !f.is_bad_offset() {
-                        f.offset() as usize + 16
-                    } else {
-                        reader.size() as usize
-                    }
-                } else {
-                    reader.size() as usize
-                };
+        let ext_hash = u64::from_le_bytes(out[..8].try_into()?);
+        let name_hash = u64::from_le_bytes(out[8..16].try_into()?);
 
-                max_size -= file.offset() as usize;
-                if size > max_size {
-                    size = max_size;
-                }
+        if ext_hash != file.ext_hash() || name_hash != file.name_hash() {
+            return Err(crate::StingrayError::HashMismatch {
+                bundle: None,
+                offset: file.offset(),
+                expected: (file.ext_hash(), file.name_hash()),
+                found: (ext_hash, name_hash),
+            });
+        }
 
-                let scrap = reader.read(fd, buffer, file_offset..file_offset + size, None)?;
+        Ok(out)
+    }
+}
 
-                let mut start = 0;
-                while start < size - 16 {
-                    if scrap[start..start + 8] == target.0
-                        && scrap[start + 8..start + 16] == target.1
-                    {
-                        break;
-                    }
-                    start += 1;
-                }
 
-                let end = {
-                    let (info, mut end) = file::get_file_info(&scrap[start..])?;
+/// Index of a file in `files` sorted by extension and name hash.
+fn file_index(files: &[BundleFile], ext_hash: u64, name_hash: u64) -> Option<usize> {
+    files.binary_search_by(|probe| {
+        match probe.ext_hash().cmp(&ext_hash) {
+            Ordering::Equal => probe.name_hash().cmp(&name_hash),
+            x => x,
+        }
+    }).ok()
+}
 
-                    for variant in info.variants() {
-                        end += variant.size() as usize;
-                    }
 
-                    start + end
-                };
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::sync::Arc;
 
-                file.set_offset(file.offset() + start as u32);
-                file.set_size((end - start) as u32);
-                file.set_bad_offset(false);
+    use super::*;
+    use crate::{BundleWriter, ChunkCache, MappedBundle, StingrayError};
+    use crate::file::{FileVariant, Language};
 
-                &scrap[start..end]
-            } else {
-                let size = consts::FILE_HEADER_SIZE + file.size() as usize;
+    fn write_bundle(num_files: u64, len: usize) -> Vec<u8> {
+        let mut writer = BundleWriter::new(6).unwrap();
+        for i in 0..num_files {
+            let payload = (0..len).map(|n| (n as u64 ^ i) as u8).collect::<Vec<_>>();
+            let variants = vec![FileVariant::new(Language::English, len as u32)];
+            writer.add_file(FileKind::config as u64, i, variants, payload).unwrap();
+        }
 
-                reader.read(fd, buffer, file_offset..file_offset + size, None)?
-            }
-        } else {
-            if file.is_bad_offset() {
-                return Err(stingray_error!("diff is 0 but file has bad offset"));
-            }
+        let mut bundle = Vec::new();
+        writer.write(&mut bundle).unwrap();
+        bundle
+    }
+
+    #[test]
+    fn reverse_reads() {
+        let bundle = write_bundle(16, consts::ZLIB_CHUNK_SIZE / 2 + 3);
+
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        let mut fd = Cursor::new(bundle);
+        let mut buffer = ReadBuffer::default();
+        version.index(&mut fd, 0, &mut buffer).unwrap();
+
+        // late chunks first so offsets come from the chunk table
+        for i in (0..16u64).rev() {
+            let mut buffer = ReadBuffer::default();
+            let out = version.read_file(&mut fd, 0, FileKind::config as u64, i, &mut buffer).unwrap();
+            assert!(out[36..].iter().enumerate().all(|(n, byte)| *byte == (n as u64 ^ i) as u8));
+        }
+    }
+
+    #[test]
+    fn mapped_bundle() {
+        let mut bundle = write_bundle(4, consts::ZLIB_CHUNK_SIZE);
+
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        let mut buffer = ReadBuffer::default();
+        version.index(&mut MappedBundle::new(&bundle), 0, &mut buffer).unwrap();
+
+        for i in 0..4u64 {
+            let out = version.read_file(&mut MappedBundle::new(&bundle), 0, FileKind::config as u64, i, &mut buffer).unwrap();
+            assert!(out[36..].iter().enumerate().all(|(n, byte)| *byte == (n as u64 ^ i) as u8));
+        }
+
+        bundle.truncate(bundle.len() - 64);
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        version.index(&mut MappedBundle::new(&bundle), 0, &mut buffer).unwrap();
+        let e = version.read_file(&mut MappedBundle::new(&bundle), 0, FileKind::config as u64, 3, &mut buffer);
+        assert!(matches!(e, Err(StingrayError::TruncatedChunk { .. })));
+    }
+
+    #[test]
+    fn bundle_stream() {
+        use std::io::{Read, Seek, SeekFrom};
+        use crate::BundleStream;
+
+        let bundle = write_bundle(5, consts::ZLIB_CHUNK_SIZE / 3);
+        let mut stream = BundleStream::new(Cursor::new(&bundle)).unwrap();
+        let mut all = Vec::new();
+        stream.read_to_end(&mut all).unwrap();
+        assert_eq!(all.len() as u64, stream.len());
+
+        // reads that cross chunk boundaries after seeking backwards
+        let mut mapped = BundleStream::new(MappedBundle::new(&bundle)).unwrap();
+        for &offset in &[consts::ZLIB_CHUNK_SIZE as u64 - 10, 7, all.len() as u64 - 3] {
+            stream.seek(SeekFrom::Start(offset)).unwrap();
+            mapped.seek(SeekFrom::Start(offset)).unwrap();
+
+            let mut out = vec![0; 20.min(all.len() - offset as usize)];
+            stream.read_exact(&mut out).unwrap();
+            assert_eq!(&out[..], &all[offset as usize..offset as usize + out.len()]);
+            mapped.read_exact(&mut out).unwrap();
+            assert_eq!(&out[..], &all[offset as usize..offset as usize + out.len()]);
+        }
+
+        assert_eq!(stream.read(&mut [0; 4]).unwrap(), 0);
+        assert!(stream.seek(SeekFrom::Current(-(all.len() as i64) - 1)).is_err());
+    }
 
-            let size = consts::FILE_HEADER_SIZE + file.size() as usize;
+    #[test]
+    fn parallel_reads() {
+        let bundle = write_bundle(3, consts::ZLIB_CHUNK_SIZE * 5 + 11);
 
-            if (file.offset() as u64 + size as u64) > reader.size() {
-                return Err(stingray_error!(
-                    "file offset ({}) and size ({}) is bigger than the raw bundle size ({})",
-                    file.offset(), size, reader.size()));
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        let mut fd = Cursor::new(&bundle);
+        let mut buffer = ReadBuffer::default();
+        version.index(&mut fd, 0, &mut buffer).unwrap();
+        version.reader_mut().set_threads(4);
+
+        // first pass fills chunk table, second pass decompresses in parallel
+        for _ in 0..2 {
+            for i in 0..3u64 {
+                let out = version.read_file(&mut fd, 0, FileKind::config as u64, i, &mut buffer).unwrap();
+                assert!(out[36..].iter().enumerate().all(|(n, byte)| *byte == (n as u64 ^ i) as u8));
+
+                let out = version.read_file(&mut MappedBundle::new(&bundle), 0, FileKind::config as u64, i, &mut buffer).unwrap();
+                assert!(out[36..].iter().enumerate().all(|(n, byte)| *byte == (n as u64 ^ i) as u8));
             }
+        }
+    }
+
+    #[test]
+    fn parallel_read_batches() {
+        // file spans more chunks than are read into the buffer at once
+        let bundle = write_bundle(2, consts::ZLIB_CHUNK_SIZE * 70 + 3);
+
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        let mut fd = Cursor::new(&bundle);
+        let mut buffer = ReadBuffer::default();
+        version.index(&mut fd, 0, &mut buffer).unwrap();
+        version.verify(&mut fd, 0, &mut buffer);
+        version.reader_mut().set_threads(3);
+
+        for i in 0..2u64 {
+            let out = version.read_file(&mut fd, 0, FileKind::config as u64, i, &mut buffer).unwrap();
+            assert_eq!(out.len(), 36 + consts::ZLIB_CHUNK_SIZE * 70 + 3);
+            assert!(out[36..].iter().enumerate().all(|(n, byte)| *byte == (n as u64 ^ i) as u8));
+        }
+    }
+
+    #[test]
+    fn prefetched_reads() {
+        let bundle = write_bundle(3, consts::ZLIB_CHUNK_SIZE * 2 + 5);
+
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        let mut fd = Cursor::new(&bundle);
+        let mut buffer = ReadBuffer::default();
+        version.index(&mut fd, 0, &mut buffer).unwrap();
+
+        let cache = Arc::new(ChunkCache::new(16));
+        let mut prefetch_buffer = ReadBuffer::default();
+        prefetch_buffer.set_cache(cache.clone());
+        let mut reader = version.reader().clone();
+        let chunks = (reader.size() as usize + consts::ZLIB_CHUNK_SIZE - 1) / consts::ZLIB_CHUNK_SIZE;
+
+        // chunks past the end are ignored
+        for chunk in 0..chunks + 2 {
+            reader.prefetch(&mut Cursor::new(&bundle), &mut prefetch_buffer, chunk).unwrap();
+        }
+        assert_eq!((cache.hits(), cache.misses(), cache.prefetched()), (0, 0, chunks as u64));
+
+        buffer.set_cache(cache.clone());
+        for i in 0..3u64 {
+            let out = version.read_file(&mut fd, 0, FileKind::config as u64, i, &mut buffer).unwrap();
+            assert!(out[36..].iter().enumerate().all(|(n, byte)| *byte == (n as u64 ^ i) as u8));
+        }
+        assert_eq!(cache.misses(), 0);
+        assert!(cache.hits() > 0);
+    }
 
-            reader.read(fd, buffer, file_offset..file_offset + size, None)?
+    #[test]
+    fn unbuffered_reads() {
+        let bundle = write_bundle(6, consts::ZLIB_CHUNK_SIZE / 2 + 3);
+
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        version.reader_mut().unbuffered(true);
+        let mut fd = Cursor::new(&bundle);
+        let mut buffer = ReadBuffer::default();
+        version.index(&mut fd, 0, &mut buffer).unwrap();
+
+        for i in (0..6u64).rev() {
+            let out = version.read_file(&mut fd, 0, FileKind::config as u64, i, &mut buffer).unwrap();
+            assert!(out[36..].iter().enumerate().all(|(n, byte)| *byte == (n as u64 ^ i) as u8));
+        }
+    }
+
+    #[test]
+    fn large_bundle_size() {
+        let mut bundle = write_bundle(1, 100);
+        let size = u64::from_le_bytes(bundle[4..12].try_into().unwrap());
+
+        // upper half of the uncompressed size is only set past 4 GiB
+        bundle[8] = 1;
+        let mut reader = BundleReader::new();
+        let mut buffer = ReadBuffer::default();
+        reader.read(&mut Cursor::new(&bundle), &mut buffer, 0..4, None).unwrap();
+        assert_eq!(reader.size(), size + (1 << 32));
+
+        let version = BundleVersion::new(Patch::new_base(), 1 << 33);
+        assert_eq!(version.size(), 1 << 33);
+
+        // contents can not hold the size from the header
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        match version.index(&mut Cursor::new(&bundle), 0, &mut buffer) {
+            Err(StingrayError::OffsetOverflow { offset, .. }) => assert_eq!(offset, size + (1 << 32)),
+            x => panic!("expected offset overflow but got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn verify_report() {
+        let bundle = write_bundle(4, consts::ZLIB_CHUNK_SIZE);
+        let chunks = (bundle.len() - 12) / 4;
+
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        let mut buffer = ReadBuffer::default();
+        version.index(&mut MappedBundle::new(&bundle), 0, &mut buffer).unwrap();
+        let report = version.verify(&mut MappedBundle::new(&bundle), 0, &mut buffer);
+        assert!(report.is_ok());
+        assert_eq!((report.files(), report.skipped(), report.diff()), (4, 0, 0));
+        assert!(report.chunks() > 4 && report.chunks() < chunks);
+
+        // files past a truncated chunk are skipped
+        let truncated = &bundle[..bundle.len() - 64];
+        let mut version = BundleVersion::new(Patch::new(1), truncated.len() as u64);
+        version.index(&mut MappedBundle::new(truncated), 0xab, &mut buffer).unwrap();
+        let report = version.verify(&mut MappedBundle::new(truncated), 0xab, &mut buffer);
+        assert!(!report.is_ok());
+        assert_eq!((report.files(), report.skipped()), (3, 1));
+        match report.errors() {
+            [e @ StingrayError::TruncatedChunk { .. }] => assert_eq!(e.bundle(), Some((0xab, Patch::new(1)))),
+            e => panic!("expected truncated chunk but got {:?}", e),
+        }
+
+        // second chunk has a length that does not fit in a chunk
+        let mut bad = bundle.clone();
+        let second = 16 + u32::from_le_bytes(bad[12..16].try_into().unwrap()) as usize;
+        bad[second..second + 4].copy_from_slice(&(consts::ZLIB_CHUNK_SIZE as u32 + 1).to_le_bytes());
+        let mut version = BundleVersion::new(Patch::new_base(), bad.len() as u64);
+        version.index(&mut MappedBundle::new(&bad), 0, &mut buffer).unwrap();
+        let report = version.verify(&mut MappedBundle::new(&bad), 0, &mut buffer);
+        assert_eq!((report.chunks(), report.files(), report.skipped()), (1, 0, 4));
+        assert!(matches!(report.errors(), [StingrayError::BadChunkLength { chunk: 1, .. }]));
+    }
+
+    #[test]
+    fn mis_sized_files() {
+        use crate::codec::Stored;
+
+        // stored chunks so the uncompressed bundle can be edited in place
+        let mut writer = BundleWriter::new(6).unwrap();
+        writer.set_codec(Arc::new(Stored));
+        for i in 0..3u64 {
+            let variants = vec![FileVariant::new(Language::English, 100)];
+            writer.add_file(FileKind::config as u64, i, variants, vec![i as u8 + 1; 100]).unwrap();
+        }
+        let mut bundle = Vec::new();
+        writer.write(&mut bundle).unwrap();
+
+        let index = |bundle: &[u8]| {
+            let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+            version.reader_mut().set_codec(Arc::new(Stored));
+            version.index(&mut MappedBundle::new(bundle), 0, &mut ReadBuffer::default()).map(|_| version)
         };
 
-        if out.len() < 36 {
-            return Err(stingray_error!(
-                "error processing file {:016x} {:016x} in bundle \"{}\"",
-                file.ext_hash().swap_bytes(),
-                file.name_hash().swap_bytes(),
-                format_bundle(bundle_hash, self.patch)));
+        // index claims the first file is 8 bytes smaller than its header says
+        let mut mis_sized = bundle.clone();
+        let size_at = 16 + 260 + 20;
+        mis_sized[size_at..size_at + 4].copy_from_slice(&92u32.to_le_bytes());
+        let mut version = index(&mis_sized).unwrap();
+        assert_eq!(version.diff(), 8);
+        let mut buffer = ReadBuffer::default();
+        for i in 0..3u64 {
+            let out = version.read_file(&mut MappedBundle::new(&mis_sized), 0, FileKind::config as u64, i, &mut buffer).unwrap();
+            assert_eq!(&out[36..], &[i as u8 + 1; 100][..]);
         }
 
-        let ext_hash = u64::from_le_bytes(out[..8].try_into()?);
-        let name_hash = u64::from_le_bytes(out[8..16].try_into()?);
+        // bundle is bigger than the files in it
+        let mut padded = bundle.clone();
+        let size = u64::from_le_bytes(padded[4..12].try_into().unwrap());
+        padded[4..12].copy_from_slice(&(size + 8).to_le_bytes());
+        let mut version = index(&padded).unwrap();
+        assert_eq!(version.diff(), 8);
+        for i in 0..3u64 {
+            let out = version.read_file(&mut MappedBundle::new(&padded), 0, FileKind::config as u64, i, &mut buffer).unwrap();
+            assert_eq!(&out[36..], &[i as u8 + 1; 100][..]);
+        }
+    }
 
-        assert!(ext_hash == file.ext_hash() && name_hash == file.name_hash(),
-            "bundle \"{}\" has hash mismatch {:016x} != {:016x} ({:?}) || {:016x} != {:016x} at offset {}",
-            format_bundle(bundle_hash, self.patch),
-            ext_hash.swap_bytes(),
-            file.ext_hash().swap_bytes(),
-            FileKind::with_hash(file.ext_hash()).as_str(),
-            name_hash.swap_bytes(),
-            file.name_hash().swap_bytes(),
-            file.offset());
+    /// Part of an uncompressed bundle for [raw_bundle](raw_bundle).
+    enum Entry {
+        /// Name hash, data and stream size of a file.
+        File(u64, Vec<u8>, u32),
+        /// Deleted file with a 24 byte stub.
+        Deleted(u64),
+        /// File in the index without any data.
+        Empty(u64),
+        /// Bytes not accounted for by the index.
+        Gap(usize),
+    }
 
-        Ok(out)
+    /// Compressed bundle of `format` with `entries` stored in raw chunks.
+    ///
+    /// `mis_size` is added to the index size of the first file of format 6.
+    fn raw_bundle(format: u32, entries: &[Entry], mis_size: i64) -> Vec<u8> {
+        let ext = FileKind::wwise_stream as u64;
+        let mut index = Vec::new();
+        let mut data = Vec::new();
+        let mut first = true;
+        for entry in entries {
+            let (hash, kind, size) = match entry {
+                Entry::File(hash, payload, stream_size) => {
+                    let start = data.len();
+                    data.extend_from_slice(&ext.to_le_bytes());
+                    data.extend_from_slice(&hash.to_le_bytes());
+                    data.extend_from_slice(&1u32.to_le_bytes());
+                    data.extend_from_slice(&0u32.to_le_bytes());
+                    data.extend_from_slice(&0u32.to_le_bytes());
+                    data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+                    data.extend_from_slice(&stream_size.to_le_bytes());
+                    data.extend_from_slice(payload);
+
+                    let mut size = (data.len() - start - consts::FILE_HEADER_SIZE) as i64;
+                    if first {
+                        size += mis_size;
+                        first = false;
+                    }
+                    (*hash, 0u32, size as u32)
+                }
+                Entry::Deleted(hash) => {
+                    data.extend_from_slice(&ext.to_le_bytes());
+                    data.extend_from_slice(&hash.to_le_bytes());
+                    data.extend_from_slice(&[0; 8]);
+                    (*hash, 1, 0)
+                }
+                Entry::Empty(hash) => (*hash, 0, 0),
+                Entry::Gap(len) => {
+                    data.extend(std::iter::repeat(0xee).take(*len));
+                    continue;
+                }
+            };
+
+            index.extend_from_slice(&ext.to_le_bytes());
+            index.extend_from_slice(&hash.to_le_bytes());
+            index.extend_from_slice(&kind.to_le_bytes());
+            if format >= 6 {
+                index.extend_from_slice(&size.to_le_bytes());
+            }
+        }
+
+        let index_size = if format < 6 { 20 } else { 24 };
+        let mut uncompressed = Vec::new();
+        uncompressed.extend_from_slice(&((index.len() / index_size) as u32).to_le_bytes());
+        uncompressed.extend_from_slice(&[0; BundleHeader::SIZE]);
+        uncompressed.extend_from_slice(&index);
+        uncompressed.extend_from_slice(&data);
+
+        let mut bundle = Vec::new();
+        bundle.extend_from_slice(&(0xf000_0000 | format).to_le_bytes());
+        bundle.extend_from_slice(&(uncompressed.len() as u64).to_le_bytes());
+        for src in uncompressed.chunks(consts::ZLIB_CHUNK_SIZE) {
+            // chunks that take a whole chunk are stored raw
+            let mut chunk = vec![0; consts::ZLIB_CHUNK_SIZE];
+            chunk[..src.len()].copy_from_slice(src);
+            bundle.extend_from_slice(&(consts::ZLIB_CHUNK_SIZE as u32).to_le_bytes());
+            bundle.extend_from_slice(&chunk);
+        }
+        bundle
+    }
+
+    #[test]
+    fn deleted_files() {
+        let ext = FileKind::wwise_stream as u64;
+        for format in [5, 6] {
+            // format 5 can not tell files without data from other files
+            let mut entries = vec![
+                Entry::File(0, vec![1; 100], 16),
+                Entry::Deleted(1),
+                Entry::File(3, vec![3; 50], 32),
+                Entry::Gap(5),
+                Entry::File(4, vec![4; 10], 8),
+            ];
+            if format >= 6 {
+                entries.insert(2, Entry::Empty(2));
+            }
+            let bundle = raw_bundle(format, &entries, -8);
+
+            let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+            let mut fd = Cursor::new(&bundle);
+            let mut buffer = ReadBuffer::default();
+            version.index(&mut fd, 0, &mut buffer).unwrap();
+            assert!(version.diff() > 0);
+            assert!(version.all_files().iter().all(|file| !file.is_unresolved()));
+            assert_eq!(version.files().iter().map(|file| file.name_hash()).collect::<Vec<_>>(), vec![0, 3, 4]);
+
+            for (hash, byte, len) in [(0, 1, 100), (3, 3, 50), (4, 4, 10)] {
+                let out = version.read_file(&mut fd, 0, ext, hash, &mut buffer).unwrap();
+                assert_eq!(&out[36..], &vec![byte; len][..]);
+            }
+            assert!(version.verify(&mut fd, 0, &mut buffer).is_ok());
+
+            // deleted files have no streamed data
+            version.index_stream(&mut fd, 0, 56, &mut buffer).unwrap();
+            let streams = version.all_files().iter()
+                .map(|file| (file.name_hash(), file.stream_offset(), file.stream_size()))
+                .collect::<Vec<_>>();
+            let mut expected = vec![(0, 0, 16), (1, 0, 0), (3, 16, 32), (4, 48, 8)];
+            if format >= 6 {
+                expected.insert(2, (2, 0, 0));
+            }
+            assert_eq!(streams, expected);
+        }
+    }
+
+    #[test]
+    fn unresolved_files() {
+        let ext = FileKind::wwise_stream as u64;
+        let bundle = raw_bundle(5, &[
+            Entry::File(0, vec![1; 100], 16),
+            Entry::Empty(1),
+            Entry::File(2, vec![2; 10], 8),
+        ], 0);
+
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        let mut fd = Cursor::new(&bundle);
+        let mut buffer = ReadBuffer::default();
+        version.index(&mut fd, 0xab, &mut buffer).unwrap();
+        assert!(version.file(ext, 1).unwrap().is_unresolved());
+
+        // only the missing file fails
+        let out = version.read_file(&mut fd, 0xab, ext, 2, &mut buffer).unwrap();
+        assert_eq!(&out[36..], &[2; 10]);
+        match version.read_file(&mut fd, 0xab, ext, 1, &mut buffer) {
+            Err(e @ StingrayError::UnresolvedOffset { .. }) => assert_eq!(e.bundle(), Some((0xab, Patch::new_base()))),
+            x => panic!("expected unresolved offset but got {:?}", x.map(|out| out.len())),
+        }
+
+        let report = version.verify(&mut fd, 0xab, &mut buffer);
+        assert_eq!(report.files(), 2);
+        assert!(matches!(report.errors(), [StingrayError::UnresolvedOffset { file: (_, 1), .. }]));
+        assert!(matches!(version.index_stream(&mut fd, 0xab, 24, &mut buffer), Err(StingrayError::UnresolvedOffset { .. })));
+    }
+
+    #[test]
+    fn saved_reader_codec() {
+        use crate::codec::{self, Stored};
+
+        // zlib can not read stored chunks so the codec has to come from the format
+        const FORMAT: u16 = 0x7e;
+        codec::register(FORMAT, Arc::new(Stored));
+
+        let mut writer = BundleWriter::new(6).unwrap();
+        writer.set_codec(Arc::new(Stored));
+        let mut first = vec![0; consts::ZLIB_CHUNK_SIZE];
+        first[..4].copy_from_slice(&[1, 2, 3, 4]);
+        writer.add_file(FileKind::config as u64, 0, vec![FileVariant::new(Language::English, first.len() as u32)], first).unwrap();
+        writer.add_file(FileKind::config as u64, 1, vec![FileVariant::new(Language::English, 16)], vec![7; 16]).unwrap();
+        let mut bundle = Vec::new();
+        writer.write(&mut bundle).unwrap();
+        bundle[..2].copy_from_slice(&FORMAT.to_le_bytes());
+
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        let mut buffer = ReadBuffer::default();
+        version.index(&mut Cursor::new(&bundle), 0, &mut buffer).unwrap();
+        version.verify(&mut Cursor::new(&bundle), 0, &mut buffer);
+
+        // caches only keep the size and chunk table of the reader
+        let saved = BundleReader::with_chunk_offsets(version.reader().size(), version.reader().chunk_offsets().to_vec());
+        assert!(saved.version().is_none());
+        let mut loaded = BundleVersion::from_parts(
+            version.patch(),
+            version.size(),
+            version.diff(),
+            None,
+            version.bundle_header().clone(),
+            saved.clone(),
+            version.all_files().to_vec(),
+        );
+        assert!(loaded.file(FileKind::config as u64, 1).unwrap().offset() > consts::ZLIB_CHUNK_SIZE as u64);
+        let out = loaded.read_file(&mut Cursor::new(&bundle), 0, FileKind::config as u64, 1, &mut buffer).unwrap();
+        assert_eq!(&out[36..], &[7; 16]);
+        assert_eq!(loaded.reader().version(), Some(FORMAT));
+
+        let out = loaded.read_file(&mut MappedBundle::new(&bundle), 0, FileKind::config as u64, 1, &mut buffer).unwrap();
+        assert_eq!(&out[36..], &[7; 16]);
+
+        // clones of a reader that has not read the format learn it on their own
+        let mut reader = saved;
+        let out = version.read_file_with(&mut reader, &mut Cursor::new(&bundle), 0, FileKind::config as u64, 1, &mut buffer).unwrap();
+        assert_eq!(&out[36..], &[7; 16]);
+        assert_eq!(reader.codec().name(), "stored");
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let properties = (1..=NUM_PROPERTIES as u64).collect::<Vec<_>>();
+        let header = BundleHeader::new(&properties).unwrap();
+        let bytes = header.to_bytes();
+        assert_eq!(&bytes[..8], &1u64.to_le_bytes());
+        assert_eq!(BundleHeader::from_bytes(&bytes).unwrap(), header);
+        assert_eq!(header.properties(), properties);
+
+        // unused slots are not properties
+        let header = BundleHeader::new(&[FileKind::lua as u64, 0, 3]).unwrap();
+        assert_eq!(BundleHeader::from_bytes(&header.to_bytes()).unwrap().properties(), vec![FileKind::lua as u64, 3]);
+
+        assert!(BundleHeader::new(&[1; NUM_PROPERTIES + 1]).is_none());
+        assert!(BundleHeader::from_bytes(&bytes[..BundleHeader::SIZE - 1]).is_err());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn read_header() {
+        let mut writer = BundleWriter::new(6).unwrap();
+        let header = BundleHeader::new(&[FileKind::config as u64]).unwrap();
+        writer.set_header(header.clone());
+        let mut bundle = Vec::new();
+        writer.write(&mut bundle).unwrap();
+
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        let mut fd = Cursor::new(bundle);
+        let mut buffer = ReadBuffer::default();
+        assert_eq!(version.read_header(&mut fd, &mut buffer).unwrap(), header);
+        assert_eq!(version.header(&mut fd, &mut buffer).unwrap(), &header.to_bytes()[..]);
+    }
+
+    #[test]
+    fn truncated_bundle() {
+        let mut bundle = write_bundle(4, consts::ZLIB_CHUNK_SIZE);
+        bundle.truncate(bundle.len() - 64);
+
+        let mut version = BundleVersion::new(Patch::new(1), bundle.len() as u64);
+        let mut fd = Cursor::new(bundle);
+        let mut buffer = ReadBuffer::default();
+        version.index(&mut fd, 0xab, &mut buffer).unwrap();
+
+        match version.read_file(&mut fd, 0xab, FileKind::config as u64, 3, &mut buffer) {
+            Err(e @ StingrayError::TruncatedChunk { .. }) => {
+                assert_eq!(e.bundle(), Some((0xab, Patch::new(1))));
+            }
+            x => panic!("expected truncated chunk but got {:?}", x.map(|out| out.len())),
+        }
     }
 }
@@ -0,0 +1,169 @@
+//! Cache of decompressed chunks shared between [ReadBuffer](crate::ReadBuffer)s.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::consts::ZLIB_CHUNK_SIZE;
+
+/// Reader id and chunk index.
+type ChunkKey = (u32, usize);
+
+struct Chunks {
+    tick: u64,
+    chunks: HashMap<ChunkKey, (u64, Box<[u8]>)>,
+    lru: BTreeMap<u64, ChunkKey>,
+}
+
+/// Bounded LRU of decompressed chunks keyed by reader and chunk.
+///
+/// Files next to each other in a bundle often share a chunk. Reading them out
+/// of order would decompress the chunk again for every file without a cache.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::Arc;
+/// use stingray::{ChunkCache, ReadBuffer};
+///
+/// let cache = Arc::new(ChunkCache::new(64));
+/// let mut buffer = ReadBuffer::default();
+/// buffer.set_cache(cache.clone());
+/// assert_eq!(cache.hits(), 0);
+/// ```
+pub struct ChunkCache {
+    capacity: usize,
+    inner: Mutex<Chunks>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    prefetched: AtomicU64,
+}
+
+impl ChunkCache {
+    /// Creates `ChunkCache` that holds up to `capacity` chunks of 64 KiB.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(Chunks {
+                tick: 0,
+                chunks: HashMap::with_capacity(capacity),
+                lru: BTreeMap::new(),
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            prefetched: AtomicU64::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of chunks read from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of chunks that had to be decompressed.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Number of chunks decompressed ahead of reads by [prefetch](crate::BundleReader::prefetch).
+    ///
+    /// Prefetched chunks are not counted as hits or misses.
+    pub fn prefetched(&self) -> u64 {
+        self.prefetched.load(Ordering::Relaxed)
+    }
+
+    /// Check if chunk is cached without counting a hit or miss.
+    pub(crate) fn contains(&self, key: ChunkKey) -> bool {
+        self.inner.lock().unwrap().chunks.contains_key(&key)
+    }
+
+    /// Copy chunk into `out` if it is cached.
+    pub(crate) fn get(&self, key: ChunkKey, out: &mut [u8]) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Chunks { tick, chunks, lru } = &mut *inner;
+        match chunks.get_mut(&key) {
+            Some((last_used, data)) => {
+                *tick += 1;
+                lru.remove(last_used);
+                lru.insert(*tick, key);
+                *last_used = *tick;
+
+                out.copy_from_slice(data);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Add chunk decompressed by [prefetch](crate::BundleReader::prefetch).
+    pub(crate) fn insert_prefetched(&self, key: ChunkKey, data: &[u8]) {
+        self.prefetched.fetch_add(1, Ordering::Relaxed);
+        self.insert(key, data);
+    }
+
+    /// Add decompressed chunk, evicting the least recently used chunk if full.
+    pub(crate) fn insert(&self, key: ChunkKey, data: &[u8]) {
+        if self.capacity == 0 || data.len() != ZLIB_CHUNK_SIZE {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let Chunks { tick, chunks, lru } = &mut *inner;
+        *tick += 1;
+
+        if let Some((last_used, cached)) = chunks.get_mut(&key) {
+            lru.remove(last_used);
+            lru.insert(*tick, key);
+            *last_used = *tick;
+            cached.copy_from_slice(data);
+            return;
+        }
+
+        // reuse allocation of evicted chunk
+        let buffer = if chunks.len() >= self.capacity {
+            let oldest = lru.keys().next().copied();
+            oldest
+                .and_then(|oldest| lru.remove(&oldest))
+                .and_then(|evict| chunks.remove(&evict))
+                .map(|(_, mut buffer)| {
+                    buffer.copy_from_slice(data);
+                    buffer
+                })
+        } else {
+            None
+        }.unwrap_or_else(|| data.into());
+
+        lru.insert(*tick, key);
+        chunks.insert(key, (*tick, buffer));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = ChunkCache::new(2);
+        let mut out = vec![0; ZLIB_CHUNK_SIZE];
+
+        cache.insert((1, 0), &vec![0; ZLIB_CHUNK_SIZE]);
+        cache.insert((1, 1), &vec![1; ZLIB_CHUNK_SIZE]);
+        assert!(cache.get((1, 0), &mut out));
+        cache.insert((1, 2), &vec![2; ZLIB_CHUNK_SIZE]);
+
+        assert!(!cache.get((1, 1), &mut out));
+        assert!(cache.get((1, 2), &mut out));
+        assert_eq!(out[0], 2);
+        assert!(cache.get((1, 0), &mut out));
+        assert_eq!(out[0], 0);
+        assert_eq!((cache.hits(), cache.misses()), (3, 1));
+    }
+}
@@ -0,0 +1,132 @@
+//! Chunk codecs for compressed bundles.
+//!
+//! Bundle chunks are decompressed with the codec registered for the bundle
+//! format version. Formats without a registered codec use [Zlib](Zlib).
+//!
+//! # Example
+//!
+//! ```
+//! use std::sync::Arc;
+//! use stingray::codec::{self, Stored};
+//!
+//! // hypothetical format that stores chunks without compression
+//! codec::register(0x7f, Arc::new(Stored));
+//! assert_eq!(codec::get(0x7f).name(), "stored");
+//! assert_eq!(codec::get(6).name(), "zlib");
+//! ```
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::{Arc, RwLock};
+
+use flate2::read::{ZlibDecoder, ZlibEncoder};
+use flate2::Compression;
+
+/// Registered codecs keyed by bundle format version.
+static CODECS: RwLock<Vec<(u16, Arc<dyn ChunkCodec>)>> = RwLock::new(Vec::new());
+
+/// Zlib codec shared by every format without a registered codec.
+static ZLIB: RwLock<Option<Arc<dyn ChunkCodec>>> = RwLock::new(None);
+
+/// Trait for implementing a chunk decompressor.
+pub trait ChunkCodec: fmt::Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Decompress `src` into `out`.
+    ///
+    /// `out` is always the size of an uncompressed chunk.
+    fn decompress(&self, src: &[u8], out: &mut [u8]) -> io::Result<usize>;
+
+    /// Compress uncompressed chunk `src` into `out`.
+    ///
+    /// Only needed for writing bundles.
+    fn compress(&self, _src: &[u8], _out: &mut Vec<u8>) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} codec does not support compression", self.name())))
+    }
+}
+
+/// Zlib deflate used by bundle formats `5` and `6`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Zlib;
+
+impl ChunkCodec for Zlib {
+    fn name(&self) -> &str {
+        "zlib"
+    }
+
+    fn decompress(&self, src: &[u8], out: &mut [u8]) -> io::Result<usize> {
+        let mut z = ZlibDecoder::new(src);
+        z.read_exact(out)?;
+        Ok(out.len())
+    }
+
+    fn compress(&self, src: &[u8], out: &mut Vec<u8>) -> io::Result<usize> {
+        ZlibEncoder::new(src, Compression::default()).read_to_end(out)
+    }
+}
+
+/// Chunks stored without compression.
+///
+/// Trailing zero padding is dropped when compressing and restored when decompressing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stored;
+
+impl ChunkCodec for Stored {
+    fn name(&self) -> &str {
+        "stored"
+    }
+
+    fn decompress(&self, src: &[u8], out: &mut [u8]) -> io::Result<usize> {
+        if src.len() > out.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "stored chunk is bigger than output"));
+        }
+
+        out[..src.len()].copy_from_slice(src);
+        for byte in &mut out[src.len()..] {
+            *byte = 0;
+        }
+        Ok(out.len())
+    }
+
+    fn compress(&self, src: &[u8], out: &mut Vec<u8>) -> io::Result<usize> {
+        let len = src.iter().rposition(|byte| *byte != 0).map_or(0, |i| i + 1);
+        out.extend_from_slice(&src[..len]);
+        Ok(len)
+    }
+}
+
+/// Register `codec` for bundle format `version`.
+///
+/// Replaces any codec previously registered for `version`.
+pub fn register(version: u16, codec: Arc<dyn ChunkCodec>) {
+    let mut codecs = CODECS.write().unwrap();
+    match codecs.binary_search_by(|(probe, _)| probe.cmp(&version)) {
+        Ok(i) => codecs[i].1 = codec,
+        Err(i) => codecs.insert(i, (version, codec)),
+    }
+}
+
+/// Check if bundle format `version` has a codec.
+///
+/// Formats `5` and `6` always use zlib.
+pub fn is_supported(version: u16) -> bool {
+    version == 5 || version == 6 || CODECS.read().unwrap()
+        .binary_search_by(|(probe, _)| probe.cmp(&version))
+        .is_ok()
+}
+
+/// Get codec for bundle format `version`.
+pub fn get(version: u16) -> Arc<dyn ChunkCodec> {
+    let codecs = CODECS.read().unwrap();
+    match codecs.binary_search_by(|(probe, _)| probe.cmp(&version)) {
+        Ok(i) => codecs[i].1.clone(),
+        Err(_) => zlib(),
+    }
+}
+
+fn zlib() -> Arc<dyn ChunkCodec> {
+    if let Some(ref codec) = *ZLIB.read().unwrap() {
+        return codec.clone();
+    }
+
+    ZLIB.write().unwrap().get_or_insert_with(|| Arc::new(Zlib)).clone()
+}
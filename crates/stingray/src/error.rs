@@ -3,6 +3,8 @@ use std::array;
 use std::error;
 use std::fmt;
 
+use crate::utility::{Patch, format_bundle};
+
 macro_rules! stingray_error {
     () => (crate::StingrayError::with_location("", file!(), line!()));
     ($($arg:expr),+) => (crate::StingrayError::with_location(&format!($($arg,)+), file!(), line!()));
@@ -10,6 +12,9 @@ macro_rules! stingray_error {
 
 pub type StingrayResult<T> = std::result::Result<T, StingrayError>;
 
+/// Hash and patch of the bundle an error happened in.
+pub type BundleId = (u64, Patch);
+
 #[derive(Debug)]
 pub enum StingrayError {
     Io(io::Error),
@@ -17,7 +22,50 @@ pub enum StingrayError {
 
     Stingray {
         error: String,
-    }
+    },
+
+    /// Compressed chunk at `offset` ends before its stored length.
+    TruncatedChunk {
+        bundle: Option<BundleId>,
+        chunk: usize,
+        offset: u64,
+    },
+
+    /// Compressed chunk at `offset` has a length prefix larger than a chunk.
+    BadChunkLength {
+        bundle: Option<BundleId>,
+        chunk: usize,
+        offset: u64,
+        len: u32,
+    },
+
+    /// File header at `offset` does not match the bundle index.
+    HashMismatch {
+        bundle: Option<BundleId>,
+        offset: u64,
+        expected: (u64, u64),
+        found: (u64, u64),
+    },
+
+    /// File with an incorrect size in the index could not be found after `offset`.
+    UnresolvedOffset {
+        bundle: Option<BundleId>,
+        offset: u64,
+        file: (u64, u64),
+    },
+
+    /// Uncompressed `offset` is outside of the bundle `size`.
+    OffsetOverflow {
+        bundle: Option<BundleId>,
+        offset: u64,
+        size: u64,
+    },
+
+    /// Bundle format version without index support or registered codec.
+    UnsupportedVersion {
+        bundle: Option<BundleId>,
+        version: u16,
+    },
 }
 
 impl StingrayError {
@@ -32,6 +80,60 @@ impl StingrayError {
             error: format!("{} at {}:{}", msg, file, line),
         }
     }
+
+    /// Attach bundle hash and patch to errors that are missing it.
+    pub fn with_bundle(mut self, hash: u64, patch: Patch) -> Self {
+        match self {
+            StingrayError::TruncatedChunk { ref mut bundle, .. }
+            | StingrayError::BadChunkLength { ref mut bundle, .. }
+            | StingrayError::HashMismatch { ref mut bundle, .. }
+            | StingrayError::UnresolvedOffset { ref mut bundle, .. }
+            | StingrayError::OffsetOverflow { ref mut bundle, .. }
+            | StingrayError::UnsupportedVersion { ref mut bundle, .. } => {
+                bundle.get_or_insert((hash, patch));
+            }
+            _ => (),
+        }
+        self
+    }
+
+    /// Bundle the error happened in if known.
+    pub fn bundle(&self) -> Option<BundleId> {
+        match *self {
+            StingrayError::TruncatedChunk { bundle, .. }
+            | StingrayError::BadChunkLength { bundle, .. }
+            | StingrayError::HashMismatch { bundle, .. }
+            | StingrayError::UnresolvedOffset { bundle, .. }
+            | StingrayError::OffsetOverflow { bundle, .. }
+            | StingrayError::UnsupportedVersion { bundle, .. } => bundle,
+            _ => None,
+        }
+    }
+
+    /// Offset the error happened at if known.
+    ///
+    /// Chunk errors use the compressed offset and file errors use the uncompressed offset.
+    pub fn offset(&self) -> Option<u64> {
+        match *self {
+            StingrayError::TruncatedChunk { offset, .. }
+            | StingrayError::BadChunkLength { offset, .. }
+            | StingrayError::HashMismatch { offset, .. }
+            | StingrayError::UnresolvedOffset { offset, .. }
+            | StingrayError::OffsetOverflow { offset, .. } => Some(offset),
+            _ => None,
+        }
+    }
+}
+
+struct BundleName(Option<BundleId>);
+
+impl fmt::Display for BundleName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some((hash, patch)) => write!(f, "bundle \"{}\"", format_bundle(hash, patch)),
+            None => write!(f, "bundle"),
+        }
+    }
 }
 
 impl fmt::Display for StingrayError {
@@ -40,6 +142,29 @@ impl fmt::Display for StingrayError {
             StingrayError::Io(ref err) => err.fmt(f),
             StingrayError::Array(ref err) => err.fmt(f),
             StingrayError::Stingray { ref error } => error.fmt(f),
+            StingrayError::TruncatedChunk { bundle, chunk, offset } => write!(f,
+                "{} has truncated chunk {} at offset {}",
+                BundleName(bundle), chunk, offset),
+            StingrayError::BadChunkLength { bundle, chunk, offset, len } => write!(f,
+                "{} has invalid length {} for chunk {} at offset {}",
+                BundleName(bundle), len, chunk, offset),
+            StingrayError::HashMismatch { bundle, offset, expected, found } => write!(f,
+                "{} has hash mismatch {:016x} != {:016x} || {:016x} != {:016x} at offset {}",
+                BundleName(bundle),
+                found.0.swap_bytes(),
+                expected.0.swap_bytes(),
+                found.1.swap_bytes(),
+                expected.1.swap_bytes(),
+                offset),
+            StingrayError::UnresolvedOffset { bundle, offset, file } => write!(f,
+                "{} is missing file {:016x} {:016x} after offset {}",
+                BundleName(bundle), file.0.swap_bytes(), file.1.swap_bytes(), offset),
+            StingrayError::OffsetOverflow { bundle, offset, size } => write!(f,
+                "{} has offset {} past its size {}",
+                BundleName(bundle), offset, size),
+            StingrayError::UnsupportedVersion { bundle, version } => write!(f,
+                "{} has unsupported format version {}",
+                BundleName(bundle), version),
         }
     }
 }
@@ -49,7 +174,7 @@ impl error::Error for StingrayError {
         match *self {
             StingrayError::Io(ref err) => Some(err),
             StingrayError::Array(ref err) => Some(err),
-            StingrayError::Stingray {..} => Some(self),
+            _ => Some(self),
         }
     }
 }
@@ -66,10 +191,11 @@ impl From<array::TryFromSliceError> for StingrayError {
     }
 }
 
-
-
-
-
-
-
-
+impl From<StingrayError> for io::Error {
+    fn from(err: StingrayError) -> io::Error {
+        match err {
+            StingrayError::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
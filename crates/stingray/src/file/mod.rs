@@ -2,6 +2,9 @@
 //!
 //! Currently has a custom implementation for `lua` and `texture` files.
 //! Other file types use a generic implementation that copies the data raw.
+//!
+//! Resources with data in a `.stream` file next to the bundle get the streamed
+//! payload through [get_file_interface_with_stream](get_file_interface_with_stream).
 
 use std::convert::TryInto;
 use std::io::Write;
@@ -15,6 +18,7 @@ mod lua;
 mod texture;
 mod strings;
 mod wwise_dep;
+mod wwise_stream;
 
 // Single use macro.
 //
@@ -117,6 +121,22 @@ impl Language {
         }
     }
 
+    /// Language code stored in file variants.
+    pub fn code(&self) -> u32 {
+        match *self {
+            Self::English           => 0,
+            Self::SimplifiedChinese => 2,
+            Self::Polish            => 4,
+            Self::Russian           => 8,
+            Self::French            => 64,
+            Self::Spanish           => 128,
+            Self::Italian           => 256,
+            Self::Portuguese        => 512,
+            Self::German            => 1024,
+            Self::Unknown(x)        => x,
+        }
+    }
+
     fn as_str(&self) -> Option<&'static str> {
         match *self {
             Self::English           => Some("english"),
@@ -137,9 +157,24 @@ pub struct FileVariant {
     // bitflag?
     lang: Language,
     size: u32,
+
+    /// Size of data stored in the `.stream` file of the bundle.
+    stream_size: u32,
 }
 
 impl FileVariant {
+    pub fn new(lang: Language, size: u32) -> Self {
+        Self::with_stream(lang, size, 0)
+    }
+
+    pub fn with_stream(lang: Language, size: u32, stream_size: u32) -> Self {
+        Self {
+            lang,
+            size,
+            stream_size,
+        }
+    }
+
     pub fn lang(&self) -> Language {
         self.lang
     }
@@ -147,6 +182,10 @@ impl FileVariant {
     pub fn size(&self) -> u32 {
         self.size
     }
+
+    pub fn stream_size(&self) -> u32 {
+        self.stream_size
+    }
 }
 
 pub struct FileInfo {
@@ -170,10 +209,19 @@ impl FileInfo {
 }
 
 pub fn get_file_info(buffer: &[u8]) -> crate::StingrayResult<(FileInfo, usize)> {
+    if buffer.len() < 24 {
+        return Err(stingray_error!("file header needs 24 bytes but got {}", buffer.len()));
+    }
+
     let ext = u64::from_le_bytes(buffer[0..8].try_into()?);
     let hash = u64::from_le_bytes(buffer[8..16].try_into()?);
     let num_variants = u32::from_le_bytes(buffer[16..20].try_into()?) as usize;
     let _unknown = u32::from_le_bytes(buffer[20..24].try_into()?);
+    if buffer.len() < 24 + num_variants * 12 {
+        return Err(stingray_error!(
+            "file header with {} variants needs {} bytes but got {}",
+            num_variants, 24 + num_variants * 12, buffer.len()));
+    }
     let mut variants = Vec::with_capacity(num_variants);
 
     for i in 0..num_variants {
@@ -181,7 +229,7 @@ pub fn get_file_info(buffer: &[u8]) -> crate::StingrayResult<(FileInfo, usize)>
         variants.push(FileVariant {
             lang: Language::from_code(u32::from_le_bytes(buffer[n..n + 4].try_into()?)),
             size: u32::from_le_bytes(buffer[n + 4..n + 8].try_into()?),
-            //_unknown: u32::from_le_bytes(buffer[n + 8..n + 12].try_into()?),
+            stream_size: u32::from_le_bytes(buffer[n + 8..n + 12].try_into()?),
         });
     }
 
@@ -202,22 +250,24 @@ pub trait FileReader<'a> {
 }
 
 // default interface for files with no implementation
-// writes file contents raw
+// writes file contents raw followed by streamed data
 struct UnknownFile<'a> {
     buffer: &'a [u8],
+    stream: &'a [u8],
 }
 
 impl<'a> FileReader<'a> for UnknownFile<'a> {
     fn decompile(&mut self, out: &mut dyn Write) -> crate::StingrayResult<usize> {
         let (_, offset) = get_file_info(self.buffer)?;
         out.write_all(&self.buffer[offset..])?;
-        Ok(self.buffer[offset..].len())
+        out.write_all(self.stream)?;
+        Ok(self.buffer[offset..].len() + self.stream.len())
     }
 }
 
 #[allow(non_snake_case, non_upper_case_globals)]
 mod FileFlags {
-    pub const BadOffset: u8 = 0b00000001;
+    pub const Unresolved: u8 = 0b00000001;
     pub const Deleted: u8   = 0b00000010;
     pub const Deleted2: u8  = 0b00000100;
 }
@@ -229,8 +279,10 @@ mod FileFlags {
 // due to a possible bug in the resource compiler Stingray uses there are some
 // file types that can be stored with an incorrect file size
 //
-// a workaround is flagging files possibly affected by this and resolving the offets
-// while extracting those files
+// a workaround is resolving the offsets of every file from the file headers
+// while indexing bundles where the index does not add up to the bundle size
+//
+// files whose header can not be found are flagged so only reading them fails
 //
 // this is also done for older bundle formats since they do not store file size in the index
 #[cfg_attr(feature = "serde_support", derive(serde::Serialize, serde::Deserialize))]
@@ -239,25 +291,46 @@ pub struct BundleFile {
     hash: u64,
     ext: u64,
 
-    size: u32,
+    size: u64,
 
     /// May be removed in a future release.
-    offset: u32,
+    offset: u64,
+
+    /// Offset of streamed data in the `.stream` file of the bundle.
+    stream_offset: u64,
+    stream_size: u64,
 
     flags: u8,
 }
 
 impl BundleFile {
-    pub fn new(hash: u64, ext: u64, size: u32, offset: u32) -> Self {
+    pub fn new(hash: u64, ext: u64, size: u64, offset: u64) -> Self {
         Self {
             hash,
             ext,
             size,
             offset,
+            stream_offset: 0,
+            stream_size: 0,
             flags: 0,
         }
     }
 
+    /// Creates `BundleFile` from parts saved by an earlier index.
+    ///
+    /// `flags` are the raw flags from [flags](BundleFile::flags).
+    pub fn from_parts(hash: u64, ext: u64, size: u64, offset: u64, stream_offset: u64, stream_size: u64, flags: u8) -> Self {
+        Self {
+            hash,
+            ext,
+            size,
+            offset,
+            stream_offset,
+            stream_size,
+            flags,
+        }
+    }
+
     pub fn name_hash(&self) -> u64 {
         self.hash
     }
@@ -266,27 +339,48 @@ impl BundleFile {
         self.ext
     }
 
-    pub fn size(&self) -> u32 {
+    pub fn size(&self) -> u64 {
         self.size
     }
 
-    pub fn offset(&self) -> u32 {
+    pub fn offset(&self) -> u64 {
         self.offset
     }
 
-    pub(crate) fn is_bad_offset(&self) -> bool {
-        self.flags & FileFlags::BadOffset != 0
+    pub fn stream_offset(&self) -> u64 {
+        self.stream_offset
+    }
+
+    /// Size of streamed data or `0` if the file has none.
+    pub fn stream_size(&self) -> u64 {
+        self.stream_size
     }
 
-    pub(crate) fn set_size(&mut self, size: u32) {
+    /// Raw flags for saving the file with [from_parts](BundleFile::from_parts).
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    /// Check if the header of the file could not be found while indexing.
+    ///
+    /// Reading unresolved files fails with [UnresolvedOffset](crate::StingrayError::UnresolvedOffset).
+    pub fn is_unresolved(&self) -> bool {
+        self.flags & FileFlags::Unresolved != 0
+    }
+
+    pub(crate) fn set_size(&mut self, size: u64) {
         self.size = size;
     }
 
-    pub(crate) fn set_offset(&mut self, offset: u32) {
+    pub(crate) fn set_offset(&mut self, offset: u64) {
         self.offset = offset;
     }
 
-    #[allow(dead_code)]
+    pub(crate) fn set_stream(&mut self, offset: u64, size: u64) {
+        self.stream_offset = offset;
+        self.stream_size = size;
+    }
+
     pub(crate) fn kind(&self) -> u8 {
         if self.flags & FileFlags::Deleted2 != 0 {
             2
@@ -297,6 +391,14 @@ impl BundleFile {
         }
     }
 
+    pub(crate) fn set_unresolved(&mut self, set: bool) {
+        if set {
+            self.flags |= FileFlags::Unresolved;
+        } else {
+            self.flags &= !FileFlags::Unresolved;
+        }
+    }
+
     pub(crate) fn set_kind(&mut self, kind: u32) {
         match kind {
             2 => self.flags |= FileFlags::Deleted2,
@@ -304,17 +406,19 @@ impl BundleFile {
             _ => (),
         }
     }
+}
 
-    pub(crate) fn set_bad_offset(&mut self, set: bool) {
-        if set {
-            self.flags |= FileFlags::BadOffset;
-        } else {
-            self.flags &= !FileFlags::BadOffset;
-        }
-    }
+pub fn get_file_interface<'a>(buffer: &'a [u8]) -> crate::StingrayResult<Box<dyn FileReader<'a> + 'a>> {
+    get_file_interface_with_stream(buffer, &[])
 }
 
-pub fn get_file_interface<'a>(buffer: &'a [u8]) -> crate::StingrayResult<Box<dyn FileReader + 'a>> {
+/// Get file interface for a file with `stream` data read from the `.stream` file.
+///
+/// See [BundleVersion::read_stream](crate::BundleVersion::read_stream).
+pub fn get_file_interface_with_stream<'a>(
+    buffer: &'a [u8],
+    stream: &'a [u8],
+) -> crate::StingrayResult<Box<dyn FileReader<'a> + 'a>> {
     let to_read: &[u8; 8] = buffer[0..8].try_into()?;
     let ext_hash = u64::from_le_bytes(*to_read);
     let to_read: &[u8; 8] = buffer[8..16].try_into()?;
@@ -325,10 +429,11 @@ pub fn get_file_interface<'a>(buffer: &'a [u8]) -> crate::StingrayResult<Box<dyn
     let r: Box<dyn FileReader> = match kind {
         FileKind::lua => Box::new(lua::Lua::new(buffer)),
         FileKind::wwise_dep => Box::new(wwise_dep::WwiseDep::new(buffer)),
-        FileKind::texture => Box::new(texture::Texture::new(buffer)),
+        FileKind::texture => Box::new(texture::Texture::new(buffer, stream)),
+        FileKind::wwise_stream => Box::new(wwise_stream::WwiseStream::new(buffer, stream)),
         FileKind::strings => Box::new(strings::Strings::new(buffer)),
         FileKind::bones => Box::new(bones::Bones::new(buffer)),
-        _ => Box::new(UnknownFile {buffer}),
+        _ => Box::new(UnknownFile {buffer, stream}),
     };
     Ok(r)
 }
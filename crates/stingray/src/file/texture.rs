@@ -4,32 +4,112 @@ use std::io::Write;
 // DDS magic word
 const MAGIC_WORD: u64 = u64::from_be(0x444453207c000000);
 
+// DX10 four character code
+const DX10: u32 = u32::from_be(0x44583130);
+
+// DDS header size including magic word
+const DDS_HEADER_SIZE: usize = 128;
+
+// extended DDS header size used with DX10 textures
+const DDS_HEADER_DXT10_SIZE: usize = 20;
+
 pub struct Texture<'a> {
     buffer: &'a [u8],
+    stream: &'a [u8],
 }
 
 impl<'a> Texture<'a> {
-    pub fn new(buffer: &'a [u8]) -> Self {
+    pub fn new(buffer: &'a [u8], stream: &'a [u8]) -> Self {
         Self {
             buffer,
+            stream,
+        }
+    }
+
+    fn is_dds(&self) -> bool {
+        if let Ok(array) = self.buffer[36..44].try_into() {
+            u64::from_le_bytes(array) == MAGIC_WORD
+        } else {
+            false
         }
     }
 }
 
 impl<'a> super::FileReader<'a> for Texture<'a> {
     fn decompile(&mut self, out: &mut dyn Write) -> crate::StingrayResult<usize> {
-        out.write_all(&self.buffer[36..])?;
-        Ok(self.buffer[36..].len())
+        let data = &self.buffer[36..];
+        if self.stream.is_empty() || !self.is_dds() {
+            out.write_all(data)?;
+            out.write_all(self.stream)?;
+            return Ok(data.len() + self.stream.len());
+        }
+
+        // streamed textures keep the largest mip levels in the stream so
+        // they go between the DDS header and the mips stored in the bundle
+        let mut header_size = DDS_HEADER_SIZE;
+        if data.len() >= DDS_HEADER_SIZE {
+            let four_cc = u32::from_le_bytes(data[84..88].try_into()?);
+            if four_cc == DX10 {
+                header_size += DDS_HEADER_DXT10_SIZE;
+            }
+        }
+        let header_size = header_size.min(data.len());
+
+        out.write_all(&data[..header_size])?;
+        out.write_all(self.stream)?;
+        out.write_all(&data[header_size..])?;
+        Ok(data.len() + self.stream.len())
     }
 
     fn path(&self) -> (Option<&str>, Option<&str>) {
-        if let Ok(array) = self.buffer[36..44].try_into() {
-            let magic_word = u64::from_le_bytes(array);
-            if magic_word == MAGIC_WORD {
-                return (None, Some("dds"))
-            }
+        if self.is_dds() {
+            return (None, Some("dds"))
         }
         (None, Some("texture"))
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file::FileReader;
+
+    fn dds(four_cc: u32, mips: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0; 36 + DDS_HEADER_SIZE];
+        buffer[36..44].copy_from_slice(&MAGIC_WORD.to_le_bytes());
+        buffer[36 + 84..36 + 88].copy_from_slice(&four_cc.to_le_bytes());
+        buffer.extend_from_slice(&[7; DDS_HEADER_DXT10_SIZE]);
+        buffer.extend_from_slice(mips);
+        buffer
+    }
+
+    #[test]
+    fn stream_splice() {
+        let stream = [1, 2, 3];
+        let mut out = Vec::new();
+
+        // largest mips from the stream go after the header
+        let buffer = dds(0, &[9, 9]);
+        let written = Texture::new(&buffer, &stream).decompile(&mut out).unwrap();
+        let data = &buffer[36..];
+        assert_eq!(written, out.len());
+        assert_eq!(&out[..DDS_HEADER_SIZE], &data[..DDS_HEADER_SIZE]);
+        assert_eq!(&out[DDS_HEADER_SIZE..DDS_HEADER_SIZE + 3], &stream);
+        assert_eq!(&out[DDS_HEADER_SIZE + 3..], &data[DDS_HEADER_SIZE..]);
+
+        // DX10 headers are extended
+        out.clear();
+        let buffer = dds(DX10, &[9, 9]);
+        Texture::new(&buffer, &stream).decompile(&mut out).unwrap();
+        let header_size = DDS_HEADER_SIZE + DDS_HEADER_DXT10_SIZE;
+        assert_eq!(&out[header_size..header_size + 3], &stream);
+        assert_eq!(&out[header_size + 3..], &[9, 9]);
+
+        // textures that are not DDS keep the stream at the end
+        out.clear();
+        let mut buffer = dds(0, &[9]);
+        buffer[36] = 0;
+        Texture::new(&buffer, &stream).decompile(&mut out).unwrap();
+        assert_eq!(&out[..], &[&buffer[36..], &stream[..]].concat()[..]);
+    }
+}
@@ -0,0 +1,39 @@
+use std::io::Write;
+
+// RIFF magic word used by wwise encoded media
+const MAGIC_WORD: &[u8] = b"RIFF";
+
+pub struct WwiseStream<'a> {
+    buffer: &'a [u8],
+    stream: &'a [u8],
+}
+
+impl<'a> WwiseStream<'a> {
+    pub fn new(buffer: &'a [u8], stream: &'a [u8]) -> Self {
+        Self {
+            buffer,
+            stream,
+        }
+    }
+}
+
+impl<'a> super::FileReader<'a> for WwiseStream<'a> {
+    fn decompile(&mut self, out: &mut dyn Write) -> crate::StingrayResult<usize> {
+        // the data in the bundle is a small stub when the media is streamed
+        if self.stream.is_empty() {
+            out.write_all(&self.buffer[36..])?;
+            Ok(self.buffer[36..].len())
+        } else {
+            out.write_all(self.stream)?;
+            Ok(self.stream.len())
+        }
+    }
+
+    fn path(&self) -> (Option<&str>, Option<&str>) {
+        if self.stream.starts_with(MAGIC_WORD) {
+            (None, Some("wem"))
+        } else {
+            (None, Some("wwise_stream"))
+        }
+    }
+}
@@ -1,4 +1,4 @@
-//! Library for reading bundle resource files from the game engine Stingray.
+//! Library for reading and writing bundle resource files from the game engine Stingray.
 
 #[macro_use]
 mod error;
@@ -8,12 +8,25 @@ pub use error::StingrayError as StingrayError;
 mod reader;
 pub use reader::ReadBuffer as ReadBuffer;
 pub use reader::BundleReader as BundleReader;
+pub use reader::BundleSource as BundleSource;
+pub use reader::MappedBundle as MappedBundle;
+pub use reader::BundleStream as BundleStream;
+
+mod writer;
+pub use writer::BundleWriter as BundleWriter;
 
 pub mod hash;
 
+pub mod codec;
+
+mod cache;
+pub use cache::ChunkCache as ChunkCache;
+
 mod bundle;
 pub use bundle::Bundle as Bundle;
 pub use bundle::BundleVersion as BundleVersion;
+pub use bundle::BundleHeader as BundleHeader;
+pub use bundle::VerifyReport as VerifyReport;
 
 pub mod file;
 pub use file::BundleFile as BundleFile;
@@ -22,6 +35,8 @@ mod utility;
 pub use utility::Patch as Patch;
 pub use utility::format_bundle as format_bundle;
 pub use utility::get_bundle_hash_patch as get_bundle_hash_patch;
+pub use utility::format_stream as format_stream;
+pub use utility::get_stream_hash_patch as get_stream_hash_patch;
 
 mod consts {
     pub(crate) const ZLIB_CHUNK_SIZE: usize = 0x10000;
@@ -3,10 +3,12 @@
 //! Segment reader for the `bundle` package format.
 use std::convert::TryInto;
 use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::ops::Range;
+use std::ops::{Deref, DerefMut, Range};
 
-use flate2::read::ZlibDecoder;
+use super::cache::ChunkCache;
+use super::codec::{self, ChunkCodec};
 use super::consts::ZLIB_CHUNK_SIZE;
 
 /// Unbuffered IO aligned read size.
@@ -15,28 +17,78 @@ const ALIGNED_READ_SIZE: usize = 4096;
 /// Size of header for compressed bundles.
 const BUNDLE_COMPRESSED_HEADER_SIZE: usize = 12;
 
+/// Minimum number of chunks in a read before chunks are decompressed in parallel.
+const PARALLEL_MIN_CHUNKS: usize = 4;
+
+/// Most chunks read into [ReadBuffer](ReadBuffer) at once for parallel decompression.
+///
+/// Bounds the source buffer for bundles that are not in memory.
+const PARALLEL_READ_CHUNKS: usize = 64;
+
 #[doc(hidden)]
 static BUNDLE_READER_ID: AtomicU32 = AtomicU32::new(1);
 
-// Helper function for decompressing from bytes to out.
-#[doc(hidden)]
-fn read_compressed(bytes: &[u8], out: &mut [u8]) -> io::Result<usize> {
-    let mut z = ZlibDecoder::new(bytes);
-    z.read_exact(out)?;
-    Ok(out.len())
+#[derive(Clone, Copy)]
+#[repr(C, align(4096))]
+struct AlignedBlock([u8; ALIGNED_READ_SIZE]);
+
+/// Byte buffer made of whole [ALIGNED_READ_SIZE] blocks at an aligned address.
+///
+/// Stays aligned for unbuffered reads when it grows so offsets into it are
+/// still valid after a reallocation.
+struct AlignedBuffer {
+    blocks: Vec<AlignedBlock>,
+}
+
+impl AlignedBuffer {
+    fn new(size: usize) -> Self {
+        let mut buffer = Self {
+            blocks: Vec::new(),
+        };
+        buffer.grow(size);
+        buffer
+    }
+
+    /// Grow to at least `size` bytes rounded up to whole blocks.
+    fn grow(&mut self, size: usize) {
+        let blocks = match size % ALIGNED_READ_SIZE {
+            0 => size / ALIGNED_READ_SIZE,
+            _ => size / ALIGNED_READ_SIZE + 1,
+        };
+
+        if blocks > self.blocks.len() {
+            self.blocks.resize(blocks, AlignedBlock([0; ALIGNED_READ_SIZE]));
+        }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // blocks are plain bytes without padding
+        unsafe { std::slice::from_raw_parts(self.blocks.as_ptr() as *const u8, self.blocks.len() * ALIGNED_READ_SIZE) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.blocks.as_mut_ptr() as *mut u8, self.blocks.len() * ALIGNED_READ_SIZE) }
+    }
 }
 
 /// Pool object for caching reads and reducing allocations.
 ///
 /// Used internally by the bundle reader object.
 pub struct ReadBuffer {
-    src: Vec<u8>,
+    src: AlignedBuffer,
     out: Vec<u8>,
     id: Option<u32>,
     offset: u64,
     last: u64,
     start: Option<usize>,
     end: Option<usize>,
+    cache: Option<Arc<ChunkCache>>,
 }
 
 impl ReadBuffer {
@@ -45,28 +97,27 @@ impl ReadBuffer {
     #[doc(hidden)]
     fn new_(size: usize) -> Self {
         Self {
-            src: vec![0; size],
+            src: AlignedBuffer::new(size),
             out: vec![0; ZLIB_CHUNK_SIZE],
             id: None,
             offset: 0,
             last: 0,
             start: None,
             end: None,
+            cache: None,
         }
     }
 
     /// Creates `ReadBuffer` with a custom buffer size.
     ///
-    /// If unbuffered IO is enabled then the buffer may increased in size to pad for aligned reads.
+    /// The buffer is rounded up to a multiple of 4096 bytes for aligned reads.
     pub fn new(size: usize) -> Self {
         Self::new_(size)
     }
 
-    fn pad(&mut self) {
-        match self.src.len() % ALIGNED_READ_SIZE {
-            0 => (),
-            x => self.src.resize(self.src.len() + ALIGNED_READ_SIZE - x, 0),
-        }
+    /// Share decompressed chunks with other `ReadBuffer`s through `cache`.
+    pub fn set_cache(&mut self, cache: Arc<ChunkCache>) {
+        self.cache = Some(cache);
     }
 
     fn reset(&mut self) {
@@ -83,6 +134,71 @@ impl Default for ReadBuffer {
     }
 }
 
+/// Source of compressed bundle data for [BundleReader](BundleReader).
+///
+/// Implemented for anything that is `Read + Seek`. Sources that have the whole
+/// bundle in memory, like memory mapped files, can return it from
+/// [as_slice](BundleSource::as_slice) to decompress chunks without copying
+/// them into [ReadBuffer](ReadBuffer) first.
+pub trait BundleSource {
+    /// Read compressed data at `offset` into `buf`.
+    ///
+    /// Returns the number of bytes read.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Compressed bundle if it is already in memory.
+    fn as_slice(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+impl<T: Read + Seek> BundleSource for T {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read(buf)
+    }
+}
+
+/// Bundle that is already in memory.
+///
+/// # Example
+///
+/// ```
+/// use stingray::{BundleVersion, BundleWriter, MappedBundle, Patch, ReadBuffer};
+///
+/// let mut bundle = Vec::new();
+/// BundleWriter::new(6).unwrap().write(&mut bundle).unwrap();
+///
+/// let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+/// let mut buffer = ReadBuffer::default();
+/// version.index(&mut MappedBundle::new(&bundle), 0, &mut buffer).unwrap();
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct MappedBundle<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> MappedBundle<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+        }
+    }
+}
+
+impl BundleSource for MappedBundle<'_> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.get(offset as usize..).unwrap_or(&[]);
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(self.data)
+    }
+}
+
 /// Get incrementing unique ID from an atomic to use with [BundleReader](BundleReader) for [ReadBuffer](ReadBuffer)s.
 fn get_unique_id() -> u32 {
     BUNDLE_READER_ID.fetch_add(1, Ordering::SeqCst)
@@ -93,9 +209,13 @@ fn get_unique_id() -> u32 {
 /// It handles seeking over compressed bundles to read data at uncompressed offsets.
 /// Internally it uses caching through [`ReadBuffer`](ReadBuffer) that is supplied by the user.
 ///
+/// Compressed bundles start with a `u32` format version followed by the
+/// uncompressed size as a `u64`. Bundles under 4 GiB leave the upper half as `0`.
+///
 /// Compressed chunks are prefixed with a `u32` size that is always `65536` or smaller.
 /// If the chunk size is `65536` than that chunk is not compressed.
-/// Otherwise, the chunk is compressed with zlib deflate.
+/// Otherwise, the chunk is compressed with the [codec](crate::codec) registered
+/// for the bundle format version (zlib deflate by default).
 ///
 /// Uncompressed a chunk is always `65536` bytes. Chunks at EOF are 0 padded to `65536`.
 #[cfg_attr(feature = "serde_support", derive(serde::Serialize, serde::Deserialize))]
@@ -103,11 +223,13 @@ fn get_unique_id() -> u32 {
 pub struct BundleReader {
     #[cfg_attr(feature = "serde_support", serde(skip))]
     offset: u32,
-    size: u32,
+    size: u64,
 
-    // zero means no compression
-    // no compression means size is ZLIB_CHUNK_SIZE
-    chunk_sizes: Vec<u16>,
+    /// Compressed offset of the end of each known chunk.
+    ///
+    /// The end of a chunk is the offset of the next chunk so looking up a
+    /// chunk offset does not depend on the number of chunks before it.
+    chunk_offsets: Vec<u64>,
 
     #[cfg_attr(feature = "serde_support", serde(skip, default = "get_unique_id"))]
     id: u32,
@@ -117,6 +239,15 @@ pub struct BundleReader {
     is_ssd: bool,
     #[cfg_attr(feature = "serde_support", serde(skip))]
     unbuffered: bool,
+
+    /// Codec set with [set_codec](BundleReader::set_codec) or the codec
+    /// registered for the format version once it is read.
+    #[cfg_attr(feature = "serde_support", serde(skip))]
+    codec: Option<Arc<dyn ChunkCodec>>,
+
+    /// Number of threads used to decompress chunks of large reads.
+    #[cfg_attr(feature = "serde_support", serde(skip))]
+    threads: usize,
 }
 
 impl BundleReader {
@@ -124,17 +255,33 @@ impl BundleReader {
         Self {
             offset: 0,
             size: 0,
-            chunk_sizes: Vec::new(),
+            chunk_offsets: Vec::new(),
             id: get_unique_id(),
             version: None,
             is_ssd: false,
             unbuffered: false,
+            codec: None,
+            threads: 1,
+        }
+    }
+
+    /// Creates `BundleReader` with a chunk table saved from [chunk_offsets](BundleReader::chunk_offsets).
+    pub fn with_chunk_offsets(size: u64, chunk_offsets: Vec<u64>) -> Self {
+        Self {
+            size,
+            chunk_offsets,
+            ..Self::new()
         }
     }
 
     /// Size of uncompressed bundle.
     pub fn size(&self) -> u64 {
-        self.size as u64
+        self.size
+    }
+
+    /// Compressed offset of the end of each chunk read so far.
+    pub fn chunk_offsets(&self) -> &[u64] {
+        &self.chunk_offsets
     }
 
     /// `6` is the bundle version used in Vermintide 2.
@@ -151,23 +298,118 @@ impl BundleReader {
 
     /// Turn on aligned reads for unbuffered IO.
     ///
-    /// Only used on Windows.
+    /// Needed for files opened with `FILE_FLAG_NO_BUFFERING` on Windows or `O_DIRECT` on Linux.
     pub fn unbuffered(&mut self, enable: bool) {
         self.unbuffered = enable;
     }
 
+    /// Decompress chunks of reads spanning several chunks with up to `threads` threads.
+    ///
+    /// Only used for chunks already in the chunk table. Reads of chunks past
+    /// the table are sequential since their offsets are not known yet.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads;
+    }
+
+    /// Keep the chunk table of `other` if it knows more chunks and its
+    /// format version if this reader has not read one.
+    ///
+    /// Used to collect what clones of this reader learned on other threads.
+    /// Returns `true` if the chunk table grew.
+    pub fn merge(&mut self, other: &BundleReader) -> bool {
+        if let (None, Some(version)) = (self.version, other.version) {
+            self.set_version(version);
+        }
+
+        if other.chunk_offsets.len() > self.chunk_offsets.len() {
+            self.chunk_offsets.clone_from(&other.chunk_offsets);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Use `codec` to decompress chunks instead of the codec registered for the bundle format.
+    pub fn set_codec(&mut self, codec: Arc<dyn ChunkCodec>) {
+        self.codec = Some(codec);
+    }
+
+    /// Codec used to decompress chunks.
+    ///
+    /// The format version is only known after the first chunk is read.
+    pub fn codec(&self) -> Arc<dyn ChunkCodec> {
+        match self.codec {
+            Some(ref codec) => codec.clone(),
+            None => codec::get(self.version.unwrap_or(0)),
+        }
+    }
+
+    /// Codec for decompressing chunks without taking the codec registry lock.
+    ///
+    /// [read](BundleReader::read) resolves the codec before any chunk is decompressed.
+    fn chunk_codec(&self) -> &dyn ChunkCodec {
+        match self.codec {
+            Some(ref codec) => &**codec,
+            None => &codec::Zlib,
+        }
+    }
+
+    /// Keep format `version` read from the first chunk and resolve its codec.
+    fn set_version(&mut self, version: u16) {
+        self.version = Some(version);
+        if self.codec.is_none() {
+            self.codec = Some(codec::get(version));
+        }
+    }
+
+    /// Read format version from the compressed bundle header.
+    ///
+    /// Readers loaded with a saved chunk table start past the first chunk so
+    /// they would not know the codec of the bundle otherwise.
+    #[doc(hidden)]
+    fn read_version(
+        &mut self,
+        fd: &mut impl BundleSource,
+        read_buffer: &mut ReadBuffer,
+    ) -> crate::StingrayResult<u64> {
+        let truncated = crate::StingrayError::TruncatedChunk {
+            bundle: None,
+            chunk: 0,
+            offset: 0,
+        };
+
+        let (header, read) = match fd.as_slice() {
+            Some(data) => (data.get(..BUNDLE_COMPRESSED_HEADER_SIZE).ok_or(truncated)?, 0),
+            None => {
+                let ReadBuffer { src, last, .. } = read_buffer;
+                let size = match self.unbuffered {
+                    true => ALIGNED_READ_SIZE,
+                    false => BUNDLE_COMPRESSED_HEADER_SIZE,
+                };
+
+                // source no longer holds the chunks it was read for
+                *last = 0;
+                let read = fd.read_at(0, &mut src[..size])?;
+                if read < BUNDLE_COMPRESSED_HEADER_SIZE {
+                    return Err(truncated);
+                }
+                (&src[..BUNDLE_COMPRESSED_HEADER_SIZE], read as u64)
+            }
+        };
+
+        let version = u16::from_le_bytes(header[..2].try_into()?);
+        self.set_version(version);
+        Ok(read)
+    }
+
     /// Reads `range` of the uncompressed bundle.
     pub fn read<'a>(
         &mut self,
-        fd: &mut (impl Read + Seek),
+        fd: &mut impl BundleSource,
         read_buffer: &'a mut ReadBuffer,
         range: Range<usize>,
         read_raw: Option<&mut u64>,
     ) -> crate::StingrayResult<&'a [u8]> {
-        if self.unbuffered {
-            read_buffer.pad();
-        }
-
         let mut ret: u64 = 0;
         let mut read: usize = 0;
         let to_read = range.end - range.start;
@@ -179,12 +421,27 @@ impl BundleReader {
             read_buffer.id = Some(self.id);
         }
 
+        // chunk 0 has the format so only later chunks need it read first
+        if self.version.is_none() && chunk > 0 {
+            ret += self.read_version(fd, read_buffer)?;
+        }
+
         if read_buffer.out.len() < to_read + chunk_offset {
             let diff = to_read + chunk_offset + ZLIB_CHUNK_SIZE + read_buffer.out.len();
             let chunks = (diff - read_buffer.out.len()) / ZLIB_CHUNK_SIZE;
             read_buffer.out.resize(chunks * ZLIB_CHUNK_SIZE, 0);
         }
 
+        let last_chunk = (range.end.max(range.start + 1) - 1) / ZLIB_CHUNK_SIZE;
+        if self.threads > 1
+            && !self.unbuffered
+            && last_chunk + 1 - chunk >= PARALLEL_MIN_CHUNKS
+            && last_chunk < self.chunk_offsets.len()
+        {
+            ret += self.read_parallel(fd, read_buffer, chunk, last_chunk)?;
+            read = to_read;
+        }
+
         let mut count = 0;
         while read < to_read as usize {
             let mut size: usize = if ZLIB_CHUNK_SIZE as u64 > (chunk_offset + to_read - read) as u64 {
@@ -221,14 +478,7 @@ impl BundleReader {
                     to_read
                 };
 
-                ret += self.read_chunk(
-                    fd,
-                    chunk,
-                    &mut read_buffer.last,
-                    &mut read_buffer.offset,
-                    &mut read_buffer.src[..],
-                    &mut read_buffer.out[count * ZLIB_CHUNK_SIZE..(count + 1) * ZLIB_CHUNK_SIZE],
-                    true)?;
+                ret += self.read_chunk_cached(fd, read_buffer, chunk, count)?;
 
                 size = len;
             } else {
@@ -237,14 +487,7 @@ impl BundleReader {
                         "something went wrong, amount to read is bigger than remainder left"));
                 }
 
-                ret += self.read_chunk(
-                    fd,
-                    chunk,
-                    &mut read_buffer.last,
-                    &mut read_buffer.offset,
-                    &mut read_buffer.src[..],
-                    &mut read_buffer.out[count * ZLIB_CHUNK_SIZE..(count + 1) * ZLIB_CHUNK_SIZE],
-                    true)?;
+                ret += self.read_chunk_cached(fd, read_buffer, chunk, count)?;
             }
             read += size;
             chunk_offset = 0;
@@ -273,25 +516,75 @@ impl BundleReader {
         Ok(&read_buffer.out[chunk_offset..chunk_offset + to_read])
     }
 
-    #[doc(hidden)]
-    fn get_offset(&self, chunk: usize) -> u64 {
-        let mut out = if chunk == 0 { 0 } else {
-            BUNDLE_COMPRESSED_HEADER_SIZE as u64
+    /// Decompress `chunk` into the [ChunkCache](ChunkCache) of `read_buffer` before it is read.
+    ///
+    /// Used to read ahead on another thread with a clone of this reader.
+    /// Does nothing if `read_buffer` has no cache, the chunk is already cached
+    /// or the chunk is past the end of the bundle.
+    pub fn prefetch(
+        &mut self,
+        fd: &mut impl BundleSource,
+        read_buffer: &mut ReadBuffer,
+        chunk: usize,
+    ) -> crate::StingrayResult<u64> {
+        let cache = match read_buffer.cache {
+            Some(ref cache) if !cache.contains((self.id, chunk)) => cache.clone(),
+            _ => return Ok(0),
         };
 
-        for size in &self.chunk_sizes[..chunk] {
-            let size = *size;
-            out += 4 + if size == 0 {
-                ZLIB_CHUNK_SIZE as u64
-            } else { size as u64 };
+        let start = chunk * ZLIB_CHUNK_SIZE;
+        if self.version.is_some() && start as u64 >= self.size() {
+            return Ok(0);
+        }
+
+        // read without the cache so prefetching is not counted as a miss
+        read_buffer.cache = None;
+        let mut read = 0;
+        let result = self.read(fd, read_buffer, start..start + 1, Some(&mut read)).map(|_| ());
+        read_buffer.cache = Some(cache.clone());
+        result?;
+
+        // chunk at the start of a read is always decompressed into the first slot
+        cache.insert_prefetched((self.id, chunk), &read_buffer.out[..ZLIB_CHUNK_SIZE]);
+        Ok(read)
+    }
+
+    /// Read `chunk` into slot `count` of `read_buffer` from the chunk cache if possible.
+    #[doc(hidden)]
+    fn read_chunk_cached(
+        &mut self,
+        fd: &mut impl BundleSource,
+        read_buffer: &mut ReadBuffer,
+        chunk: usize,
+        count: usize,
+    ) -> crate::StingrayResult<u64> {
+        let ReadBuffer { src, out, offset, last, cache, .. } = read_buffer;
+        let out = &mut out[count * ZLIB_CHUNK_SIZE..(count + 1) * ZLIB_CHUNK_SIZE];
+        if let Some(cache) = cache {
+            if cache.get((self.id, chunk), out) {
+                return Ok(0);
+            }
+        }
+
+        let read = self.read_chunk(fd, chunk, last, offset, &mut src[..], out, true)?;
+        if let Some(cache) = cache {
+            cache.insert((self.id, chunk), out);
+        }
+        Ok(read)
+    }
+
+    #[doc(hidden)]
+    fn get_offset(&self, chunk: usize) -> u64 {
+        match chunk {
+            0 => 0,
+            _ => self.chunk_offsets[chunk - 1],
         }
-        out
     }
 
     #[doc(hidden)]
     fn read_chunk(
         &mut self,
-        fd: &mut (impl Read + Seek),
+        fd: &mut impl BundleSource,
         chunk: usize,
         last: &mut u64,
         offset: &mut u64,
@@ -299,13 +592,17 @@ impl BundleReader {
         out: &mut [u8],
         use_buffer: bool
     ) -> crate::StingrayResult<u64> {
+        if let Some(data) = fd.as_slice() {
+            return self.read_chunk_mapped(data, chunk, out);
+        }
+
         let mut ret = 0;
-        let mut co_len = self.chunk_sizes.len();
+        let mut co_len = self.chunk_offsets.len();
         if co_len < chunk {
             for i in co_len..chunk {
                 ret += self.read_chunk(fd, i, last, offset, source, out, false)?;
             }
-            co_len = self.chunk_sizes.len();
+            co_len = self.chunk_offsets.len();
         }
 
         let co = self.get_offset(chunk);
@@ -337,20 +634,26 @@ impl BundleReader {
             return Err(stingray_error!("offset is 0 but chunk is not ({})", chunk));
         }
 
+        let truncated = || crate::StingrayError::TruncatedChunk {
+            bundle: None,
+            chunk,
+            offset: co,
+        };
+
         // last is always greater than 0 in active chunk
         let read = if *last == 0
             || co + ZLIB_CHUNK_SIZE as u64 + 8 >= *offset + *last
 
-            // BundleFile.is_bad_offset() can mess with offsets
+            // reading files out of order can seek backwards
             // if that happens then force new read
             || *offset > co as u64
         {
             // align chunk offset for unbuffered reads
             let seek_to = if self.unbuffered {
                 if source.len() < ZLIB_CHUNK_SIZE + ALIGNED_READ_SIZE {
-                    panic!("unbuffered reads need a larger buffer");
+                    return Err(stingray_error!("unbuffered reads need a larger buffer"));
                 } else if source.len() % ALIGNED_READ_SIZE > 0 {
-                    panic!("unbuffered reads need an aligned buffer");
+                    return Err(stingray_error!("unbuffered reads need an aligned buffer"));
                 }
 
                 size += co as usize % ALIGNED_READ_SIZE;
@@ -368,8 +671,7 @@ impl BundleReader {
 
             *offset = seek_to;
 
-            fd.seek(SeekFrom::Start(seek_to as u64)).unwrap_or_else(|_| panic!("seek fail"));
-            let read = fd.read(&mut source[0..size]).unwrap_or_else(|_| panic!("read fail {}", size)) as u64;
+            let read = fd.read_at(seek_to, &mut source[0..size])? as u64;
             *last = read as u64;
             ret += read;
             read
@@ -389,29 +691,39 @@ impl BundleReader {
         } else {
             *last
         } as usize;
+        if start + off + 4 > end {
+            return Err(truncated());
+        }
         let source = &source[start..end];
         let read = (end - start) as u64;
 
         if chunk == 0 {
-            self.version = Some(u16::from_le_bytes(source[..2].try_into()?));
-            self.size = u32::from_le_bytes(source[4..8].try_into()?);
+            self.set_version(u16::from_le_bytes(source[..2].try_into()?));
+            self.size = u64::from_le_bytes(source[4..12].try_into()?);
         }
         let len = u32::from_le_bytes(source[off..off + 4].try_into()?);
 
         if len as usize > ZLIB_CHUNK_SIZE {
-            return Err(stingray_error!(
-                "bundle segment has invalid length {} > {} ({}, {}, {}, {}, {}, {}) {:?}",
-                len, ZLIB_CHUNK_SIZE, ret, co, off, size, self.size, chunk, self.chunk_sizes));
+            return Err(crate::StingrayError::BadChunkLength {
+                bundle: None,
+                chunk,
+                offset: co,
+                len,
+            });
         }
 
         if use_buffer {
+            if off + 4 + len as usize > source.len() {
+                return Err(truncated());
+            }
+
             if out.len() != ZLIB_CHUNK_SIZE {
                 return Err(stingray_error!("output buffer is smaller then ZLIB_CHUNK_SIZE"));
             }
             match len {
-                x if x < 0x10000 => { read_compressed(&source[off + 4..off + 4 + len as usize], out)?; },
+                x if x < 0x10000 => { self.chunk_codec().decompress(&source[off + 4..off + 4 + len as usize], out)?; },
                 65536 => out.copy_from_slice(&source[off + 4..off + 4 + ZLIB_CHUNK_SIZE]),
-                _ => panic!(),
+                _ => unreachable!(),
             }
         }
 
@@ -420,18 +732,19 @@ impl BundleReader {
         while read >= (offset + 4) as u64 {
             let len =  u32::from_le_bytes(source[offset..offset + 4].try_into()?);
             if len as usize > ZLIB_CHUNK_SIZE {
-                return Err(stingray_error!(
-                    "bundle segment has invalid length {} > {} ({}, {}, {}, {}, {}, {}) {:?}",
-                    len, ZLIB_CHUNK_SIZE, ret, co, off, size, self.size, chunk, self.chunk_sizes));
+                return Err(crate::StingrayError::BadChunkLength {
+                    bundle: None,
+                    chunk: chunk_count,
+                    offset: match chunk_count == chunk {
+                        true => co,
+                        false => co + offset as u64,
+                    },
+                    len,
+                });
             }
 
             if chunk_count >= co_len {
-                let size = if len == ZLIB_CHUNK_SIZE as u32 {
-                    0
-                } else {
-                    len
-                } as u16;
-                self.chunk_sizes.push(size);
+                self.chunk_offsets.push(co + (offset + 4) as u64 + len as u64);
             }
 
             chunk_count += 1;
@@ -440,6 +753,210 @@ impl BundleReader {
 
         Ok(ret)
     }
+
+    /// Decompress chunks `first..=last` into `read_buffer` with multiple threads.
+    ///
+    /// Bundles that are not in memory are read [PARALLEL_READ_CHUNKS] chunks at a time.
+    #[doc(hidden)]
+    fn read_parallel(
+        &mut self,
+        fd: &mut impl BundleSource,
+        read_buffer: &mut ReadBuffer,
+        first: usize,
+        last: usize,
+    ) -> crate::StingrayResult<u64> {
+        let batch = match fd.as_slice() {
+            Some(_) => last + 1 - first,
+            None => PARALLEL_READ_CHUNKS,
+        };
+
+        let mut ret = 0;
+        let mut chunk = first;
+        while chunk <= last {
+            let batch_last = last.min(chunk + batch - 1);
+            let out_offset = (chunk - first) * ZLIB_CHUNK_SIZE;
+            ret += self.read_parallel_batch(fd, read_buffer, out_offset, chunk, batch_last)?;
+            chunk = batch_last + 1;
+        }
+
+        Ok(ret)
+    }
+
+    /// Decompress chunks `first..=last` into `read_buffer` from `out_offset` with multiple threads.
+    #[doc(hidden)]
+    fn read_parallel_batch(
+        &mut self,
+        fd: &mut impl BundleSource,
+        read_buffer: &mut ReadBuffer,
+        out_offset: usize,
+        first: usize,
+        last: usize,
+    ) -> crate::StingrayResult<u64> {
+        let start = self.get_offset(first);
+        let end = self.get_offset(last + 1);
+        let len = (end - start) as usize;
+
+        let ReadBuffer { src, out, offset, last: src_len, .. } = read_buffer;
+        let out = &mut out[out_offset..];
+
+        let data = match fd.as_slice() {
+            Some(data) => data.get(start as usize..end as usize),
+            None => {
+                // chunks are at most ZLIB_CHUNK_SIZE with a length prefix so this stays bounded
+                if len > (last + 1 - first) * (ZLIB_CHUNK_SIZE + 4) + BUNDLE_COMPRESSED_HEADER_SIZE {
+                    return Err(stingray_error!("chunk table has chunks larger than ZLIB_CHUNK_SIZE"));
+                }
+                src.grow(len);
+
+                let mut read = 0;
+                while read < len {
+                    match fd.read_at(start + read as u64, &mut src[read..len])? {
+                        0 => break,
+                        n => read += n,
+                    }
+                }
+
+                // keep ReadBuffer consistent for sequential reads of the same chunks
+                *offset = start;
+                *src_len = read as u64;
+                src.get(..len).filter(|_| read == len)
+            }
+        };
+
+        let data = match data {
+            Some(data) => data,
+            None => {
+                // find first chunk that is not fully in the source
+                let size = fd.as_slice().map(|data| data.len() as u64).unwrap_or(start + *src_len);
+                let chunk = (first..=last)
+                    .find(|chunk| self.get_offset(chunk + 1) > size)
+                    .unwrap_or(last);
+                return Err(crate::StingrayError::TruncatedChunk {
+                    bundle: None,
+                    chunk,
+                    offset: self.get_offset(chunk),
+                });
+            }
+        };
+
+        if first == 0 {
+            self.set_version(u16::from_le_bytes(data[..2].try_into()?));
+            self.size = u64::from_le_bytes(data[4..12].try_into()?);
+        }
+
+        let mut tasks = Vec::with_capacity(last + 1 - first);
+        for (chunk, out) in (first..=last).zip(out.chunks_mut(ZLIB_CHUNK_SIZE)) {
+            let co = (self.get_offset(chunk) - start) as usize + match chunk {
+                0 => BUNDLE_COMPRESSED_HEADER_SIZE,
+                _ => 0,
+            };
+            let ce = (self.get_offset(chunk + 1) - start) as usize;
+            tasks.push((&data[co + 4..ce], out));
+        }
+
+        let codec = self.chunk_codec();
+        let per_thread = (tasks.len() + self.threads - 1) / self.threads;
+        crossbeam_utils::thread::scope(|s| {
+            let threads = tasks.chunks_mut(per_thread).map(|tasks| {
+                s.spawn(move |_| -> io::Result<()> {
+                    for (source, out) in tasks {
+                        match source.len() {
+                            ZLIB_CHUNK_SIZE => out.copy_from_slice(source),
+                            _ => { codec.decompress(source, out)?; },
+                        }
+                    }
+                    Ok(())
+                })
+            }).collect::<Vec<_>>();
+
+            threads.into_iter().try_for_each(|thread| thread.join().unwrap())
+        }).unwrap()?;
+
+        Ok(len as u64)
+    }
+
+    /// Decompress `chunk` straight from an in memory bundle.
+    #[doc(hidden)]
+    fn read_chunk_mapped(
+        &mut self,
+        data: &[u8],
+        chunk: usize,
+        out: &mut [u8],
+    ) -> crate::StingrayResult<u64> {
+        if data.len() < BUNDLE_COMPRESSED_HEADER_SIZE {
+            return Err(crate::StingrayError::TruncatedChunk {
+                bundle: None,
+                chunk: 0,
+                offset: 0,
+            });
+        }
+
+        if chunk == 0 || self.version.is_none() {
+            self.set_version(u16::from_le_bytes(data[..2].try_into()?));
+            self.size = u64::from_le_bytes(data[4..12].try_into()?);
+        }
+
+        // walk length prefixes of chunks that are not in the chunk table yet
+        while self.chunk_offsets.len() <= chunk {
+            let i = self.chunk_offsets.len();
+            let co = self.get_offset(i);
+            let start = co as usize + match i {
+                0 => BUNDLE_COMPRESSED_HEADER_SIZE,
+                _ => 0,
+            };
+
+            let truncated = crate::StingrayError::TruncatedChunk {
+                bundle: None,
+                chunk: i,
+                offset: co,
+            };
+            if start + 4 > data.len() {
+                return Err(truncated);
+            }
+
+            let len = u32::from_le_bytes(data[start..start + 4].try_into()?);
+            if len as usize > ZLIB_CHUNK_SIZE {
+                return Err(crate::StingrayError::BadChunkLength {
+                    bundle: None,
+                    chunk: i,
+                    offset: co,
+                    len,
+                });
+            }
+
+            let end = (start + 4 + len as usize) as u64;
+            if end > data.len() as u64 {
+                return Err(truncated);
+            }
+            self.chunk_offsets.push(end);
+        }
+
+        if out.len() != ZLIB_CHUNK_SIZE {
+            return Err(stingray_error!("output buffer is smaller then ZLIB_CHUNK_SIZE"));
+        }
+
+        let co = self.get_offset(chunk) as usize;
+        let end = self.get_offset(chunk + 1) as usize;
+        let start = co + 4 + match chunk {
+            0 => BUNDLE_COMPRESSED_HEADER_SIZE,
+            _ => 0,
+        };
+        if end > data.len() {
+            return Err(crate::StingrayError::TruncatedChunk {
+                bundle: None,
+                chunk,
+                offset: co as u64,
+            });
+        }
+
+        let source = &data[start..end];
+        match source.len() {
+            ZLIB_CHUNK_SIZE => out.copy_from_slice(source),
+            _ => { self.chunk_codec().decompress(source, out)?; },
+        }
+
+        Ok((end - co) as u64)
+    }
 }
 
 impl Default for BundleReader {
@@ -447,3 +964,119 @@ impl Default for BundleReader {
         Self::new()
     }
 }
+
+/// `Read + Seek` over the uncompressed contents of a bundle.
+///
+/// Reads are served by [BundleReader](BundleReader) and its chunk table so
+/// seeking only decompresses the chunks that are read.
+///
+/// # Example
+///
+/// ```
+/// use std::io::{Read, Seek, SeekFrom};
+/// use stingray::{BundleStream, BundleWriter, MappedBundle};
+///
+/// let mut bundle = Vec::new();
+/// BundleWriter::new(6).unwrap().write(&mut bundle).unwrap();
+///
+/// let mut stream = BundleStream::new(MappedBundle::new(&bundle)).unwrap();
+/// assert_eq!(stream.len(), 260);
+///
+/// let mut num_files = [0; 4];
+/// stream.read_exact(&mut num_files).unwrap();
+/// assert_eq!(u32::from_le_bytes(num_files), 0);
+///
+/// stream.seek(SeekFrom::End(-4)).unwrap();
+/// let mut rest = Vec::new();
+/// assert_eq!(stream.read_to_end(&mut rest).unwrap(), 4);
+/// ```
+pub struct BundleStream<S> {
+    source: S,
+    reader: BundleReader,
+    buffer: ReadBuffer,
+    pos: u64,
+}
+
+impl<S: BundleSource> BundleStream<S> {
+    /// Creates `BundleStream` over compressed bundle `source`.
+    ///
+    /// Reads the first chunk to get the uncompressed size.
+    pub fn new(source: S) -> crate::StingrayResult<Self> {
+        Self::with_reader(source, BundleReader::new())
+    }
+
+    /// Creates `BundleStream` with an existing reader to reuse its chunk table.
+    pub fn with_reader(mut source: S, mut reader: BundleReader) -> crate::StingrayResult<Self> {
+        let mut buffer = ReadBuffer::default();
+        if reader.version().is_none() {
+            reader.read(&mut source, &mut buffer, 0..1, None)?;
+        }
+
+        Ok(Self {
+            source,
+            reader,
+            buffer,
+            pos: 0,
+        })
+    }
+
+    /// Size of uncompressed bundle.
+    pub fn len(&self) -> u64 {
+        self.reader.size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn reader(&self) -> &BundleReader {
+        &self.reader
+    }
+
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+}
+
+impl<S: BundleSource> Read for BundleStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.len();
+        if self.pos >= len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // stop at the end of the chunk so every read decompresses at most one chunk
+        let start = self.pos as usize;
+        let end = (start - start % ZLIB_CHUNK_SIZE + ZLIB_CHUNK_SIZE)
+            .min(len as usize)
+            .min(start + buf.len());
+
+        let out = self.reader.read(&mut self.source, &mut self.buffer, start..end, None)?;
+        buf[..out.len()].copy_from_slice(out);
+        self.pos += out.len() as u64;
+        Ok(out.len())
+    }
+}
+
+impl<S: BundleSource> Seek for BundleStream<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let add_signed = |pos: u64, offset: i64| match offset < 0 {
+            true => pos.checked_sub(offset.unsigned_abs()),
+            false => pos.checked_add(offset as u64),
+        };
+
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => add_signed(self.len(), offset),
+            SeekFrom::Current(offset) => add_signed(self.pos, offset),
+        };
+
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")),
+        }
+    }
+}
@@ -24,6 +24,20 @@ fn format_bundle_(hash: u64, patch: Patch) -> String {
     }
 }
 
+/// Create stream file name from hash and patch.
+///
+/// # Example
+///
+/// ```
+/// use stingray::Patch;
+/// use stingray::format_stream;
+///
+/// assert_eq!(format_stream(0x0123456789abcdef, Patch::new(6)).as_str(), "0123456789abcdef.patch_006.stream");
+/// ```
+pub fn format_stream<T: Into<Patch>>(hash: u64, patch: T) -> String {
+    format!("{}.stream", format_bundle_(hash, patch.into()))
+}
+
 /// Try to parse bundle path into u64 hash and u16 patch number.
 ///
 /// # Example
@@ -57,6 +71,26 @@ fn get_bundle_hash_patch_(path: &Path) -> Option<(u64, Patch)> {
     Some((hash, Patch::new(patch)))
 }
 
+/// Try to parse stream path into u64 hash and u16 patch number of the owning bundle.
+///
+/// # Example
+///
+/// ```
+/// use stingray::Patch;
+/// use stingray::get_stream_hash_patch;
+///
+/// assert_eq!(get_stream_hash_patch("0123456789abcdef.stream"), Some((0x0123456789abcdef, Patch::new_base())));
+/// assert_eq!(get_stream_hash_patch("0123456789abcdef.patch_006.stream"), Some((0x0123456789abcdef, Patch::new(6))));
+/// assert_eq!(get_stream_hash_patch("0123456789abcdef"), None);
+/// ```
+pub fn get_stream_hash_patch<T: AsRef<Path>>(stream: T) -> Option<(u64, Patch)> {
+    let path = stream.as_ref();
+    match path.extension() {
+        Some(ext) if ext == "stream" => get_bundle_hash_patch_(Path::new(path.file_stem()?)),
+        _ => None,
+    }
+}
+
 /// Wrapper around patch numbers for bundles.
 #[repr(transparent)]
 #[cfg_attr(feature = "serde_support", derive(serde::Serialize, serde::Deserialize))]
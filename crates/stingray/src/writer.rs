@@ -0,0 +1,392 @@
+//! Writer for the `bundle` package format.
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use flate2::read::ZlibEncoder;
+use flate2::Compression;
+
+use crate::codec::ChunkCodec;
+use crate::consts::{FILE_HEADER_SIZE, ZLIB_CHUNK_SIZE};
+use crate::file::FileVariant;
+use crate::bundle::BundleHeader;
+
+/// Magic bits stored in the high half of the format version.
+const BUNDLE_FORMAT_MAGIC: u32 = 0xf000_0000;
+
+struct WriterFile {
+    ext: u64,
+    hash: u64,
+    variants: Vec<FileVariant>,
+    payload: Vec<u8>,
+}
+
+/// Writer for compressed bundles.
+///
+/// Does the reverse of [BundleVersion::index](crate::BundleVersion::index) and
+/// [BundleReader::read](crate::BundleReader::read).
+///
+/// # Example
+///
+/// ```
+/// use std::io::Cursor;
+/// use stingray::{BundleVersion, BundleWriter, Patch, ReadBuffer};
+/// use stingray::file::{FileKind, FileVariant, Language};
+///
+/// let payload = b"return 0".to_vec();
+/// let mut writer = BundleWriter::new(6).unwrap();
+/// writer.add_file(
+///     FileKind::config as u64,
+///     0x1234,
+///     vec![FileVariant::new(Language::English, payload.len() as u32)],
+///     payload,
+/// ).unwrap();
+///
+/// let mut bundle = Vec::new();
+/// writer.write(&mut bundle).unwrap();
+///
+/// let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+/// let mut fd = Cursor::new(bundle);
+/// let mut buffer = ReadBuffer::default();
+/// version.index(&mut fd, 0, &mut buffer).unwrap();
+///
+/// let file = version.read_file(&mut fd, 0, FileKind::config as u64, 0x1234, &mut buffer).unwrap();
+/// assert_eq!(&file[36..], b"return 0");
+/// ```
+pub struct BundleWriter {
+    version: u16,
+    header: BundleHeader,
+    files: Vec<WriterFile>,
+    level: Compression,
+    codec: Option<Arc<dyn ChunkCodec>>,
+}
+
+impl BundleWriter {
+    /// Creates `BundleWriter` for bundle format `version`.
+    ///
+    /// Only formats `5` and `6` are supported.
+    pub fn new(version: u16) -> crate::StingrayResult<Self> {
+        if version != 5 && version != 6 {
+            return Err(crate::StingrayError::UnsupportedVersion {
+                bundle: None,
+                version,
+            });
+        }
+
+        Ok(Self {
+            version,
+            header: BundleHeader::default(),
+            files: Vec::new(),
+            level: Compression::default(),
+            codec: None,
+        })
+    }
+
+    /// Bundle format version that is written.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Set the header stored after the file count.
+    pub fn set_header(&mut self, header: BundleHeader) {
+        self.header = header;
+    }
+
+    /// Set zlib compression level used for chunks.
+    pub fn set_compression(&mut self, level: u32) {
+        self.level = Compression::new(level);
+    }
+
+    /// Compress chunks with `codec` instead of zlib.
+    ///
+    /// Readers need the same codec [registered](crate::codec::register) or
+    /// [set](crate::BundleReader::set_codec) to read the bundle.
+    pub fn set_codec(&mut self, codec: Arc<dyn ChunkCodec>) {
+        self.codec = Some(codec);
+    }
+
+    /// Add a file to the bundle.
+    ///
+    /// `payload` is the data for every variant back to back and has to match
+    /// the sum of variant sizes.
+    pub fn add_file(
+        &mut self,
+        ext_hash: u64,
+        name_hash: u64,
+        variants: Vec<FileVariant>,
+        payload: Vec<u8>,
+    ) -> crate::StingrayResult<()> {
+        let size = variants.iter().map(|variant| variant.size() as u64).sum::<u64>();
+        if size != payload.len() as u64 {
+            return Err(stingray_error!(
+                "variant sizes ({}) do not match payload size ({}) for file {:016x} {:016x}",
+                size, payload.len(), ext_hash, name_hash));
+        }
+        if variants.is_empty() {
+            return Err(stingray_error!("file {:016x} {:016x} has no variants", ext_hash, name_hash));
+        }
+        // the index of newer formats stores file size as u32
+        let index_size = index_file_size(variants.len(), size);
+        if self.version >= 6 && index_size > u32::MAX as u64 {
+            return Err(stingray_error!(
+                "file {:016x} {:016x} is bigger than expected {} > {}",
+                ext_hash, name_hash, index_size, u32::MAX));
+        }
+
+        self.files.push(WriterFile {
+            ext: ext_hash,
+            hash: name_hash,
+            variants,
+            payload,
+        });
+        Ok(())
+    }
+
+    /// Number of files added to the writer.
+    pub fn num_files(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Serialize the uncompressed bundle.
+    fn uncompressed(&self) -> crate::StingrayResult<Vec<u8>> {
+        let index_size = if self.version < 6 {
+            20
+        } else {
+            24
+        };
+
+        let mut out = Vec::with_capacity(4 + BundleHeader::SIZE + self.files.len() * index_size);
+        out.extend_from_slice(&(self.files.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.header.to_bytes());
+
+        for file in &self.files {
+            out.extend_from_slice(&file.ext.to_le_bytes());
+            out.extend_from_slice(&file.hash.to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+            if self.version >= 6 {
+                let size = index_file_size(file.variants.len(), file.payload.len() as u64);
+                out.extend_from_slice(&(size as u32).to_le_bytes());
+            }
+        }
+
+        for file in &self.files {
+            out.extend_from_slice(&file.ext.to_le_bytes());
+            out.extend_from_slice(&file.hash.to_le_bytes());
+            out.extend_from_slice(&(file.variants.len() as u32).to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+            for variant in &file.variants {
+                out.extend_from_slice(&variant.lang().code().to_le_bytes());
+                out.extend_from_slice(&variant.size().to_le_bytes());
+                out.extend_from_slice(&variant.stream_size().to_le_bytes());
+            }
+            out.extend_from_slice(&file.payload);
+        }
+
+        Ok(out)
+    }
+
+    /// Write compressed bundle to `out`.
+    ///
+    /// Returns the number of bytes written.
+    pub fn write(&self, out: &mut impl Write) -> crate::StingrayResult<u64> {
+        let data = self.uncompressed()?;
+
+        out.write_all(&(BUNDLE_FORMAT_MAGIC | self.version as u32).to_le_bytes())?;
+        out.write_all(&(data.len() as u64).to_le_bytes())?;
+        let mut written = 12;
+
+        let mut chunk = vec![0; ZLIB_CHUNK_SIZE];
+        let mut compressed = Vec::with_capacity(ZLIB_CHUNK_SIZE);
+        for src in data.chunks(ZLIB_CHUNK_SIZE) {
+            // chunks at EOF are 0 padded
+            chunk[..src.len()].copy_from_slice(src);
+            for byte in &mut chunk[src.len()..] {
+                *byte = 0;
+            }
+
+            compressed.clear();
+            match self.codec {
+                Some(ref codec) => codec.compress(&chunk, &mut compressed)?,
+                None => ZlibEncoder::new(&chunk[..], self.level).read_to_end(&mut compressed)?,
+            };
+
+            // chunks that do not shrink are stored raw
+            let chunk = if compressed.len() < ZLIB_CHUNK_SIZE {
+                &compressed[..]
+            } else {
+                &chunk[..]
+            };
+
+            out.write_all(&(chunk.len() as u32).to_le_bytes())?;
+            out.write_all(chunk)?;
+            written += 4 + chunk.len() as u64;
+        }
+
+        Ok(written)
+    }
+}
+
+/// Size stored in the index of format `6`.
+///
+/// Readers expect every file to take [FILE_HEADER_SIZE](FILE_HEADER_SIZE) plus
+/// this size. Headers grow by 12 bytes for every variant past the first.
+fn index_file_size(num_variants: usize, payload: u64) -> u64 {
+    (24 + 12 * num_variants as u64 + payload).saturating_sub(FILE_HEADER_SIZE as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::codec::Stored;
+    use crate::file::{FileKind, Language};
+    use crate::{BundleVersion, Patch, ReadBuffer};
+
+    fn round_trip(version_format: u16) {
+        let mut writer = BundleWriter::new(version_format).unwrap();
+        let header = BundleHeader::new(&[FileKind::config as u64, FileKind::lua as u64]).unwrap();
+        writer.set_header(header.clone());
+        let mut files = Vec::new();
+        for i in 0..64u64 {
+            // mix of small files and files spanning several chunks
+            let len = if i % 8 == 0 { ZLIB_CHUNK_SIZE * 2 + 7 } else { 100 + i as usize };
+            let payload = (0..len).map(|n| (n as u64 * (i + 1)) as u8).collect::<Vec<_>>();
+            writer.add_file(
+                FileKind::config as u64,
+                i,
+                vec![FileVariant::new(Language::English, payload.len() as u32)],
+                payload.clone()).unwrap();
+            files.push((i, payload));
+        }
+
+        let mut bundle = Vec::new();
+        let written = writer.write(&mut bundle).unwrap();
+        assert_eq!(written, bundle.len() as u64);
+
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        let mut fd = Cursor::new(bundle);
+        let mut buffer = ReadBuffer::default();
+        version.index(&mut fd, 0, &mut buffer).unwrap();
+        assert_eq!(version.bundle_header(), &header);
+        assert_eq!(version.bundle_header().properties(), vec![FileKind::config as u64, FileKind::lua as u64]);
+
+        // format 5 sizes are resolved from file headers
+        assert_eq!(version.files().len(), files.len());
+        for file in version.files() {
+            assert_eq!(file.size() as usize, files[file.name_hash() as usize].1.len());
+        }
+
+        for (hash, payload) in &files {
+            let out = version.read_file(&mut fd, 0, FileKind::config as u64, *hash, &mut buffer).unwrap();
+            assert_eq!(&out[36..], &payload[..]);
+        }
+    }
+
+    #[test]
+    fn round_trip_v5() {
+        round_trip(5);
+    }
+
+    #[test]
+    fn round_trip_v6() {
+        round_trip(6);
+    }
+
+    #[test]
+    fn stream_offsets() {
+        let mut writer = BundleWriter::new(6).unwrap();
+        for i in 0..4u64 {
+            let variants = vec![FileVariant::with_stream(Language::English, 8, 16 * i as u32)];
+            writer.add_file(FileKind::wwise_stream as u64, i, variants, vec![0; 8]).unwrap();
+        }
+
+        let mut bundle = Vec::new();
+        writer.write(&mut bundle).unwrap();
+
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        let mut fd = Cursor::new(bundle);
+        let mut buffer = ReadBuffer::default();
+        version.index(&mut fd, 0, &mut buffer).unwrap();
+        version.index_stream(&mut fd, 0, 96, &mut buffer).unwrap();
+
+        let stream = (0..96u8).collect::<Vec<_>>();
+        let mut stream_fd = Cursor::new(stream);
+        let mut out = Vec::new();
+        let data = version.read_stream(&mut stream_fd, FileKind::wwise_stream as u64, 3, &mut out).unwrap();
+        assert_eq!(data, &(48..96u8).collect::<Vec<_>>()[..]);
+        assert!(version.index_stream(&mut fd, 0, 95, &mut buffer).is_err());
+    }
+
+    #[test]
+    fn stored_codec() {
+        let payload = b"stored chunk".to_vec();
+        let mut writer = BundleWriter::new(6).unwrap();
+        writer.set_codec(Arc::new(Stored));
+        writer.add_file(
+            FileKind::config as u64,
+            0,
+            vec![FileVariant::new(Language::English, payload.len() as u32)],
+            payload).unwrap();
+
+        let mut bundle = Vec::new();
+        writer.write(&mut bundle).unwrap();
+
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        version.reader_mut().set_codec(Arc::new(Stored));
+        let mut fd = Cursor::new(bundle);
+        let mut buffer = ReadBuffer::default();
+        version.index(&mut fd, 0, &mut buffer).unwrap();
+
+        let out = version.read_file(&mut fd, 0, FileKind::config as u64, 0, &mut buffer).unwrap();
+        assert_eq!(&out[36..], b"stored chunk");
+    }
+
+    #[test]
+    fn multiple_variants() {
+        let english = b"english".to_vec();
+        let german = b"deutsch!!".to_vec();
+        let payload = [&english[..], &german[..]].concat();
+
+        let mut writer = BundleWriter::new(6).unwrap();
+        writer.add_file(
+            FileKind::strings as u64,
+            0,
+            vec![
+                FileVariant::new(Language::English, english.len() as u32),
+                FileVariant::new(Language::German, german.len() as u32),
+            ],
+            payload.clone()).unwrap();
+        writer.add_file(
+            FileKind::config as u64,
+            1,
+            vec![FileVariant::new(Language::English, 4)],
+            vec![1; 4]).unwrap();
+
+        let mut bundle = Vec::new();
+        writer.write(&mut bundle).unwrap();
+
+        let mut version = BundleVersion::new(Patch::new_base(), bundle.len() as u64);
+        let mut fd = Cursor::new(bundle);
+        let mut buffer = ReadBuffer::default();
+        version.index(&mut fd, 0, &mut buffer).unwrap();
+        assert_eq!(version.diff(), 0);
+
+        // second variant header comes before the payload
+        let out = version.read_file(&mut fd, 0, FileKind::strings as u64, 0, &mut buffer).unwrap();
+        assert_eq!(out.len(), 48 + payload.len());
+        assert_eq!(&out[48..], &payload[..]);
+        let out = version.read_file(&mut fd, 0, FileKind::config as u64, 1, &mut buffer).unwrap();
+        assert_eq!(&out[36..], &[1; 4]);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        assert!(matches!(BundleWriter::new(7), Err(crate::StingrayError::UnsupportedVersion { version: 7, .. })));
+    }
+
+    #[test]
+    fn payload_mismatch() {
+        let mut writer = BundleWriter::new(6).unwrap();
+        assert!(writer.add_file(0, 0, vec![FileVariant::new(Language::English, 4)], vec![0; 3]).is_err());
+    }
+}